@@ -0,0 +1,382 @@
+use crate::{
+    components::*,
+    error::Result,
+    resources::{Event, Events},
+    systems::Systems,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+// Slowest vertical speed that counts as rising or falling
+const CLIMBING: f32 = 0.01;
+
+const MOVING: f32 = 0.1;
+
+///
+/// What an entity must be doing for a transition to be taken
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Condition {
+    /// Standing on a block
+    Grounded,
+    Airborne,
+    Rising,
+    Falling,
+    /// Moving horizontally
+    Running,
+    Still,
+    FacingLeft,
+    FacingRight,
+    /// Fired during the last turn
+    Shot,
+    /// Hit by a bullet during the last turn
+    Hurt,
+    /// A non-looping state played its last frame
+    Finished,
+    /// The current state lasted at least this many turns
+    After(u64),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+///
+/// Facts about an entity during a turn, tested by conditions
+///
+struct Facts {
+    vel: Vel,
+    grounded: bool,
+    dir: f32,
+    shot: bool,
+    hurt: bool,
+    finished: bool,
+    ticks: u64,
+}
+
+impl Condition {
+    fn holds(&self, f: &Facts) -> bool {
+        match self {
+            Condition::Grounded => f.grounded,
+            Condition::Airborne => !f.grounded,
+            Condition::Rising => f.vel.y >= CLIMBING,
+            Condition::Falling => f.vel.y <= -CLIMBING,
+            Condition::Running => f.vel.x.abs() >= MOVING,
+            Condition::Still => f.vel.x.abs() < MOVING,
+            Condition::FacingLeft => f.dir < 0.0,
+            Condition::FacingRight => f.dir >= 0.0,
+            Condition::Shot => f.shot,
+            Condition::Hurt => f.hurt,
+            Condition::Finished => f.finished,
+            Condition::After(n) => f.ticks >= *n,
+            Condition::All(cs) => cs.iter().all(|c| c.holds(f)),
+            Condition::Any(cs) => cs.iter().any(|c| c.holds(f)),
+            Condition::Not(c) => !c.holds(f),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transition {
+    pub to: String,
+    pub when: Condition,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnimationState {
+    pub asset: Asset,
+    pub frames: u32,
+    /// Turns each frame is shown
+    pub frame_ticks: u64,
+    #[serde(default = "default_looping")]
+    pub looping: bool,
+    /// Tried in order; the first one whose condition holds is taken
+    #[serde(default)]
+    pub transitions: Vec<Transition>,
+}
+
+fn default_looping() -> bool {
+    true
+}
+
+///
+/// States of an animation and the transitions between them
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnimationGraph {
+    pub name: String,
+    pub initial: String,
+    pub states: HashMap<String, AnimationState>,
+    /// Transitions tried from every state before its own
+    #[serde(default)]
+    pub any: Vec<Transition>,
+}
+
+impl AnimationGraph {
+    ///
+    /// State to enter from `current`, if any transition applies
+    ///
+    fn next(&self, current: &str, facts: &Facts) -> Option<&str> {
+        let own = self.states.get(current).map(|s| &s.transitions[..]);
+
+        self.any
+            .iter()
+            .chain(own.unwrap_or(&[]))
+            .find(|t| t.to != current && t.when.holds(facts))
+            .map(|t| t.to.as_str())
+    }
+}
+
+///
+/// Registry of animation graphs, looked up by name
+///
+#[derive(Clone, Debug, Default)]
+pub struct Animations {
+    graphs: HashMap<String, AnimationGraph>,
+}
+
+impl Animations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Read graphs from a JSON list, replacing those of the same name
+    ///
+    pub fn load(&mut self, bytes: &[u8]) -> Result<()> {
+        let list: Vec<AnimationGraph> = serde_json::from_slice(bytes)?;
+        for graph in list {
+            self.insert(graph);
+        }
+
+        Ok(())
+    }
+
+    pub fn open<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.load(&fs::read(path)?)
+    }
+
+    pub fn insert(&mut self, graph: AnimationGraph) {
+        for t in graph
+            .states
+            .values()
+            .flat_map(|s| &s.transitions)
+            .chain(&graph.any)
+        {
+            if !graph.states.contains_key(&t.to) {
+                warn!("Animation {} moves to unknown state {}", graph.name, t.to);
+            }
+        }
+        self.graphs.insert(graph.name.clone(), graph);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AnimationGraph> {
+        self.graphs.get(name)
+    }
+}
+
+///
+/// Move animations between states and advance their frames by one turn
+///
+/// Only the events pushed since `since` are considered, those of this turn.
+///
+pub(crate) struct Animate {
+    pub since: usize,
+}
+
+impl<'a> System<'a> for Animate {
+    type SystemData = (
+        Read<'a, Animations>,
+        Read<'a, Events>,
+        WriteStorage<'a, Animation>,
+        WriteStorage<'a, Asset>,
+        Entities<'a>,
+        ReadStorage<'a, Vel>,
+        ReadStorage<'a, Grounded>,
+        ReadStorage<'a, Dir>,
+        ReadStorage<'a, Player>,
+    );
+
+    fn run(
+        &mut self,
+        (graphs, events, mut anim, mut asset, e, vel, grounded, dir, ply): Self::SystemData,
+    ) {
+        let mut shot = HashSet::new();
+        let mut hurt = HashSet::new();
+        for event in events.0.iter().skip(self.since) {
+            match event {
+                Event::Shot { player } => {
+                    shot.insert(*player);
+                }
+                Event::Hit { target, .. } => {
+                    hurt.insert(*target);
+                }
+                _ => {}
+            }
+        }
+
+        for (e, anim) in (&e, &mut anim).join() {
+            let graph = match graphs.get(&anim.graph) {
+                Some(graph) => graph,
+                None => continue,
+            };
+            if anim.state.is_empty() {
+                anim.state = graph.initial.clone();
+            }
+
+            let id = ply.get(e).map(|p| p.id);
+            let facts = Facts {
+                vel: vel.get(e).cloned().unwrap_or_else(Vel::zero),
+                grounded: grounded.contains(e),
+                dir: dir.get(e).map(|d| d.0).unwrap_or(1.0),
+                shot: id.map(|id| shot.contains(&id)).unwrap_or(false),
+                hurt: id.map(|id| hurt.contains(&id)).unwrap_or(false),
+                finished: anim.finished,
+                ticks: anim.ticks,
+            };
+
+            match graph.next(&anim.state, &facts) {
+                Some(next) if graph.states.contains_key(next) => {
+                    trace!("Animation {}: {} -> {}", anim.graph, anim.state, next);
+                    anim.state = next.into();
+                    anim.ticks = 0;
+                }
+                _ => anim.ticks += 1,
+            }
+
+            let state = match graph.states.get(&anim.state) {
+                Some(state) => state,
+                None => continue,
+            };
+            let frames = state.frames.max(1) as u64;
+            let played = anim.ticks / state.frame_ticks.max(1);
+            anim.finished = !state.looping && played >= frames;
+            anim.frame = if state.looping {
+                played % frames
+            } else {
+                played.min(frames - 1)
+            } as u32;
+
+            let _ = asset.insert(e, state.asset);
+        }
+    }
+}
+
+impl Systems {
+    ///
+    /// Replace the animation graphs driving `Animation` components
+    ///
+    pub fn set_animations(&mut self, animations: Animations) {
+        *self.world.write_resource::<Animations>() = animations;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::CreateEntity;
+    use crate::resources::Action;
+
+    const GRAPH: &[u8] = br#"[{
+        "name": "hero",
+        "initial": "idle",
+        "states": {
+            "idle": {"asset": 10, "frames": 2, "frame_ticks": 2,
+                     "transitions": [{"to": "jump", "when": "Airborne"}]},
+            "jump": {"asset": 12, "frames": 3, "frame_ticks": 5, "looping": false,
+                     "transitions": [{"to": "idle", "when": "Grounded"}]}
+        }
+    }]"#;
+
+    fn world() -> (Systems, Entity) {
+        let mut sys = Systems::new().unwrap();
+        let mut animations = Animations::new();
+        animations.load(GRAPH).unwrap();
+        sys.set_animations(animations);
+
+        sys.create_entity().create_terrain_block(
+            Pos::new(-50.0, -10.0),
+            Size::new(100.0, 10.0),
+            Asset(1),
+        );
+        let player = Player {
+            id: 1,
+            class: CLASS_CHIBA,
+            lives: 3,
+        };
+        let user =
+            sys.create_entity()
+                .create_user(Pos::zero(), Size::new(10.0, 10.0), player, Asset(0));
+        sys.world
+            .write_storage::<Animation>()
+            .insert(user, Animation::new("hero"))
+            .unwrap();
+
+        (sys, user)
+    }
+
+    fn state(sys: &Systems, e: Entity) -> Animation {
+        sys.world
+            .read_storage::<Animation>()
+            .get(e)
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn jumps_play_until_landing() {
+        let (mut sys, user) = world();
+        sys.update();
+        assert_eq!(state(&sys, user).state, "idle");
+        assert!(sys.world.read_storage::<Grounded>().contains(user));
+
+        let mut act = Action::default();
+        act.jump();
+        sys.add_action(act);
+
+        let mut airborne = 0;
+        for _ in 0..200 {
+            sys.update();
+            let anim = state(&sys, user);
+            if anim.state == "idle" {
+                break;
+            }
+            airborne += 1;
+            // The jump plays once and holds its last frame
+            assert_eq!(anim.frame as u64, (anim.ticks / 5).min(2));
+            assert_eq!(anim.finished, anim.ticks >= 15);
+            assert_eq!(
+                sys.world.read_storage::<Asset>().get(user),
+                Some(&Asset(12))
+            );
+        }
+
+        assert!(airborne > 30, "landed after {} turns", airborne);
+        assert_eq!(state(&sys, user).state, "idle");
+    }
+
+    #[test]
+    fn hovering_is_not_standing() {
+        let (mut sys, user) = world();
+        // Still in mid-air, as at the apex of a jump
+        sys.world
+            .write_storage::<Pos>()
+            .insert(user, Pos::new(0.0, 100.0))
+            .unwrap();
+        sys.world
+            .write_storage::<Acc>()
+            .insert(user, Acc::zero())
+            .unwrap();
+
+        sys.update();
+        sys.update();
+
+        assert_eq!(state(&sys, user).state, "jump");
+        assert!(!sys.world.read_storage::<Grounded>().contains(user));
+    }
+}
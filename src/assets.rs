@@ -0,0 +1,327 @@
+use crate::{
+    components::*,
+    error::{Error, Result},
+    level::Level,
+    prefab::Prefabs,
+    protocol::Terrain,
+    systems::Systems,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use std::{collections::HashMap, fs, path::Path};
+
+///
+/// Name of the asset given to bullets, if the manifest has one
+///
+pub const BULLET: &str = "bullet";
+
+///
+/// Asset of bullets when the manifest doesn't name one
+///
+pub const DEFAULT_BULLET: Asset = Asset(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AssetKind {
+    Sprite,
+    SpriteSheet,
+    Sound,
+    Animation,
+}
+
+///
+/// Layout of the frames of a sprite sheet or animation
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Frames {
+    pub width: u32,
+    pub height: u32,
+    pub count: u32,
+    /// Frames per row of the sheet, all of them if unset
+    #[serde(default)]
+    pub columns: Option<u32>,
+    /// Display time of each frame
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+}
+
+impl Frames {
+    ///
+    /// Top-left corner of a frame in the sheet, in pixels
+    ///
+    pub fn offset(&self, frame: u32) -> (u32, u32) {
+        let columns = self.columns.unwrap_or(self.count).max(1);
+        let frame = frame % self.count.max(1);
+        (
+            (frame % columns) * self.width,
+            (frame / columns) * self.height,
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AssetEntry {
+    pub id: Asset,
+    pub name: String,
+    /// Location of the resource, relative to the manifest
+    pub path: String,
+    pub kind: AssetKind,
+    #[serde(default)]
+    pub frames: Option<Frames>,
+}
+
+///
+/// Registry of the assets listed in a manifest, looked up by id or name
+///
+#[derive(Clone, Debug, Default)]
+pub struct Assets {
+    entries: HashMap<Asset, AssetEntry>,
+    names: HashMap<String, Asset>,
+}
+
+impl Assets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Read a manifest, a JSON list of assets
+    ///
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let list: Vec<AssetEntry> = serde_json::from_slice(bytes)?;
+
+        let mut assets = Self::new();
+        for entry in list {
+            assets.insert(entry)?;
+        }
+
+        Ok(assets)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_slice(&fs::read(path)?)
+    }
+
+    ///
+    /// Add an asset, refusing ids and names already taken
+    ///
+    pub fn insert(&mut self, entry: AssetEntry) -> Result<()> {
+        if self.entries.contains_key(&entry.id) {
+            return Err(Error::InvalidAsset(format!("id {} is taken", entry.id.0)).into());
+        }
+        if self.names.contains_key(&entry.name) {
+            return Err(Error::InvalidAsset(format!("name {} is taken", entry.name)).into());
+        }
+
+        self.names.insert(entry.name.clone(), entry.id);
+        self.entries.insert(entry.id, entry);
+
+        Ok(())
+    }
+
+    pub fn get(&self, asset: Asset) -> Option<&AssetEntry> {
+        self.entries.get(&asset)
+    }
+
+    pub fn named(&self, name: &str) -> Option<Asset> {
+        self.names.get(name).cloned()
+    }
+
+    pub fn contains(&self, asset: Asset) -> bool {
+        self.entries.contains_key(&asset)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AssetEntry> {
+        self.entries.values()
+    }
+
+    ///
+    /// Report the uses of unknown assets, failing on the first one
+    ///
+    fn check<I: IntoIterator<Item = (String, Asset)>>(&self, uses: I) -> Result<()> {
+        let missing: Vec<_> = uses
+            .into_iter()
+            .filter(|(_, asset)| !self.contains(*asset))
+            .collect();
+
+        for (user, asset) in &missing {
+            warn!("{} uses unknown asset {}", user, asset.0);
+        }
+
+        match missing.into_iter().next() {
+            Some((user, asset)) => Err(Error::UnknownAsset(asset.0, user).into()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn validate_terrain(&self, terrain: &[Terrain]) -> Result<()> {
+        self.check(
+            terrain
+                .iter()
+                .map(|t| (format!("terrain {}", t.id), t.asset)),
+        )
+    }
+
+    pub fn validate_level(&self, level: &Level) -> Result<()> {
+        let rects = level.terrain.iter().chain(level.blocks.iter());
+        let landmarks = level.landmarks.iter().map(|l| l.asset);
+        let items = level.items.iter().map(|i| i.asset);
+
+        self.check(
+            rects
+                .map(|r| r.asset)
+                .chain(landmarks)
+                .chain(items)
+                .map(|asset| (format!("level {}", level.name), asset)),
+        )
+    }
+
+    pub fn validate_prefabs(&self, prefabs: &Prefabs) -> Result<()> {
+        self.check(prefabs.iter().filter_map(|p| {
+            p.components
+                .asset
+                .map(|a| (format!("prefab {}", p.name), a))
+        }))
+    }
+}
+
+impl Systems {
+    ///
+    /// Replace the assets used to pick the appearance of new entities
+    ///
+    pub fn set_assets(&mut self, assets: Assets) {
+        *self.world.write_resource::<Assets>() = assets;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{entities::CreateEntity, level::Rect, resources::Action};
+
+    const MANIFEST: &[u8] = br#"[
+        {"id": 1, "name": "ground", "path": "ground.png", "kind": "Sprite"},
+        {"id": 7, "name": "bullet", "path": "bullet.png", "kind": "Sprite"},
+        {"id": 8, "name": "walk", "path": "walk.png", "kind": "Animation",
+         "frames": {"width": 16, "height": 32, "count": 6, "columns": 4}}
+    ]"#;
+
+    fn entry(id: u64, name: &str) -> AssetEntry {
+        AssetEntry {
+            id: Asset(id),
+            name: name.into(),
+            path: format!("{}.png", name),
+            kind: AssetKind::Sprite,
+            frames: None,
+        }
+    }
+
+    #[test]
+    fn manifest_is_looked_up_by_id_and_name() {
+        let assets = Assets::from_slice(MANIFEST).unwrap();
+
+        assert_eq!(assets.len(), 3);
+        assert_eq!(assets.named("walk"), Some(Asset(8)));
+        assert_eq!(assets.get(Asset(1)).unwrap().name, "ground");
+        assert_eq!(assets.named("missing"), None);
+        assert!(!assets.contains(Asset(2)));
+    }
+
+    #[test]
+    fn taken_ids_and_names_are_refused() {
+        let mut assets = Assets::new();
+        assets.insert(entry(1, "ground")).unwrap();
+
+        assert!(assets.insert(entry(1, "grass")).is_err());
+        assert!(assets.insert(entry(2, "ground")).is_err());
+        assert_eq!(assets.len(), 1);
+        assert!(Assets::from_slice(br#"[{"id": 1}]"#).is_err());
+    }
+
+    #[test]
+    fn frames_wrap_into_rows() {
+        let assets = Assets::from_slice(MANIFEST).unwrap();
+        let frames = assets.get(Asset(8)).unwrap().frames.as_ref().unwrap();
+
+        assert_eq!(frames.offset(0), (0, 0));
+        assert_eq!(frames.offset(3), (48, 0));
+        assert_eq!(frames.offset(5), (16, 32));
+        // Past the last frame starts over
+        assert_eq!(frames.offset(6), (0, 0));
+    }
+
+    #[test]
+    fn unknown_assets_are_reported() {
+        let assets = Assets::from_slice(MANIFEST).unwrap();
+        let mut level = Level::new("castle");
+        level.blocks.push(Rect {
+            pos: Pos::zero(),
+            size: Size::new(10.0, 10.0),
+            asset: Asset(1),
+        });
+        assert!(assets.validate_level(&level).is_ok());
+
+        level.terrain.push(Rect {
+            pos: Pos::zero(),
+            size: Size::new(10.0, 10.0),
+            asset: Asset(3),
+        });
+        match assets
+            .validate_level(&level)
+            .unwrap_err()
+            .downcast::<Error>()
+        {
+            Ok(Error::UnknownAsset(3, user)) => assert_eq!(user, "level castle"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut prefabs = Prefabs::new();
+        prefabs
+            .load(br#"[{"name": "coin", "components": {"asset": 9}}]"#)
+            .unwrap();
+        assert!(assets.validate_prefabs(&prefabs).is_err());
+        assert!(assets.validate_prefabs(&Prefabs::builtin()).is_ok());
+    }
+
+    fn bullet_asset(assets: Assets) -> Asset {
+        let mut systems = Systems::new().unwrap();
+        systems.set_assets(assets);
+        let player = Player {
+            id: 1,
+            class: CLASS_CHIBA,
+            lives: 3,
+        };
+        systems
+            .create_entity()
+            .create_user(Pos::zero(), Size::new(10.0, 10.0), player, Asset(1));
+        systems.add_action(Action {
+            take: true,
+            update: true,
+            ..Action::default()
+        });
+        systems.update();
+
+        let bullets = systems.world.read_storage::<Bullet>();
+        let assets = systems.world.read_storage::<Asset>();
+        let shot: Vec<_> = (&bullets, &assets).join().map(|(_, a)| *a).collect();
+        assert_eq!(shot.len(), 1);
+        shot[0]
+    }
+
+    #[test]
+    fn bullets_take_the_named_asset() {
+        assert_eq!(
+            bullet_asset(Assets::from_slice(MANIFEST).unwrap()),
+            Asset(7)
+        );
+        assert_eq!(bullet_asset(Assets::new()), DEFAULT_BULLET);
+    }
+}
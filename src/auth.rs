@@ -0,0 +1,316 @@
+use crate::{components::Class, error::Result, protocol::*};
+use hmac::Hmac;
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{fs, path::Path};
+
+/// PBKDF2 iterations for new password hashes, unless set otherwise
+pub const ROUNDS: u32 = 100_000;
+
+/// Length of the derived key, in bytes
+const KEY_LEN: usize = 32;
+
+///
+/// Server-side check of the credentials sent on login
+///
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, login: &Login) -> std::result::Result<Account, LoginRejection>;
+}
+
+///
+/// Account entry of the file read by `FileAuth`
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccountEntry {
+    pub id: u64,
+    pub name: String,
+    pub username: String,
+    pub salt: String,
+    /// PBKDF2-HMAC-SHA256 of the password, as written by `hash_password`
+    pub password: String,
+    /// Tokens accepted instead of the password
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// Classes the account may play, any if empty
+    #[serde(default)]
+    pub classes: Vec<Class>,
+}
+
+impl AccountEntry {
+    fn account(&self) -> Account {
+        Account {
+            id: self.id,
+            name: self.name.clone(),
+        }
+    }
+}
+
+///
+/// Derive the stored form of a password, `pbkdf2-sha256$<rounds>$<hex key>`
+///
+pub fn hash_password(salt: &str, password: &str, rounds: u32) -> String {
+    format!(
+        "pbkdf2-sha256${}${}",
+        rounds,
+        derive(salt, password, rounds)
+    )
+}
+
+///
+/// Check a password against the stored form given by `hash_password`
+///
+pub fn verify_password(hash: &str, salt: &str, password: &str) -> bool {
+    let mut parts = hash.splitn(3, '$');
+    match (parts.next(), parts.next().map(str::parse), parts.next()) {
+        (Some("pbkdf2-sha256"), Some(Ok(rounds)), Some(key)) if rounds > 0 => {
+            same(key, &derive(salt, password, rounds))
+        }
+        _ => {
+            warn!("Unsupported password hash, it must be made by hash_password");
+            false
+        }
+    }
+}
+
+fn derive(salt: &str, password: &str, rounds: u32) -> String {
+    let mut key = [0; KEY_LEN];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(
+        password.as_bytes(),
+        salt.as_bytes(),
+        rounds as usize,
+        &mut key,
+    );
+
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+///
+/// Accounts read from a JSON file, for local servers
+///
+pub struct FileAuth {
+    accounts: Vec<AccountEntry>,
+    rounds: u32,
+}
+
+impl FileAuth {
+    pub fn new(accounts: Vec<AccountEntry>) -> Self {
+        Self {
+            accounts,
+            rounds: ROUNDS,
+        }
+    }
+
+    ///
+    /// Set the PBKDF2 iterations of the passwords hashed by `add`
+    ///
+    /// Unknown users are checked with as many, so it should match the
+    /// accounts already in the file.
+    ///
+    pub fn with_rounds(mut self, rounds: u32) -> Self {
+        self.rounds = rounds;
+        self
+    }
+
+    ///
+    /// Add an account, storing its password hashed
+    ///
+    pub fn add(
+        &mut self,
+        id: u64,
+        name: &str,
+        username: &str,
+        salt: &str,
+        password: &str,
+    ) -> &mut AccountEntry {
+        self.accounts.push(AccountEntry {
+            id,
+            name: name.into(),
+            username: username.into(),
+            salt: salt.into(),
+            password: hash_password(salt, password, self.rounds),
+            tokens: Vec::new(),
+            classes: Vec::new(),
+        });
+
+        self.accounts.last_mut().unwrap()
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let accounts: Vec<AccountEntry> = serde_json::from_slice(&fs::read(path.as_ref())?)?;
+        info!(
+            "Loaded {} accounts from {}",
+            accounts.len(),
+            path.as_ref().display()
+        );
+
+        Ok(Self::new(accounts))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(&self.accounts)?)?;
+        Ok(())
+    }
+
+    fn find(
+        &self,
+        credentials: &Credentials,
+    ) -> std::result::Result<&AccountEntry, LoginRejection> {
+        match credentials {
+            Credentials::Token(token) => self
+                .accounts
+                .iter()
+                .find(|a| a.tokens.iter().any(|t| same(t, token)))
+                .ok_or(LoginRejection::InvalidToken),
+            Credentials::Password { username, password } => {
+                let entry = self.accounts.iter().find(|a| a.username == *username);
+
+                // Unknown users take as long as wrong passwords, and get the
+                // same answer, so that names can't be probed
+                let valid = match entry {
+                    Some(e) => verify_password(&e.password, &e.salt, password),
+                    None => {
+                        derive(username, password, self.rounds);
+                        false
+                    }
+                };
+
+                entry
+                    .filter(|_| valid)
+                    .ok_or(LoginRejection::BadCredentials)
+            }
+        }
+    }
+}
+
+impl Authenticator for FileAuth {
+    fn authenticate(&self, login: &Login) -> std::result::Result<Account, LoginRejection> {
+        let credentials = login
+            .credentials
+            .as_ref()
+            .ok_or(LoginRejection::MissingCredentials)?;
+        let entry = self.find(credentials)?;
+
+        if !entry.classes.is_empty() && !entry.classes.contains(&login.cls) {
+            return Err(LoginRejection::ClassUnavailable(login.cls));
+        }
+
+        Ok(entry.account())
+    }
+}
+
+///
+/// Compare secrets in time independent of where they differ
+///
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::*;
+
+    // Far fewer than `ROUNDS`, to keep the tests fast
+    const TEST_ROUNDS: u32 = 1000;
+
+    fn auth() -> FileAuth {
+        let mut auth = FileAuth::new(Vec::new()).with_rounds(TEST_ROUNDS);
+        let entry = auth.add(1, "Alice", "alice", "pepper", "hunter2");
+        entry.tokens.push("t0k3n".into());
+        entry.classes.push(CLASS_CHIBA);
+
+        auth
+    }
+
+    fn login(credentials: Credentials) -> Login {
+        Login {
+            cls: CLASS_CHIBA,
+            session: None,
+            credentials: Some(credentials),
+            spectator: false,
+        }
+    }
+
+    fn password(username: &str, password: &str) -> Credentials {
+        Credentials::Password {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    #[test]
+    fn passwords_are_salted_and_stretched() {
+        let hash = hash_password("pepper", "hunter2", TEST_ROUNDS);
+
+        assert!(hash.starts_with("pbkdf2-sha256$1000$"));
+        assert_ne!(hash, hash_password("salt", "hunter2", TEST_ROUNDS));
+        assert!(verify_password(&hash, "pepper", "hunter2"));
+        assert!(!verify_password(&hash, "pepper", "hunter3"));
+
+        let single = format!("pbkdf2-sha256$1${}", derive("pepper", "hunter2", 1));
+        assert!(verify_password(&single, "pepper", "hunter2"));
+        assert!(!verify_password(
+            &derive("pepper", "hunter2", 1),
+            "pepper",
+            "hunter2"
+        ));
+    }
+
+    #[test]
+    fn accounts_use_the_configured_rounds() {
+        let mut auth = FileAuth::new(Vec::new());
+        assert_eq!(auth.rounds, ROUNDS);
+
+        auth = auth.with_rounds(TEST_ROUNDS);
+        let entry = auth.add(2, "Bob", "bob", "salt", "secret");
+        assert!(entry.password.starts_with("pbkdf2-sha256$1000$"));
+    }
+
+    #[test]
+    fn unknown_users_and_wrong_passwords_look_the_same() {
+        let auth = auth();
+
+        let account = auth
+            .authenticate(&login(password("alice", "hunter2")))
+            .unwrap();
+        assert_eq!(account.id, 1);
+
+        for (user, pass) in &[("alice", "hunter3"), ("bob", "hunter2")] {
+            match auth.authenticate(&login(password(user, pass))) {
+                Err(LoginRejection::BadCredentials) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_and_classes_are_checked() {
+        let auth = auth();
+
+        assert!(auth
+            .authenticate(&login(Credentials::Token("t0k3n".into())))
+            .is_ok());
+        assert!(matches!(
+            auth.authenticate(&login(Credentials::Token("t0k3m".into()))),
+            Err(LoginRejection::InvalidToken)
+        ));
+
+        let mut saitama = login(Credentials::Token("t0k3n".into()));
+        saitama.cls = CLASS_SAITAMA;
+        assert!(matches!(
+            auth.authenticate(&saitama),
+            Err(LoginRejection::ClassUnavailable(_))
+        ));
+
+        saitama.credentials = None;
+        assert!(matches!(
+            auth.authenticate(&saitama),
+            Err(LoginRejection::MissingCredentials)
+        ));
+    }
+}
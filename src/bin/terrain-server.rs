@@ -0,0 +1,71 @@
+use env_logger::Env;
+use gunma::{assets::Assets, terrain_server::TerrainServer};
+use std::{env, path::Path, process};
+
+const USAGE: &str = "usage: terrain-server <level file or directory> \
+                     [--addr <address>] [--default <level>] [--assets <asset manifest>]";
+
+struct Args {
+    path: String,
+    addr: String,
+    /// Level of a directory served to clients that don't name one
+    default: Option<String>,
+    assets: Option<String>,
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut path = None;
+    let mut addr = None;
+    let mut default = None;
+    let mut assets = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => addr = Some(args.next()?),
+            "--default" => default = Some(args.next()?),
+            "--assets" => assets = Some(args.next()?),
+            _ if arg.starts_with("--") || path.is_some() => return None,
+            _ => path = Some(arg),
+        }
+    }
+
+    Some(Args {
+        path: path?,
+        addr: addr.unwrap_or_else(|| "127.0.0.1:8080".into()),
+        default,
+        assets,
+    })
+}
+
+fn main() {
+    // Reloads and rejected requests are only reported in the log
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    let args = match parse(env::args().skip(1)) {
+        Some(args) => args,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let server = if Path::new(&args.path).is_dir() {
+        TerrainServer::open_dir(&args.path, args.default.as_deref())
+    } else {
+        TerrainServer::open(&args.path)
+    };
+
+    let server = server.and_then(|s| match &args.assets {
+        Some(assets) => s.with_assets(Assets::open(assets)?),
+        None => Ok(s),
+    });
+
+    let result = server.and_then(|s| {
+        println!("Serving terrain on ws://{}/ws/", args.addr);
+        s.serve(&args.addr)
+    });
+    if let Err(e) = result {
+        eprintln!("terrain-server: {}", e);
+        process::exit(1);
+    }
+}
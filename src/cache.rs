@@ -0,0 +1,213 @@
+use crate::{
+    error::Result,
+    protocol::{ChunkHash, Manifest, Terrain},
+};
+use log::*;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{from_slice, to_vec};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+///
+/// Content-addressed store of terrain chunks on disk
+///
+/// Chunks are stored once under their hash in `objects/`, and the manifest of
+/// each version of a map under `maps/<map>/<version>.json`. The manifest used
+/// last is remembered so the terrain can be reloaded without a server.
+///
+pub struct TerrainCache {
+    dir: PathBuf,
+}
+
+impl TerrainCache {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join("objects"))?;
+        fs::create_dir_all(dir.join("maps"))?;
+
+        Ok(Self { dir })
+    }
+
+    pub fn manifest(&self, map: &str, version: u64) -> Option<Manifest> {
+        self.read(&self.manifest_path(map, version))
+    }
+
+    ///
+    /// Manifest saved by the last call to `save_manifest`
+    ///
+    pub fn last_manifest(&self) -> Option<Manifest> {
+        self.read(&self.dir.join("last.json"))
+    }
+
+    pub fn save_manifest(&self, manifest: &Manifest) -> Result<()> {
+        let path = self.manifest_path(&manifest.map, manifest.version);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        self.write(&path, manifest)?;
+        self.write(&self.dir.join("last.json"), manifest)
+    }
+
+    ///
+    /// Terrain stored under the given hash, if present and intact
+    ///
+    pub fn load(&self, hash: &ChunkHash) -> Option<Vec<Terrain>> {
+        // The hash comes from the server and ends up in a path
+        if hash.0.is_empty() || !hash.0.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let terrain: Vec<Terrain> = self.read(&self.object_path(hash))?;
+
+        if ChunkHash::of(&terrain) != *hash {
+            warn!("Cached chunk {} is corrupted", hash.0);
+            return None;
+        }
+
+        Some(terrain)
+    }
+
+    ///
+    /// Store terrain, returning the hash it can be loaded with
+    ///
+    pub fn store(&self, terrain: &[Terrain]) -> Result<ChunkHash> {
+        let hash = ChunkHash::of(terrain);
+        let path = self.object_path(&hash);
+
+        if !path.exists() {
+            self.write(&path, &terrain)?;
+        }
+
+        Ok(hash)
+    }
+
+    ///
+    /// All terrain of the manifest used last, if every chunk is cached
+    ///
+    pub fn last_terrain(&self) -> Option<Vec<Terrain>> {
+        let manifest = self.last_manifest()?;
+
+        let mut terrain = Vec::new();
+        let mut seen = HashSet::new();
+        for entry in &manifest.chunks {
+            let items = self.load(&entry.hash)?;
+            // Terrain overlapping several chunks is stored with each of them
+            terrain.extend(items.into_iter().filter(|t| seen.insert(t.id)));
+        }
+
+        Some(terrain)
+    }
+
+    fn manifest_path(&self, map: &str, version: u64) -> PathBuf {
+        let map: String = map
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        self.dir
+            .join("maps")
+            .join(map)
+            .join(format!("{}.json", version))
+    }
+
+    fn object_path(&self, hash: &ChunkHash) -> PathBuf {
+        self.dir.join("objects").join(format!("{}.json", hash.0))
+    }
+
+    fn read<T: DeserializeOwned>(&self, path: &Path) -> Option<T> {
+        let bytes = fs::read(path).ok()?;
+
+        match from_slice(&bytes) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("Ignoring unreadable cache file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    ///
+    /// Write through a temporary file so readers never see partial content
+    ///
+    fn write<T: Serialize + ?Sized>(&self, path: &Path, value: &T) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, to_vec(value)?)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::*;
+
+    fn terrain(id: u64, x: f32) -> Terrain {
+        Terrain {
+            id,
+            pos: Pos::new(x, 0.0),
+            size: Size::new(10.0, 10.0),
+            asset: Asset(1),
+            block: false,
+        }
+    }
+
+    #[test]
+    fn stored_terrain_loads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TerrainCache::open(dir.path()).unwrap();
+        let items = vec![terrain(1, 0.0), terrain(2, 20.0)];
+
+        let hash = cache.store(&items).unwrap();
+        assert_eq!(cache.store(&items).unwrap(), hash);
+
+        let ids: Vec<_> = cache.load(&hash).unwrap().iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn corrupted_or_unsafe_entries_are_not_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TerrainCache::open(dir.path()).unwrap();
+
+        let hash = cache.store(&[terrain(1, 0.0)]).unwrap();
+        let other = serde_json::to_vec(&[terrain(2, 0.0)]).unwrap();
+        fs::write(cache.object_path(&hash), other).unwrap();
+        assert!(cache.load(&hash).is_none());
+
+        fs::write(cache.object_path(&hash), b"{").unwrap();
+        assert!(cache.load(&hash).is_none());
+
+        assert!(cache.load(&ChunkHash("../last".into())).is_none());
+    }
+
+    #[test]
+    fn last_terrain_needs_every_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TerrainCache::open(dir.path()).unwrap();
+        // The second item overlaps both chunks
+        let items = vec![terrain(1, 10.0), terrain(2, 95.0), terrain(3, 150.0)];
+        let manifest = Manifest::new("map", 3, 100.0, &items);
+        cache.save_manifest(&manifest).unwrap();
+
+        cache.store(&items[..2]).unwrap();
+        assert!(cache.last_terrain().is_none());
+
+        cache.store(&items[1..]).unwrap();
+        let mut ids: Vec<_> = cache.last_terrain().unwrap().iter().map(|t| t.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(cache.manifest("map", 3).unwrap().chunks.len(), 2);
+    }
+}
@@ -0,0 +1,509 @@
+use crate::{
+    components::*,
+    level::Level,
+    resources::{Event, Events, Tick, View},
+    systems::Systems,
+    vector::Vector,
+};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+
+// Targets moving further than this in one turn teleported, and the camera
+// jumps with them
+const TELEPORT: f32 = 100.0;
+
+///
+/// Rectangle of the world, from its lowest to its highest corner
+///
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Bounds {
+    pub min: Pos,
+    pub max: Pos,
+}
+
+impl Bounds {
+    ///
+    /// Smallest rectangle holding the terrain and blocks of a level
+    ///
+    pub fn of_level(level: &Level) -> Option<Bounds> {
+        let mut rects = level.terrain.iter().chain(level.blocks.iter());
+        let first = rects.next()?;
+
+        let init = Bounds {
+            min: first.pos,
+            max: first.pos + first.size,
+        };
+        Some(rects.fold(init, |b, r| Bounds {
+            min: Pos::new(b.min.x.min(r.pos.x), b.min.y.min(r.pos.y)),
+            max: Pos::new(
+                b.max.x.max(r.pos.x + r.size.x),
+                b.max.y.max(r.pos.y + r.size.y),
+            ),
+        }))
+    }
+}
+
+///
+/// View of the world shown on screen
+///
+/// Follows the user, or what a spectator looks at, keeping it inside a
+/// dead zone around the center. It snaps to a target it just acquired or
+/// that teleported. World coordinates go up, screen ones down from the
+/// top-left corner.
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Camera {
+    /// Center of the view, before shaking
+    pub center: Pos,
+    /// Extent of the world shown
+    pub view: Size,
+    /// Extent of the screen in pixels
+    pub screen: Size,
+    /// Half extent of the area around the center the target moves freely in
+    pub dead_zone: Size,
+    /// Share of the way to its goal the camera moves each turn; 1 snaps
+    pub smoothing: f32,
+    /// Distance shown ahead of the target in the direction it faces
+    pub look_ahead: f32,
+    /// Area the view stays inside, if set
+    pub bounds: Option<Bounds>,
+    /// Largest shake offset
+    pub shake_amplitude: f32,
+    /// Shake added when the user is hit, from 0 to 1
+    pub shake_on_hit: f32,
+    /// Shake lost each turn
+    pub shake_decay: f32,
+    /// Skip entities outside the view when building the draw list
+    pub cull: bool,
+    trauma: f32,
+    offset: Vel,
+    last: Pos,
+    tracking: bool,
+    /// Target followed on the last turn and where it was
+    #[serde(default)]
+    focus: Option<(Option<u64>, Pos)>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            center: Pos::zero(),
+            view: Size::new(800.0, 600.0),
+            screen: Size::new(800.0, 600.0),
+            dead_zone: Size::new(40.0, 60.0),
+            smoothing: 0.2,
+            look_ahead: 80.0,
+            bounds: None,
+            shake_amplitude: 12.0,
+            shake_on_hit: 0.5,
+            shake_decay: 0.04,
+            cull: true,
+            trauma: 0.0,
+            offset: Vel::zero(),
+            last: Pos::zero(),
+            tracking: false,
+            focus: None,
+        }
+    }
+}
+
+impl Camera {
+    ///
+    /// Center of the view as shown, with the shake applied
+    ///
+    pub fn pos(&self) -> Pos {
+        self.center + self.offset
+    }
+
+    ///
+    /// Whether the camera followed an entity on the last turn
+    ///
+    pub fn is_tracking(&self) -> bool {
+        self.tracking
+    }
+
+    ///
+    /// Shake the view; `amount` adds up to at most 1
+    ///
+    pub fn shake(&mut self, amount: f32) {
+        self.trauma = (self.trauma + amount).min(1.0);
+    }
+
+    ///
+    /// The camera `alpha` of the way from the previous turn to the last one
+    ///
+    pub fn at(&self, alpha: f32) -> Camera {
+        let alpha = alpha.clamp(0.0, 1.0);
+
+        Camera {
+            center: self.last + (self.center - self.last) * alpha,
+            ..self.clone()
+        }
+    }
+
+    ///
+    /// Where to center on a target at `focus` facing `dir`, looking ahead
+    /// unless `dir` is 0
+    ///
+    fn goal(&self, focus: Pos, dir: f32) -> Pos {
+        let ahead = if dir == 0.0 {
+            0.0
+        } else {
+            self.look_ahead * dir.signum()
+        };
+        focus + Vel::new(ahead, 0.0)
+    }
+
+    ///
+    /// Move towards a target centered at `focus` facing `dir`
+    ///
+    fn follow(&mut self, focus: Pos, dir: f32) {
+        let goal = self.goal(focus, dir);
+
+        // Only the part of the move leaving the dead zone is followed
+        let d = goal - self.center;
+        let excess = Vel::new(
+            d.x - d.x.max(-self.dead_zone.x).min(self.dead_zone.x),
+            d.y - d.y.max(-self.dead_zone.y).min(self.dead_zone.y),
+        );
+
+        self.center += excess * self.smoothing.clamp(0.0, 1.0);
+        self.clamp();
+    }
+
+    ///
+    /// Center on a target at once, with no motion to interpolate
+    ///
+    fn snap(&mut self, focus: Pos, dir: f32) {
+        self.center = self.goal(focus, dir);
+        self.clamp();
+        self.last = self.center;
+    }
+
+    ///
+    /// Keep the view inside the bounds, centering it where it can't fit
+    ///
+    fn clamp(&mut self) {
+        let b = match self.bounds {
+            Some(b) => b,
+            None => return,
+        };
+
+        let half = self.view / 2.0;
+        let axis = |c: f32, min: f32, max: f32, half: f32| {
+            if max - min <= 2.0 * half {
+                (min + max) / 2.0
+            } else {
+                c.max(min + half).min(max - half)
+            }
+        };
+
+        self.center = Pos::new(
+            axis(self.center.x, b.min.x, b.max.x, half.x),
+            axis(self.center.y, b.min.y, b.max.y, half.y),
+        );
+    }
+
+    fn update_shake(&mut self, tick: u64) {
+        self.trauma = (self.trauma - self.shake_decay).max(0.0);
+
+        // Smooth pseudo-noise, the same for the same turn
+        let t = tick as f32;
+        let strength = self.shake_amplitude * self.trauma * self.trauma;
+        self.offset = Vel::new(
+            strength * (t * 1.7).sin() * (t * 0.37).cos(),
+            strength * (t * 2.3).cos() * (t * 0.53).sin(),
+        );
+    }
+
+    fn scale(&self) -> Vel {
+        Vel::new(
+            self.screen.x / self.view.x.max(f32::EPSILON),
+            self.screen.y / self.view.y.max(f32::EPSILON),
+        )
+    }
+
+    ///
+    /// Top-left corner of the view in world coordinates
+    ///
+    fn top_left(&self) -> Pos {
+        let pos = self.pos();
+        Pos::new(pos.x - self.view.x / 2.0, pos.y + self.view.y / 2.0)
+    }
+
+    ///
+    /// Screen pixel showing a point of the world
+    ///
+    pub fn world_to_screen(&self, pos: &Pos) -> Pos {
+        let scale = self.scale();
+        let tl = self.top_left();
+        Pos::new((pos.x - tl.x) * scale.x, (tl.y - pos.y) * scale.y)
+    }
+
+    ///
+    /// Point of the world shown at a screen pixel
+    ///
+    pub fn screen_to_world(&self, pixel: &Pos) -> Pos {
+        let scale = self.scale();
+        let tl = self.top_left();
+        Pos::new(tl.x + pixel.x / scale.x, tl.y - pixel.y / scale.y)
+    }
+
+    ///
+    /// Whether any part of a rectangle of the world is in view
+    ///
+    pub fn is_visible(&self, pos: &Pos, size: &Size) -> bool {
+        let c = self.pos();
+        let half = self.view / 2.0;
+
+        pos.x <= c.x + half.x
+            && pos.x + size.x >= c.x - half.x
+            && pos.y <= c.y + half.y
+            && pos.y + size.y >= c.y - half.y
+    }
+}
+
+///
+/// Move the camera after the world moved
+///
+/// Only the events pushed since `since` are considered, those of this turn.
+///
+pub(crate) struct FollowCamera {
+    pub since: usize,
+}
+
+impl<'a> System<'a> for FollowCamera {
+    type SystemData = (
+        Write<'a, Camera>,
+        Read<'a, View>,
+        Read<'a, Events>,
+        Read<'a, Tick>,
+        ReadStorage<'a, Pos>,
+        ReadStorage<'a, Size>,
+        ReadStorage<'a, Dir>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, User>,
+    );
+
+    fn run(
+        &mut self,
+        (mut camera, view, events, tick, pos, siz, dir, ply, user): Self::SystemData,
+    ) {
+        let center =
+            |pos: &Pos, siz: Option<&Size>| *pos + siz.cloned().unwrap_or_else(Size::zero) / 2.0;
+
+        let user = (&pos, siz.maybe(), dir.maybe(), ply.maybe(), &user)
+            .join()
+            .next();
+        let target = match user {
+            Some((pos, siz, dir, ply, _)) => Some((
+                center(pos, siz),
+                dir.map(|d| d.0).unwrap_or(1.0),
+                ply.map(|p| p.id),
+            )),
+            None => match *view {
+                View::Follow(id) => (&pos, siz.maybe(), dir.maybe(), &ply)
+                    .join()
+                    .find(|(_, _, _, p)| p.id == id)
+                    .map(|(pos, siz, dir, _)| {
+                        (center(pos, siz), dir.map(|d| d.0).unwrap_or(1.0), Some(id))
+                    }),
+                View::Free(pos) => Some((pos, 0.0, None)),
+            },
+        };
+
+        camera.last = camera.center;
+        let previous = camera.focus.take();
+        camera.focus = target.map(|(focus, _, id)| (id, focus));
+        camera.tracking = match target {
+            Some((_, _, id)) => id.is_some() || user.is_some(),
+            None => false,
+        };
+
+        if let Some((focus, dir, id)) = target {
+            let jumped = match previous {
+                Some((last_id, last)) => last_id != id || (focus - last).len() > TELEPORT,
+                None => true,
+            };
+            if jumped {
+                camera.snap(focus, dir);
+            } else {
+                camera.follow(focus, dir);
+            }
+
+            let hits = events.0.iter().skip(self.since).filter(|e| match e {
+                Event::Hit { target, .. } => Some(*target) == id,
+                _ => false,
+            });
+            let amount = hits.count() as f32 * camera.shake_on_hit;
+            camera.shake(amount);
+        }
+
+        camera.update_shake(tick.0);
+    }
+}
+
+impl Systems {
+    pub fn camera(&self) -> Camera {
+        (*self.world.read_resource::<Camera>()).clone()
+    }
+
+    ///
+    /// Replace the camera, e.g. with a copy from `camera` with new settings
+    ///
+    pub fn set_camera(&mut self, camera: Camera) {
+        *self.world.write_resource::<Camera>() = camera;
+    }
+
+    ///
+    /// Resize the screen, keeping the world shown per pixel
+    ///
+    pub fn resize_screen(&mut self, screen: Size) {
+        let mut camera = self.world.write_resource::<Camera>();
+        let scale = camera.scale();
+        camera.view = Size::new(screen.x / scale.x, screen.y / scale.y);
+        camera.screen = screen;
+        camera.clamp();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{entities::CreateEntity, level::Rect};
+
+    fn camera() -> Camera {
+        Camera {
+            smoothing: 1.0,
+            shake_amplitude: 0.0,
+            ..Camera::default()
+        }
+    }
+
+    fn systems() -> (Systems, Entity) {
+        let mut sys = Systems::new().unwrap();
+        sys.set_camera(camera());
+        let player = Player {
+            id: 1,
+            class: CLASS_CHIBA,
+            lives: 3,
+        };
+        let user = sys.create_entity().create_user(
+            Pos::new(1000.0, 1000.0),
+            Size::new(10.0, 10.0),
+            player,
+            Asset(0),
+        );
+        sys.world
+            .write_storage::<Acc>()
+            .insert(user, Acc::zero())
+            .unwrap();
+        (sys, user)
+    }
+
+    #[test]
+    fn screen_and_world_convert_both_ways() {
+        let mut camera = camera();
+        camera.center = Pos::new(100.0, 50.0);
+        camera.screen = Size::new(1600.0, 1200.0);
+
+        let pixel = camera.world_to_screen(&Pos::new(100.0, 50.0));
+        assert_eq!((pixel.x, pixel.y), (800.0, 600.0));
+        let corner = camera.world_to_screen(&Pos::new(-300.0, 350.0));
+        assert_eq!((corner.x, corner.y), (0.0, 0.0));
+
+        let back = camera.screen_to_world(&Pos::new(400.0, 900.0));
+        assert_eq!((back.x, back.y), (-100.0, -100.0));
+    }
+
+    #[test]
+    fn targets_move_freely_in_the_dead_zone() {
+        let mut camera = camera();
+        camera.follow(Pos::new(-60.0, 50.0), 0.0);
+        assert_eq!((camera.center.x, camera.center.y), (-20.0, 0.0));
+
+        camera.follow(Pos::new(-20.0, 0.0), 1.0);
+        assert_eq!(camera.center.x, 20.0);
+    }
+
+    #[test]
+    fn bounds_hold_the_view_or_center_it() {
+        let mut camera = camera();
+        camera.bounds = Some(Bounds {
+            min: Pos::new(0.0, 0.0),
+            max: Pos::new(2000.0, 300.0),
+        });
+
+        camera.snap(Pos::new(-500.0, 900.0), 0.0);
+        assert_eq!((camera.center.x, camera.center.y), (400.0, 150.0));
+        camera.snap(Pos::new(1900.0, 0.0), 1.0);
+        assert_eq!(camera.center.x, 1600.0);
+    }
+
+    #[test]
+    fn free_view_has_no_look_ahead() {
+        let mut sys = Systems::new().unwrap();
+        sys.set_camera(camera());
+        *sys.world.write_resource::<View>() = View::Free(Pos::new(300.0, 0.0));
+
+        sys.update();
+
+        assert_eq!(sys.camera().center.x, 300.0);
+    }
+
+    #[test]
+    fn camera_snaps_to_new_and_teleported_targets() {
+        let (mut sys, user) = systems();
+        let mut smooth = camera();
+        smooth.smoothing = 0.1;
+        sys.set_camera(smooth);
+
+        // Acquired far from the origin, centered at once
+        sys.update();
+        let c = sys.camera();
+        assert_eq!((c.center.x, c.center.y), (1085.0, 1005.0));
+        assert_eq!(c.at(0.5).center.x, 1085.0);
+
+        // Walking eases the camera along
+        sys.world
+            .write_storage::<Vel>()
+            .insert(user, Vel::new(-5.0, 0.0))
+            .unwrap();
+        sys.world
+            .write_storage::<Dir>()
+            .insert(user, Dir(-1.0))
+            .unwrap();
+        sys.update();
+        let eased = sys.camera().center.x;
+        assert!(eased < 1085.0 && eased > 1000.0 - 80.0);
+
+        // Respawning elsewhere is not eased into
+        sys.world
+            .write_storage::<Vel>()
+            .insert(user, Vel::zero())
+            .unwrap();
+        sys.world
+            .write_storage::<Pos>()
+            .insert(user, Pos::new(0.0, 0.0))
+            .unwrap();
+        sys.update();
+        assert_eq!(sys.camera().center.x, 5.0 - 80.0);
+    }
+
+    #[test]
+    fn levels_without_terrain_unbound_the_camera() {
+        let mut sys = Systems::new().unwrap();
+        let mut level = Level::new("castle");
+        level.blocks.push(Rect {
+            pos: Pos::zero(),
+            size: Size::new(100.0, 10.0),
+            asset: Asset(1),
+        });
+
+        sys.load_level(&level);
+        assert!(sys.camera().bounds.is_some());
+
+        sys.load_level(&Level::new("void"));
+        assert!(sys.camera().bounds.is_none());
+    }
+}
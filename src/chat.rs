@@ -0,0 +1,250 @@
+use crate::{
+    components::*,
+    protocol::{now_millis, Chat, ChatRejection, ChatScope},
+    room::Room,
+    validation::RateLimiter,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+///
+/// Hook deciding what players may say
+///
+pub trait ChatFilter: Send + Sync {
+    ///
+    /// Return the text to deliver, possibly censored, or `None` to refuse it
+    ///
+    fn filter(&self, text: &str) -> Option<String>;
+}
+
+///
+/// Filter masking a list of words, case-insensitively
+///
+pub struct WordFilter {
+    words: Vec<String>,
+}
+
+impl WordFilter {
+    pub fn new(words: &[&str]) -> Self {
+        Self {
+            words: words.iter().map(|w| w.to_lowercase()).collect(),
+        }
+    }
+
+    fn mask(&self, word: &str, out: &mut String) {
+        if self.words.contains(&word.to_lowercase()) {
+            out.extend(word.chars().map(|_| '*'));
+        } else {
+            out.push_str(word);
+        }
+    }
+}
+
+impl ChatFilter for WordFilter {
+    ///
+    /// Words are runs of letters and digits, so punctuation around them
+    /// doesn't let them through
+    ///
+    fn filter(&self, text: &str) -> Option<String> {
+        let mut out = String::with_capacity(text.len());
+        let mut start = None;
+
+        for (i, c) in text.char_indices() {
+            match (c.is_alphanumeric(), start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    self.mask(&text[s..i], &mut out);
+                    start = None;
+                    out.push(c);
+                }
+                (false, None) => out.push(c),
+                (true, Some(_)) => {}
+            }
+        }
+        if let Some(s) = start {
+            self.mask(&text[s..], &mut out);
+        }
+
+        Some(out)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatLimits {
+    /// Longest message in characters
+    pub max_len: usize,
+    pub messages_per_sec: f64,
+    pub burst: f64,
+}
+
+impl Default for ChatLimits {
+    fn default() -> Self {
+        Self {
+            max_len: 200,
+            messages_per_sec: 1.0,
+            burst: 5.0,
+        }
+    }
+}
+
+///
+/// Server-side check and fan-out of chat messages
+///
+pub struct ChatRouter {
+    limits: ChatLimits,
+    filter: Option<Box<dyn ChatFilter>>,
+    limiters: HashMap<u64, RateLimiter>,
+}
+
+impl ChatRouter {
+    pub fn new(limits: ChatLimits) -> Self {
+        Self {
+            limits,
+            filter: None,
+            limiters: HashMap::new(),
+        }
+    }
+
+    pub fn with_filter(mut self, filter: Box<dyn ChatFilter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    ///
+    /// Check a message and pick who receives it among `players`
+    ///
+    /// Returns the message to send, stamped with its sender, and the ids of
+    /// its recipients. Team messages reach players of the sender's class only.
+    ///
+    pub fn route(
+        &mut self,
+        sender: u64,
+        name: Option<String>,
+        team: Class,
+        mut chat: Chat,
+        players: &[(u64, Class)],
+    ) -> std::result::Result<(Chat, Vec<u64>), ChatRejection> {
+        let text = chat.text.trim();
+        if text.is_empty() {
+            return Err(ChatRejection::Empty);
+        }
+        if text.chars().count() > self.limits.max_len {
+            return Err(ChatRejection::TooLong {
+                max: self.limits.max_len,
+            });
+        }
+
+        let limits = &self.limits;
+        let limiter = self
+            .limiters
+            .entry(sender)
+            .or_insert_with(|| RateLimiter::new(limits.messages_per_sec, limits.burst));
+        if !limiter.allow() {
+            return Err(ChatRejection::RateLimited);
+        }
+
+        chat.text = match &self.filter {
+            Some(filter) => filter.filter(text).ok_or(ChatRejection::Filtered)?,
+            None => text.to_string(),
+        };
+        chat.from = sender;
+        chat.name = name;
+        chat.sent = now_millis();
+
+        let recipients = players
+            .iter()
+            .filter(|(_, cls)| chat.scope == ChatScope::All || *cls == team)
+            .map(|(id, _)| *id)
+            .collect();
+
+        debug!("Chat from {} ({:?}): {}", sender, chat.scope, chat.text);
+
+        Ok((chat, recipients))
+    }
+
+    ///
+    /// Route a message among the members and spectators of a room
+    ///
+    /// Spectators only receive messages for all players, and their own team
+    /// messages only reach other spectators.
+    ///
+    pub fn route_room(
+        &mut self,
+        room: &Room,
+        sender: u64,
+        name: Option<String>,
+        chat: Chat,
+    ) -> std::result::Result<(Chat, Vec<u64>), ChatRejection> {
+        let mut players: Vec<_> = room.members().iter().map(|m| (m.player, m.cls)).collect();
+        players.extend(room.spectators().iter().map(|&s| (s, CLASS_NEUTRAL)));
+
+        let team = players
+            .iter()
+            .find(|(id, _)| *id == sender)
+            .map(|(_, cls)| *cls)
+            .unwrap_or(CLASS_NEUTRAL);
+
+        self.route(sender, name, team, chat, &players)
+    }
+
+    ///
+    /// Forget the rate limit state of a disconnected player
+    ///
+    pub fn remove(&mut self, player: u64) {
+        self.limiters.remove(&player);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_masked_whatever_surrounds_them() {
+        let filter = WordFilter::new(&["Darn", "heck"]);
+
+        assert_eq!(
+            filter
+                .filter("DARN, what the heck!? darned (heck)")
+                .unwrap(),
+            "****, what the ****!? darned (****)"
+        );
+        assert_eq!(filter.filter("  héck  darn").unwrap(), "  héck  ****");
+    }
+
+    #[test]
+    fn messages_are_checked_and_routed() {
+        let limits = ChatLimits {
+            max_len: 5,
+            messages_per_sec: 0.0,
+            burst: 2.0,
+        };
+        let mut router = ChatRouter::new(limits).with_filter(Box::new(WordFilter::new(&["bad"])));
+        let players = [(1, CLASS_CHIBA), (2, CLASS_SAITAMA), (3, CLASS_CHIBA)];
+        let mut send = |scope, text: &str| {
+            router.route(1, None, CLASS_CHIBA, Chat::new(scope, text), &players)
+        };
+
+        assert!(matches!(
+            send(ChatScope::All, "  "),
+            Err(ChatRejection::Empty)
+        ));
+        assert!(matches!(
+            send(ChatScope::All, "too long"),
+            Err(ChatRejection::TooLong { max: 5 })
+        ));
+
+        let (chat, to) = send(ChatScope::Team, " bad ").unwrap();
+        assert_eq!((chat.text.as_str(), chat.from), ("***", 1));
+        assert_eq!(to, vec![1, 3]);
+
+        let (_, to) = send(ChatScope::All, "hi").unwrap();
+        assert_eq!(to, vec![1, 2, 3]);
+
+        assert!(matches!(
+            send(ChatScope::All, "hi"),
+            Err(ChatRejection::RateLimited)
+        ));
+    }
+}
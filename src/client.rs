@@ -1,110 +1,925 @@
-use crate::{error::Result, protocol::Message};
+use crate::{
+    config::{Backoff, Tls},
+    error::{Error, Result},
+    protocol::{Hello, HelloAck, Message, Rejection, Sequenced},
+    transport::Transport,
+};
 use log::*;
+use native_tls::{Certificate, Identity, TlsConnector};
 use std::{
-    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
-    thread::{spawn, JoinHandle},
+    collections::VecDeque,
+    fs,
+    io::{self, Cursor, ErrorKind, Read, Write},
+    mem,
+    sync::mpsc::{channel, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant},
 };
 use websocket::client::ClientBuilder;
-use websocket::{receiver::Reader, sender::Writer, stream::sync::TcpStream, OwnedMessage};
+use websocket::{
+    dataframe::DataFrame,
+    stream::sync::NetworkStream,
+    ws::{dataframe::DataFrame as _, Message as _},
+    OwnedMessage, WebSocketError,
+};
+
+/// Maximum number of messages kept until the peer acknowledges them
+const MAX_PENDING: usize = 1024;
+
+/// Time given to our close frame to leave before the socket is dropped
+const CLOSE_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Closing,
+    Closed,
+}
+
+///
+/// Transport under a client, from dialing to closed
+///
+enum Link {
+    /// Connection and websocket upgrade running on their own thread
+    Dialing(Receiver<Result<Connection>>),
+    Open(Connection),
+    Down,
+}
 
+///
+/// Websocket connection driven by polling from the game loop
+///
 pub struct Client {
-    wr_tx: Sender<OwnedMessage>,
-    rd_rx: Receiver<OwnedMessage>,
-    _wr_thread: JoinHandle<()>,
-    _rd_thread: JoinHandle<()>,
+    url: String,
+    tls: Tls,
+    timeout: Duration,
+    /// Reconnection policy, none to stay closed once the connection drops
+    backoff: Option<Backoff>,
+    next_seq: u64,
+    unacked: VecDeque<(u64, Message)>,
+    attempts: u32,
+    retry_at: Option<Instant>,
+    /// The last attempt to connect failed, to be delayed by the backoff
+    failed: bool,
+    /// A connection was established since the last call to `reconnect`
+    established: bool,
+    rejection: Option<Rejection>,
+    /// Limit of the handshake or of the close in progress
+    deadline: Option<Instant>,
+    link: Link,
+    inbox: VecDeque<OwnedMessage>,
+    state: ConnectionState,
+    features: Vec<String>,
 }
 
 impl Client {
-    pub fn new(s: &str) -> Result<Self> {
-        let client = ClientBuilder::new(s)?
-            .add_protocol("rust-websocket")
-            .connect_insecure()?;
-
-        let (rd_tx, rd_rx) = channel();
-        let (wr_tx, wr_rx) = channel();
-        let (ws_rx, ws_tx) = client.split()?;
-        let wr_tx2 = wr_tx.clone();
-        let _wr_thread = spawn(move || {
-            wr_loop(ws_tx, wr_rx).unwrap();
-        });
-        let _rd_thread = spawn(move || {
-            rd_loop(ws_rx, wr_tx2, rd_tx).unwrap();
+    ///
+    /// Start connecting to the server, without waiting
+    ///
+    /// The connection and the protocol handshake are carried on by `poll`,
+    /// and given up if not done within `timeout`.
+    ///
+    pub fn connect(s: &str, tls: &Tls, timeout: Duration) -> Self {
+        let mut client = Self {
+            url: s.into(),
+            tls: tls.clone(),
+            timeout,
+            backoff: None,
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            attempts: 0,
+            retry_at: None,
+            failed: false,
+            established: false,
+            rejection: None,
+            deadline: None,
+            link: Link::Down,
+            inbox: VecDeque::new(),
+            state: ConnectionState::Closed,
+            features: Vec::new(),
+        };
+        client.dial();
+
+        client
+    }
+
+    ///
+    /// Re-establish the connection after it drops, waiting between attempts
+    ///
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = Some(backoff);
+        self
+    }
+
+    ///
+    /// Open the websocket on another thread, as it blocks
+    ///
+    fn dial(&mut self) {
+        let (tx, rx) = channel();
+        let (url, tls) = (self.url.clone(), self.tls.clone());
+        thread::spawn(move || {
+            let _ = tx.send(open(&url, &tls));
         });
 
-        Ok(Self {
-            wr_tx,
-            rd_rx,
-            _wr_thread,
-            _rd_thread,
-        })
+        self.link = Link::Dialing(rx);
+        self.state = ConnectionState::Connecting;
+        self.deadline = Some(Instant::now() + self.timeout);
+        self.features.clear();
+    }
+
+    ///
+    /// Exchange protocol version, codec and features with the peer
+    ///
+    fn handshake(&mut self, msg: OwnedMessage) -> Result<()> {
+        match decode(msg)? {
+            Message::HelloAck(HelloAck {
+                rejection: Some(r), ..
+            }) => {
+                error!("Server rejected handshake: {}", r);
+                self.rejection = Some(r.clone());
+                Err(Error::Incompatible(r).into())
+            }
+            Message::HelloAck(ack) => {
+                debug!("Handshake completed: {:?}", ack);
+                self.features = ack.features;
+                self.state = ConnectionState::Connected;
+                self.deadline = None;
+                self.attempts = 0;
+                self.retry_at = None;
+                self.established = true;
+                Ok(())
+            }
+            msg => {
+                error!("Invalid response to hello: {:?}", msg);
+                Err(Error::HandshakeError.into())
+            }
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    ///
+    /// Start closing the connection
+    ///
+    /// It is closed once our close frame is sent, without waiting for the
+    /// peer's, which never comes if the connection is half-open.
+    ///
+    pub fn close(&mut self) {
+        if self.state == ConnectionState::Closing || self.state == ConnectionState::Closed {
+            return;
+        }
+
+        let sent = match &mut self.link {
+            Link::Open(conn) => conn.write(&OwnedMessage::Close(None)).is_ok(),
+            _ => false,
+        };
+        if sent {
+            self.state = ConnectionState::Closing;
+            self.deadline = Some(Instant::now() + Duration::from_millis(CLOSE_TIMEOUT_MS));
+        } else {
+            self.shutdown();
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.link = Link::Down;
+        self.state = ConnectionState::Closed;
+        self.deadline = None;
+    }
+
+    ///
+    /// Re-establish a closed connection, honoring the backoff delay
+    ///
+    /// Connecting is carried on by `poll`; returns `true` once a connection
+    /// has been established since the last call.
+    ///
+    pub fn reconnect(&mut self) -> Result<bool> {
+        if let Some(r) = &self.rejection {
+            return Err(Error::Incompatible(r.clone()).into());
+        }
+        if mem::replace(&mut self.established, false) {
+            info!("Connected to {}", self.url);
+            return Ok(true);
+        }
+        let backoff = match &self.backoff {
+            Some(backoff) => backoff,
+            None => return Ok(false),
+        };
+        if mem::replace(&mut self.failed, false) {
+            self.attempts += 1;
+            let delay = backoff.delay(self.attempts);
+            warn!(
+                "Connecting to {} failed (attempt {}), retry in {:?}",
+                self.url, self.attempts, delay
+            );
+            self.retry_at = Some(Instant::now() + delay);
+        }
+
+        if self.state != ConnectionState::Closed {
+            return Ok(false);
+        }
+        if let Some(at) = self.retry_at {
+            if Instant::now() < at {
+                return Ok(false);
+            }
+        }
+        if let Some(max) = backoff.max_attempts {
+            if self.attempts >= max {
+                return Err(Error::Disconnected.into());
+            }
+        }
+
+        self.dial();
+        Ok(false)
+    }
+
+    ///
+    /// Send a message and keep it until the peer acknowledges it
+    ///
+    /// Messages sent while the connection is down, or lost with it, are sent
+    /// again by `flush` once the session is resumed.
+    ///
+    pub fn send_buffered(&mut self, msg: Message) -> Result<()> {
+        self.next_seq += 1;
+        let seq = self.next_seq;
+
+        if self.unacked.len() >= MAX_PENDING {
+            warn!("Unacknowledged buffer full, dropping oldest message");
+            self.unacked.pop_front();
+        }
+        self.unacked.push_back((seq, msg.clone()));
+
+        if self.state == ConnectionState::Connected {
+            if let Err(e) = self.send_sequenced(seq, msg) {
+                warn!("Keeping message {} after send failure: {}", seq, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_sequenced(&mut self, seq: u64, msg: Message) -> Result<()> {
+        if self.has_feature("ack") {
+            return self.send(Message::Sequenced(Box::new(Sequenced { seq, msg })));
+        }
+
+        // The peer won't acknowledge anything, what was written is all we know
+        self.send(msg)?;
+        self.ack(seq);
+        Ok(())
+    }
+
+    ///
+    /// Forget the messages the peer received, up to `seq`
+    ///
+    pub fn ack(&mut self, seq: u64) {
+        while self
+            .unacked
+            .front()
+            .map(|(s, _)| *s <= seq)
+            .unwrap_or(false)
+        {
+            self.unacked.pop_front();
+        }
+    }
+
+    ///
+    /// Send again all messages not acknowledged yet
+    ///
+    pub fn flush(&mut self) -> Result<()> {
+        let unacked: Vec<_> = self.unacked.iter().cloned().collect();
+        for (seq, msg) in unacked {
+            self.send_sequenced(seq, msg)?;
+        }
+
+        Ok(())
     }
 
     pub fn send(&mut self, msg: Message) -> Result<()> {
-        let msg = OwnedMessage::Binary(serde_json::to_vec(&msg)?);
-        Ok(self.wr_tx.send(msg)?)
+        let conn = match &mut self.link {
+            Link::Open(conn) if self.state == ConnectionState::Connected => conn,
+            _ => return Err(Error::Disconnected.into()),
+        };
+
+        let res = conn.write(&OwnedMessage::Binary(serde_json::to_vec(&msg)?));
+        if res.is_err() {
+            self.shutdown();
+        }
+        res
+    }
+
+    ///
+    /// Move the connection forward and pending bytes in both directions,
+    /// without blocking
+    ///
+    pub fn poll(&mut self) -> Result<()> {
+        if self.state == ConnectionState::Closed {
+            return Ok(());
+        }
+
+        let connecting = self.state == ConnectionState::Connecting;
+        let res = self.pump();
+        if let Err(e) = &res {
+            if connecting {
+                warn!("Couldn't connect to {}: {}", self.url, e);
+                self.failed = true;
+            } else {
+                warn!("Connection to {} lost: {}", self.url, e);
+            }
+            self.shutdown();
+        }
+        res
+    }
+
+    fn pump(&mut self) -> Result<()> {
+        let expired = self.deadline.map(|d| Instant::now() >= d).unwrap_or(false);
+
+        if let Link::Dialing(rx) = &self.link {
+            let mut conn = match rx.try_recv() {
+                Ok(res) => res?,
+                Err(TryRecvError::Empty) if expired => return Err(Error::TimedOut.into()),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => return Err(Error::Disconnected.into()),
+            };
+            let hello = serde_json::to_vec(&Message::Hello(Hello::new()))?;
+            conn.write(&OwnedMessage::Binary(hello))?;
+            self.link = Link::Open(conn);
+        }
+
+        let conn = match &mut self.link {
+            Link::Open(conn) => conn,
+            _ => return Err(Error::Disconnected.into()),
+        };
+
+        conn.flush()?;
+        if self.state == ConnectionState::Closing {
+            if conn.is_flushed() || expired {
+                self.shutdown();
+            }
+            return Ok(());
+        }
+
+        let (msgs, eof) = conn.read()?;
+        for msg in msgs {
+            match msg {
+                OwnedMessage::Close(_) => {
+                    if let Link::Open(conn) = &mut self.link {
+                        let _ = conn.write(&OwnedMessage::Close(None));
+                    }
+                    self.shutdown();
+                    return Ok(());
+                }
+                OwnedMessage::Ping(data) => {
+                    if let Link::Open(conn) = &mut self.link {
+                        conn.write(&OwnedMessage::Pong(data))?;
+                    }
+                }
+                OwnedMessage::Pong(_) => {}
+                msg if self.state == ConnectionState::Connecting => self.handshake(msg)?,
+                msg => self.inbox.push_back(msg),
+            }
+        }
+
+        if eof {
+            return Err(Error::Disconnected.into());
+        }
+        if self.state == ConnectionState::Connecting && expired {
+            return Err(Error::TimedOut.into());
+        }
+        Ok(())
     }
 
     pub fn try_recv(&mut self) -> Result<Option<Message>> {
-        match self.rd_rx.try_recv() {
-            Ok(OwnedMessage::Binary(msg)) => Ok(Some(serde_json::from_slice(&msg)?)),
-            Err(TryRecvError::Empty) => Ok(None),
-            Err(e) => Err(e.into()),
-            _ => unreachable!(),
+        // Messages received before the connection dropped come first
+        let polled = if self.inbox.is_empty() {
+            self.poll()
+        } else {
+            Ok(())
+        };
+
+        match self.inbox.pop_front() {
+            Some(msg) => decode(msg).map(Some),
+            None => {
+                polled?;
+                if self.state == ConnectionState::Closed {
+                    Err(Error::Disconnected.into())
+                } else {
+                    Ok(None)
+                }
+            }
         }
     }
+}
+
+///
+/// Connect and upgrade to a websocket, blocking until done
+///
+fn open(url: &str, tls: &Tls) -> Result<Connection> {
+    let client = ClientBuilder::new(url)?
+        .add_protocol("rust-websocket")
+        .connect(connector(tls)?)?;
+    let (stream, buffered) = client.into_stream();
+
+    Connection::new(stream, buffered)
+}
+
+impl Transport for Client {
+    fn send(&mut self, msg: Message) -> Result<()> {
+        Client::send(self, msg)
+    }
+
+    fn try_recv(&mut self) -> Result<Option<Message>> {
+        Client::try_recv(self)
+    }
+
+    fn state(&self) -> ConnectionState {
+        Client::state(self)
+    }
+
+    fn close(&mut self) {
+        Client::close(self)
+    }
+
+    fn has_feature(&self, feature: &str) -> bool {
+        Client::has_feature(self, feature)
+    }
+
+    fn send_buffered(&mut self, msg: Message) -> Result<()> {
+        Client::send_buffered(self, msg)
+    }
+
+    fn ack(&mut self, seq: u64) {
+        Client::ack(self, seq)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Client::flush(self)
+    }
 
-    pub fn recv(&mut self) -> Result<Message> {
-        match self.rd_rx.recv() {
-            Ok(OwnedMessage::Binary(msg)) => Ok(serde_json::from_slice(&msg)?),
-            Err(e) => Err(e.into()),
-            _ => unreachable!(),
+    fn poll(&mut self) -> Result<bool> {
+        // Failures are logged and retried by `reconnect`
+        let _ = Client::poll(self);
+        Client::reconnect(self)
+    }
+}
+
+fn decode(msg: OwnedMessage) -> Result<Message> {
+    match msg {
+        OwnedMessage::Binary(data) => {
+            serde_json::from_slice(&data).map_err(|e| Error::MalformedMessage(e.to_string()).into())
         }
+        msg => Err(Error::UnexpectedFrame(format!("{:?}", msg)).into()),
+    }
+}
+
+///
+/// Build the TLS connector described by the configuration
+///
+/// `None` leaves the system defaults in place.
+///
+fn connector(tls: &Tls) -> Result<Option<TlsConnector>> {
+    if tls.ca_roots.is_empty() && tls.identity.is_none() && !tls.accept_invalid_certs {
+        return Ok(None);
     }
+
+    let mut builder = TlsConnector::builder();
+
+    for path in &tls.ca_roots {
+        builder.add_root_certificate(Certificate::from_pem(&fs::read(path)?)?);
+    }
+    if let Some(identity) = &tls.identity {
+        let der = fs::read(&identity.pkcs12)?;
+        builder.identity(Identity::from_pkcs12(&der, &identity.password)?);
+    }
+    builder.danger_accept_invalid_certs(tls.accept_invalid_certs);
+
+    Ok(Some(builder.build()?))
 }
 
-fn wr_loop(mut ws_tx: Writer<TcpStream>, rx: Receiver<OwnedMessage>) -> Result<()> {
-    loop {
-        let msg = match rx.recv() {
-            Ok(msg) => msg,
-            Err(_) => break,
+///
+/// Websocket framing over a non-blocking plain or TLS stream
+///
+/// Partial frames are kept in `rd_buf` until complete, and bytes the
+/// socket could not take yet wait in `wr_buf`.
+///
+struct Connection {
+    stream: Box<dyn NetworkStream + Send>,
+    rd_buf: Vec<u8>,
+    wr_buf: Vec<u8>,
+    frames: Vec<DataFrame>,
+}
+
+impl Connection {
+    fn new(
+        stream: Box<dyn NetworkStream + Send>,
+        buffered: Option<(Vec<u8>, usize, usize)>,
+    ) -> Result<Self> {
+        stream.as_tcp().set_nonblocking(true)?;
+
+        let rd_buf = match buffered {
+            Some((buf, pos, cap)) => buf[pos..cap].to_vec(),
+            None => Vec::new(),
         };
 
-        match msg {
-            OwnedMessage::Close(_) => {
-                ws_tx.send_message(&msg)?;
-                break;
+        Ok(Self {
+            stream,
+            rd_buf,
+            wr_buf: Vec::new(),
+            frames: Vec::new(),
+        })
+    }
+
+    fn is_flushed(&self) -> bool {
+        self.wr_buf.is_empty()
+    }
+
+    fn write(&mut self, msg: &OwnedMessage) -> Result<()> {
+        msg.serialize(&mut self.wr_buf, true)?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        while !self.wr_buf.is_empty() {
+            match self.stream.write(&self.wr_buf) {
+                Ok(0) => return Err(Error::Disconnected.into()),
+                Ok(n) => {
+                    self.wr_buf.drain(..n);
+                }
+                Err(ref e) if would_block(e) => return Ok(()),
+                Err(e) => return Err(e.into()),
             }
-            msg => ws_tx.send_message(&msg)?,
+        }
+
+        match self.stream.flush() {
+            Err(ref e) if would_block(e) => Ok(()),
+            r => Ok(r?),
         }
     }
 
-    Ok(())
-}
+    ///
+    /// Read what is available and return the messages completed by it
+    ///
+    /// Also tells if the peer closed the stream, after the messages it sent
+    /// before doing so.
+    ///
+    fn read(&mut self) -> Result<(Vec<OwnedMessage>, bool)> {
+        let mut chunk = [0u8; 4096];
+        let mut eof = false;
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(n) => self.rd_buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if would_block(e) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut msgs = Vec::new();
+
+        loop {
+            let mut cur = Cursor::new(&self.rd_buf[..]);
+            let frame = match DataFrame::read_dataframe(&mut cur, false) {
+                Ok(frame) => frame,
+                Err(WebSocketError::IoError(ref e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(WebSocketError::NoDataAvailable) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let used = cur.position() as usize;
+            self.rd_buf.drain(..used);
 
-fn rd_loop(
-    mut ws_rx: Reader<TcpStream>,
-    ws_tx: Sender<OwnedMessage>,
-    tx: Sender<OwnedMessage>,
-) -> Result<()> {
-    for msg in ws_rx.incoming_messages() {
-        match msg {
-            Ok(OwnedMessage::Close(_)) => {
-                ws_tx.send(OwnedMessage::Close(None))?;
+            if frame.opcode() >= 8 {
+                msgs.push(OwnedMessage::from_dataframes(vec![frame])?);
+            } else {
+                let last = frame.is_last();
+                self.frames.push(frame);
+                if last {
+                    let frames = mem::take(&mut self.frames);
+                    msgs.push(OwnedMessage::from_dataframes(frames)?);
+                }
             }
-            Ok(OwnedMessage::Ping(data)) => {
-                ws_tx.send(OwnedMessage::Pong(data))?;
+        }
+
+        Ok((msgs, eof))
+    }
+}
+
+fn would_block(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::mpsc::channel,
+        thread::{self, sleep},
+    };
+
+    ///
+    /// Connect and wait for the protocol handshake
+    ///
+    fn connected(url: &str, tls: &Tls) -> Result<Client> {
+        let mut client = Client::connect(url, tls, Duration::from_secs(5));
+
+        while client.state == ConnectionState::Connecting {
+            client.poll()?;
+            sleep(Duration::from_millis(1));
+        }
+        if client.state != ConnectionState::Connected {
+            return Err(Error::Disconnected.into());
+        }
+        client.established = false;
+
+        Ok(client)
+    }
+
+    ///
+    /// Answer the handshake of a connection and give every other message to
+    /// `on_msg`, which returns the replies or `None` to drop the connection
+    ///
+    fn answer<S, F>(mut client: websocket::sync::Client<S>, conn: usize, on_msg: &mut F)
+    where
+        S: websocket::stream::sync::Stream,
+        F: FnMut(usize, Message) -> Option<Vec<Message>>,
+    {
+        loop {
+            let msg = match client.recv_message() {
+                Ok(OwnedMessage::Binary(data)) => serde_json::from_slice(&data).unwrap(),
+                Ok(OwnedMessage::Close(_)) | Err(_) => break,
+                Ok(_) => continue,
+            };
+            let replies = match msg {
+                Message::Hello(hello) => Some(vec![Message::HelloAck(hello.accept())]),
+                msg => on_msg(conn, msg),
+            };
+            let replies = match replies {
+                Some(replies) => replies,
+                None => break,
+            };
+            for reply in replies {
+                let data = serde_json::to_vec(&reply).unwrap();
+                let _ = client.send_message(&OwnedMessage::Binary(data));
             }
-            Ok(OwnedMessage::Binary(data)) => {
-                tx.send(OwnedMessage::Binary(data))?;
+        }
+    }
+
+    ///
+    /// Websocket server answering its first `conns` connections in turn
+    ///
+    fn server<F>(conns: usize, mut on_msg: F) -> String
+    where
+        F: FnMut(usize, Message) -> Option<Vec<Message>> + Send + 'static,
+    {
+        let server = websocket::sync::Server::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+
+        thread::spawn(move || {
+            let reqs = server.filter_map(|r| r.ok()).take(conns);
+            for (n, req) in reqs.enumerate() {
+                answer(req.accept().unwrap(), n, &mut on_msg);
             }
-            Ok(msg) => warn!("Received unsupported message: {:?}", msg),
-            Err(e) => {
-                warn!("Receive error: {}", e);
-                ws_tx.send(OwnedMessage::Close(None))?;
-                break;
+        });
+
+        url
+    }
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    ///
+    /// Secure websocket server with a self-signed certificate for localhost,
+    /// answering terrain requests with an empty map
+    ///
+    fn tls_server() -> String {
+        let der = fs::read(fixture("localhost.p12")).unwrap();
+        let identity = Identity::from_pkcs12(&der, "gunma").unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        let server = websocket::sync::Server::bind_secure("127.0.0.1:0", acceptor).unwrap();
+        let url = format!("wss://localhost:{}", server.local_addr().unwrap().port());
+
+        thread::spawn(move || {
+            let mut on_msg = |_, _| Some(vec![Message::EndTerrain]);
+            for req in server.filter_map(|r| r.ok()) {
+                if let Ok(client) = req.accept() {
+                    answer(client, 0, &mut on_msg);
+                }
             }
+        });
+
+        url
+    }
+
+    fn wait_until<F: FnMut() -> bool>(mut done: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            sleep(Duration::from_millis(1));
         }
     }
 
-    Ok(())
+    #[test]
+    fn unacknowledged_messages_are_sent_again() {
+        let (tx, rx) = channel();
+        let url = server(2, move |conn, msg| {
+            let seq = match msg {
+                Message::Sequenced(s) => s.seq,
+                _ => return Some(vec![]),
+            };
+            tx.send((conn, seq)).unwrap();
+            match (conn, seq) {
+                (0, 1) => Some(vec![Message::Ack(1)]),
+                (0, _) => None,
+                _ => Some(vec![]),
+            }
+        });
+
+        let mut client = connected(&url, &Tls::default())
+            .unwrap()
+            .with_backoff(Backoff::default());
+        assert!(client.has_feature("ack"));
+        client.send_buffered(Message::GetAllTerrain).unwrap();
+        client.send_buffered(Message::GetAllTerrain).unwrap();
+
+        wait_until(|| match client.try_recv() {
+            Ok(Some(Message::Ack(seq))) => {
+                client.ack(seq);
+                false
+            }
+            Ok(_) => false,
+            Err(_) => client.state() == ConnectionState::Closed,
+        });
+
+        client.send_buffered(Message::GetAllTerrain).unwrap();
+        wait_until(|| Transport::poll(&mut client).unwrap());
+        client.flush().unwrap();
+
+        let seen: Vec<_> = rx.iter().take(4).collect();
+        assert_eq!(seen, vec![(0, 1), (0, 2), (1, 2), (1, 3)]);
+    }
+
+    #[test]
+    fn bad_frames_give_typed_errors() {
+        let e = decode(OwnedMessage::Binary(b"{".to_vec())).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::MalformedMessage(_))));
+
+        let e = decode(OwnedMessage::Text("hi".into())).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::UnexpectedFrame(_))));
+    }
+
+    #[test]
+    fn messages_decode() {
+        let bytes = serde_json::to_vec(&Message::EndTerrain).unwrap();
+        assert!(matches!(
+            decode(OwnedMessage::Binary(bytes)),
+            Ok(Message::EndTerrain)
+        ));
+    }
+
+    #[test]
+    fn wss_with_self_signed_root() {
+        let url = tls_server();
+        let tls = Tls {
+            ca_roots: vec![fixture("localhost.pem")],
+            ..Tls::default()
+        };
+
+        let mut client = connected(&url, &tls).unwrap();
+        assert_eq!(client.state(), ConnectionState::Connected);
+
+        client.send(Message::GetAllTerrain).unwrap();
+        let mut reply = None;
+        wait_until(|| {
+            reply = client.try_recv().unwrap();
+            reply.is_some()
+        });
+        assert!(matches!(reply, Some(Message::EndTerrain)));
+    }
+
+    #[test]
+    fn wss_refuses_untrusted_certificate() {
+        let url = tls_server();
+
+        assert!(connected(&url, &Tls::default()).is_err());
+    }
+
+    #[test]
+    fn wss_can_skip_verification() {
+        let url = tls_server();
+        let tls = Tls {
+            accept_invalid_certs: true,
+            ..Tls::default()
+        };
+
+        let client = connected(&url, &tls).unwrap();
+        assert_eq!(client.state(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn connecting_never_blocks() {
+        // Accepts connections but never upgrades them
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let start = Instant::now();
+        let mut client = Client::connect(&url, &Tls::default(), Duration::from_millis(50));
+        assert_eq!(client.state(), ConnectionState::Connecting);
+        assert!(client.poll().is_ok());
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        let mut res = Ok(());
+        wait_until(|| {
+            res = client.poll();
+            client.state() == ConnectionState::Closed
+        });
+        let e = res.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::TimedOut)));
+    }
+
+    #[test]
+    fn close_completes_without_the_peer() {
+        // The server stops reading, as over a half-open connection
+        let url = server(1, |_, _| {
+            sleep(Duration::from_secs(5));
+            None
+        });
+        let mut client = connected(&url, &Tls::default()).unwrap();
+        client.send(Message::GetAllTerrain).unwrap();
+
+        client.close();
+        assert_eq!(client.state(), ConnectionState::Closing);
+        let start = Instant::now();
+        wait_until(|| {
+            let _ = client.poll();
+            client.state() == ConnectionState::Closed
+        });
+        assert!(start.elapsed() < Duration::from_millis(CLOSE_TIMEOUT_MS));
+    }
+
+    #[test]
+    fn rejected_handshake_is_not_retried() {
+        let server = websocket::sync::Server::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        thread::spawn(move || {
+            for req in server.filter_map(|r| r.ok()) {
+                let mut client = req.accept().unwrap();
+                let _ = client.recv_message();
+                let hello = Hello {
+                    version: 0,
+                    ..Hello::new()
+                };
+                let data = serde_json::to_vec(&Message::HelloAck(hello.accept())).unwrap();
+                let _ = client.send_message(&OwnedMessage::Binary(data));
+            }
+        });
+
+        let e = connected(&url, &Tls::default()).err().unwrap();
+        assert!(matches!(e.downcast_ref(), Some(Error::Incompatible(_))));
+
+        let mut client = Client::connect(&url, &Tls::default(), Duration::from_secs(5))
+            .with_backoff(Backoff::default());
+        wait_until(|| {
+            let _ = client.poll();
+            client.state() == ConnectionState::Closed
+        });
+        let e = client.reconnect().unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Incompatible(_))));
+    }
+
+    #[test]
+    fn failed_attempts_back_off() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let backoff = Backoff {
+            initial_ms: 10_000,
+            max_ms: 10_000,
+            max_attempts: Some(1),
+        };
+        let mut client =
+            Client::connect(&url, &Tls::default(), Duration::from_secs(5)).with_backoff(backoff);
+        wait_until(|| {
+            let _ = client.poll();
+            client.state() == ConnectionState::Closed
+        });
+
+        // The next attempt waits for the delay, then is the last one
+        assert!(!client.reconnect().unwrap());
+        assert_eq!(client.state(), ConnectionState::Closed);
+        client.retry_at = Some(Instant::now());
+        assert!(client.reconnect().is_err());
+    }
 }
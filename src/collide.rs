@@ -1,47 +1,90 @@
 use crate::components::*;
 
-use ncollide2d::{
-    math::Isometry,
-    query::{contact, time_of_impact},
-    shape::Cuboid,
-};
+// Contacts are reported up to this distance apart
+const PREDICTION: f32 = 3.0;
+
+///
+/// Distance between two intervals on one axis, negative if they overlap,
+/// and the direction from the first to the second
+///
+fn gap(a: f32, sa: f32, b: f32, sb: f32) -> (f32, f32) {
+    let right = b - (a + sa);
+    let left = a - (b + sb);
+
+    if right > left {
+        (right, 1.0)
+    } else {
+        (left, -1.0)
+    }
+}
+
+///
+/// Times at which an interval moving at `v` starts and stops touching a
+/// still one, if it ever does
+///
+fn sweep(a: f32, sa: f32, v: f32, b: f32, sb: f32) -> Option<(f32, f32)> {
+    if v == 0.0 {
+        if a <= b + sb && b <= a + sa {
+            Some((f32::NEG_INFINITY, f32::INFINITY))
+        } else {
+            None
+        }
+    } else {
+        let t1 = (b - (a + sa)) / v;
+        let t2 = (b + sb - a) / v;
+        Some((t1.min(t2), t1.max(t2)))
+    }
+}
 
 pub fn toi(p1: &Pos, s1: &Size, v1: &Vel, p2: &Pos, s2: &Size, v2: &Vel) -> f32 {
-    let m1 = *p1 + *s1 / 2.0;
-    let m1 = Isometry::translation(m1.x, m1.y);
-    let c1 = Cuboid::new((*s1 / 2.0).to_vec());
-    let v1 = v1.to_vec();
-
-    let m2 = *p2 + *s2 / 2.0;
-    let m2 = Isometry::translation(m2.x, m2.y);
-    let c2 = Cuboid::new((*s2 / 2.0).to_vec());
-    let v2 = v2.to_vec();
-
-    time_of_impact(&m1, &v1, &c1, &m2, &v2, &c2)
-        .unwrap_or(1.0)
-        .min(1.0)
+    let v = *v1 - *v2;
+    if v.x == 0.0 && v.y == 0.0 {
+        return 1.0;
+    }
+
+    let x = sweep(p1.x, s1.x, v.x, p2.x, s2.x);
+    let y = sweep(p1.y, s1.y, v.y, p2.y, s2.y);
+
+    match (x, y) {
+        (Some((ex, lx)), Some((ey, ly))) => {
+            let (enter, exit) = (ex.max(ey), lx.min(ly));
+            if enter <= exit && exit >= 0.0 {
+                enter.clamp(0.0, 1.0)
+            } else {
+                1.0
+            }
+        }
+        _ => 1.0,
+    }
 }
 
 pub fn normal(p1: &Pos, s1: &Size, p2: &Pos, s2: &Size) -> Option<Vel> {
-    let m1 = *p1 + *s1 / 2.0;
-    let m1 = Isometry::translation(m1.x, m1.y);
-    let c1 = Cuboid::new((*s1 / 2.0).to_vec());
-
-    let m2 = *p2 + *s2 / 2.0;
-    let m2 = Isometry::translation(m2.x, m2.y);
-    let c2 = Cuboid::new((*s2 / 2.0).to_vec());
-
-    contact(&m1, &c1, &m2, &c2, 3.0).map(|c| {
-        let x = c.normal.as_ref()[0].round();
-        let y = c.normal.as_ref()[1].round();
-        Vel::new(x, y)
-    })
+    let (gx, dx) = gap(p1.x, s1.x, p2.x, s2.x);
+    let (gy, dy) = gap(p1.y, s1.y, p2.y, s2.y);
+
+    if gx > 0.0 && gy > 0.0 {
+        // Apart diagonally, the normal points from corner to corner
+        let d = (gx * gx + gy * gy).sqrt();
+        if d > PREDICTION {
+            return None;
+        }
+        return Some(Vel::new((gx / d * dx).round(), (gy / d * dy).round()));
+    }
+
+    // Otherwise along the axis of the least penetration or the gap
+    if gx.max(gy) > PREDICTION {
+        None
+    } else if gx >= gy {
+        Some(Vel::new(dx, 0.0))
+    } else {
+        Some(Vel::new(0.0, dy))
+    }
 }
 
 pub fn cease_vel(p1: &Pos, s1: &Size, v1: &Vel, p2: &Pos, s2: &Size) -> Vel {
-    let vel = match normal(p1, s1, p2, s2) {
+    match normal(p1, s1, p2, s2) {
         Some(n) => {
-            let mut v = v1.clone();
+            let mut v = *v1;
 
             if n.x * v1.x > 0.0 {
                 v.x = 0.0;
@@ -54,9 +97,7 @@ pub fn cease_vel(p1: &Pos, s1: &Size, v1: &Vel, p2: &Pos, s2: &Size) -> Vel {
             v
         }
         None => Vel::zero(),
-    };
-
-    vel
+    }
 }
 
 pub fn update_vel(p1: &Pos, s1: &Size, v1: &Vel, p2: &Pos, s2: &Size, v2: &Vel) -> (Vel, Vel) {
@@ -72,3 +113,84 @@ pub fn update_vel(p1: &Pos, s1: &Size, v1: &Vel, p2: &Pos, s2: &Size, v2: &Vel)
 pub fn collide(p1: &Pos, s1: &Size, v1: &Vel, p2: &Pos, s2: &Size, v2: &Vel) -> bool {
     toi(p1, s1, v1, p2, s2, v2) < 1.0
 }
+
+///
+/// Check if the first box stands on the second one
+///
+pub fn rests_on(p1: &Pos, s1: &Size, p2: &Pos, s2: &Size) -> bool {
+    normal(p1, s1, p2, s2).map(|n| n.y < 0.0).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ground() -> (Pos, Size) {
+        (Pos::new(-50.0, -10.0), Size::new(100.0, 10.0))
+    }
+
+    #[test]
+    fn falling_box_hits_ground_halfway() {
+        let (gp, gs) = ground();
+        let t = toi(
+            &Pos::new(0.0, 10.0),
+            &Size::new(10.0, 10.0),
+            &Vel::new(0.0, -20.0),
+            &gp,
+            &gs,
+            &Vel::zero(),
+        );
+        assert!((t - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn boxes_missing_each_other_never_collide() {
+        let (gp, gs) = ground();
+        let (p, s) = (Pos::new(0.0, 10.0), Size::new(10.0, 10.0));
+
+        assert_eq!(
+            toi(&p, &s, &Vel::new(5.0, 0.0), &gp, &gs, &Vel::zero()),
+            1.0
+        );
+        assert_eq!(
+            toi(&p, &s, &Vel::new(0.0, 5.0), &gp, &gs, &Vel::zero()),
+            1.0
+        );
+        assert!(!collide(
+            &p,
+            &s,
+            &Vel::new(0.0, -5.0),
+            &gp,
+            &gs,
+            &Vel::zero()
+        ));
+    }
+
+    #[test]
+    fn resting_box_stops_falling_but_slides() {
+        let (gp, gs) = ground();
+        let (p, s) = (Pos::new(0.0, 0.0), Size::new(10.0, 10.0));
+        let v = Vel::new(2.0, -1.0);
+
+        assert_eq!(toi(&p, &s, &v, &gp, &gs, &Vel::zero()), 0.0);
+
+        let (v1, v2) = update_vel(&p, &s, &v, &gp, &gs, &Vel::zero());
+        assert!((v1.x - 1.8).abs() < 1e-6);
+        assert_eq!(v1.y, 0.0);
+        assert_eq!((v2.x, v2.y), (0.0, 0.0));
+    }
+
+    #[test]
+    fn normal_points_from_first_to_second() {
+        let s = Size::new(10.0, 10.0);
+        let o = Pos::new(0.0, 0.0);
+
+        let n = normal(&o, &s, &Pos::new(11.0, 2.0), &s).unwrap();
+        assert_eq!((n.x, n.y), (1.0, 0.0));
+
+        let n = normal(&o, &s, &Pos::new(3.0, -9.0), &s).unwrap();
+        assert_eq!((n.x, n.y), (0.0, -1.0));
+
+        assert!(normal(&o, &s, &Pos::new(14.0, 0.0), &s).is_none());
+    }
+}
@@ -1,9 +1,39 @@
 use crate::vector::Vector;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{Error as _, Unexpected},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use specs::prelude::*;
 use specs_derive::Component;
 
-#[derive(Component, Clone, Copy, Debug, Serialize, Deserialize)]
+///
+/// Serialize a unit component as `true`, since `null` would read back as a
+/// missing component in saves
+///
+macro_rules! impl_tag {
+    ($n:tt) => {
+        impl Serialize for $n {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bool(true)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $n {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                if bool::deserialize(deserializer)? {
+                    Ok($n)
+                } else {
+                    Err(D::Error::invalid_value(
+                        Unexpected::Bool(false),
+                        &"true for a present tag",
+                    ))
+                }
+            }
+        }
+    };
+}
+
+#[derive(Component, PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Class(pub u64);
 
 pub const CLASS_NEUTRAL: Class = Class(0);
@@ -15,9 +45,16 @@ impl_vector!(Vel);
 impl_vector!(Size);
 impl_vector!(Acc);
 
+/// Downward acceleration per turn
+pub const GRAVITY: f32 = 0.15;
+/// Horizontal speed while running
+pub const RUN_SPEED: f32 = 5.0;
+/// Vertical speed given by jumping
+pub const JUMP_SPEED: f32 = 5.0;
+
 impl Acc {
     pub fn gravity() -> Self {
-        Self::new(0.0, -0.15)
+        Self::new(0.0, -GRAVITY)
     }
 }
 
@@ -28,9 +65,11 @@ pub struct Player {
     pub lives: u64,
 }
 
-#[derive(Component, Clone, Debug, Serialize, Deserialize)]
+#[derive(Component, Clone, Debug)]
 pub struct User;
 
+impl_tag!(User);
+
 impl Player {
     pub fn new(id: u64, class: Class, lives: u64) -> Self {
         Self { id, class, lives }
@@ -55,14 +94,207 @@ pub struct Landmark {
     pub class: Class,
 }
 
+///
+/// Object lying in the level, identified by its kind
+///
 #[derive(Component, Clone, Debug, Serialize, Deserialize)]
-pub struct Background;
+pub struct Item {
+    pub kind: String,
+}
 
+///
+/// Area raising an event when a player enters it
+///
 #[derive(Component, Clone, Debug, Serialize, Deserialize)]
+pub struct Trigger {
+    pub name: String,
+    /// Fire only for the first player entering
+    pub once: bool,
+}
+
+///
+/// Playback state of an animation graph, driven by `Animate`
+///
+/// Renderers draw `frame` of the entity's `Asset`, which follows the state.
+///
+#[derive(Component, Clone, Debug, Serialize, Deserialize)]
+pub struct Animation {
+    pub graph: String,
+    /// Current state, the initial one of the graph if empty
+    #[serde(default)]
+    pub state: String,
+    #[serde(default)]
+    pub frame: u32,
+    /// Turns spent in the current state
+    #[serde(default)]
+    pub ticks: u64,
+    /// Whether a non-looping state played its last frame
+    #[serde(default)]
+    pub finished: bool,
+}
+
+impl Animation {
+    pub fn new(graph: &str) -> Self {
+        Self {
+            graph: graph.into(),
+            state: String::new(),
+            frame: 0,
+            ticks: 0,
+            finished: false,
+        }
+    }
+}
+
+#[derive(Component, Clone, Debug)]
+pub struct Background;
+
+impl_tag!(Background);
+
+#[derive(Component, Clone, Debug)]
 pub struct Block;
 
+impl_tag!(Block);
+
+///
+/// Standing on a block, as found by collision during the last turn
+///
+#[derive(Component, Clone, Debug)]
+pub struct Grounded;
+
 #[derive(Component, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Dir(pub f32);
 
 #[derive(Component, PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Asset(pub u64);
+
+///
+/// Drawing order of an entity; higher layers are drawn over lower ones
+///
+/// Entities without one are layered by what they are.
+///
+#[derive(
+    Component, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Serialize, Deserialize,
+)]
+pub struct Layer(pub i32);
+
+pub const LAYER_BACKGROUND: Layer = Layer(0);
+pub const LAYER_BLOCK: Layer = Layer(10);
+pub const LAYER_ITEM: Layer = Layer(20);
+pub const LAYER_LANDMARK: Layer = Layer(30);
+pub const LAYER_PLAYER: Layer = Layer(40);
+pub const LAYER_USER: Layer = Layer(45);
+pub const LAYER_BULLET: Layer = Layer(50);
+
+///
+/// Cell of the terrain grid streamed in and out around the user
+///
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Chunk {
+    pub x: i64,
+    pub y: i64,
+}
+
+impl Chunk {
+    pub fn new(x: i64, y: i64) -> Self {
+        Self { x, y }
+    }
+
+    ///
+    /// Chunk containing the given position
+    ///
+    pub fn of(pos: &Pos, size: f32) -> Self {
+        Self::new((pos.x / size).floor() as i64, (pos.y / size).floor() as i64)
+    }
+
+    pub fn contains(&self, pos: &Pos, size: f32) -> bool {
+        Self::of(pos, size) == *self
+    }
+
+    ///
+    /// All chunks a box overlaps
+    ///
+    pub fn covering(pos: &Pos, area: &Size, size: f32) -> Vec<Chunk> {
+        let (x0, x1) = span(pos.x, area.x, size);
+        let (y0, y1) = span(pos.y, area.y, size);
+
+        let mut chunks = Vec::new();
+        for x in x0..=x1 {
+            for y in y0..=y1 {
+                chunks.push(Chunk::new(x, y));
+            }
+        }
+        chunks
+    }
+
+    ///
+    /// Check if a box overlaps this chunk
+    ///
+    pub fn overlaps(&self, pos: &Pos, area: &Size, size: f32) -> bool {
+        let (x0, x1) = span(pos.x, area.x, size);
+        let (y0, y1) = span(pos.y, area.y, size);
+
+        (x0..=x1).contains(&self.x) && (y0..=y1).contains(&self.y)
+    }
+
+    ///
+    /// Distance to another chunk, counted in chunks along the longest axis
+    ///
+    pub fn distance(&self, other: &Chunk) -> i64 {
+        (self.x - other.x).abs().max((self.y - other.y).abs())
+    }
+
+    ///
+    /// All chunks within the given distance
+    ///
+    pub fn around(&self, radius: i64) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        for x in self.x - radius..=self.x + radius {
+            for y in self.y - radius..=self.y + radius {
+                chunks.push(Chunk::new(x, y));
+            }
+        }
+        chunks
+    }
+}
+
+///
+/// First and last chunk indices of a segment, its end excluded
+///
+fn span(from: f32, len: f32, size: f32) -> (i64, i64) {
+    let first = (from / size).floor() as i64;
+    let last = ((from + len) / size).ceil() as i64 - 1;
+
+    (first, last.max(first))
+}
+
+///
+/// Terrain streamed in with the chunks it overlaps
+///
+/// `id` is the id of the terrain on the server, so that an item overlapping
+/// several chunks is loaded only once.
+///
+#[derive(Component, Clone, Debug, Serialize, Deserialize)]
+pub struct Streamed {
+    pub id: u64,
+    pub chunks: Vec<Chunk>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn box_covers_the_chunks_it_overlaps() {
+        let covering = Chunk::covering(&Pos::new(-10.0, 50.0), &Size::new(120.0, 50.0), 100.0);
+        assert_eq!(
+            covering,
+            vec![Chunk::new(-1, 0), Chunk::new(0, 0), Chunk::new(1, 0)]
+        );
+
+        // Touching the next chunk is not overlapping it
+        let edge = Chunk::covering(&Pos::new(0.0, 0.0), &Size::new(100.0, 100.0), 100.0);
+        assert_eq!(edge, vec![Chunk::new(0, 0)]);
+        assert!(!Chunk::new(1, 0).overlaps(&Pos::new(0.0, 0.0), &Size::new(100.0, 1.0), 100.0));
+        assert!(Chunk::new(1, 0).overlaps(&Pos::new(0.0, 0.0), &Size::new(101.0, 1.0), 100.0));
+    }
+}
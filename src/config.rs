@@ -1,9 +1,29 @@
+use crate::protocol::Credentials;
 use serde::{Deserialize, Serialize};
+use std::{fmt, time::Duration};
 
+///
+/// Settings left out of a configuration file take their default value
+///
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub game_server: Option<String>,
+    /// Ends with the name of the level to load, e.g. `/ws/castle`, or `/ws/`
+    /// for the default one
     pub terrain_server: String,
+    pub reconnect: Backoff,
+    pub heartbeat: Heartbeat,
+    pub tls: Tls,
+    pub timeouts: Timeouts,
+    pub streaming: Streaming,
+    /// Directory of the on-disk terrain cache, disabled if unset
+    pub cache: Option<String>,
+    /// Sent to the game server on login, never written out with the rest
+    #[serde(skip_serializing)]
+    pub credentials: Option<Credentials>,
+    /// Log in as a spectator, receiving the world without playing
+    pub spectator: bool,
 }
 
 impl Default for Config {
@@ -11,10 +31,141 @@ impl Default for Config {
         Self {
             game_server: None,
             terrain_server: "ws://127.0.0.1:8080/ws/".into(),
+            reconnect: Backoff::default(),
+            heartbeat: Heartbeat::default(),
+            tls: Tls::default(),
+            timeouts: Timeouts::default(),
+            streaming: Streaming::default(),
+            cache: None,
+            credentials: None,
+            spectator: false,
         }
     }
 }
 
+///
+/// Delays between reconnection attempts
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Backoff {
+    pub initial_ms: u64,
+    pub max_ms: u64,
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial_ms: 500,
+            max_ms: 30_000,
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    ///
+    /// Delay to wait after the given number of failed attempts
+    ///
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let shift = attempts.saturating_sub(1).min(16);
+        let ms = self.initial_ms.saturating_mul(1 << shift).min(self.max_ms);
+        Duration::from_millis(ms)
+    }
+}
+
+///
+/// Application-level ping settings
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval_ms: 1000,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+///
+/// Time allowed for each kind of request before it is given up
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Timeouts {
+    pub handshake_ms: u64,
+    pub login_ms: u64,
+    pub terrain_ms: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake_ms: 5000,
+            login_ms: 5000,
+            terrain_ms: 30_000,
+        }
+    }
+}
+
+///
+/// Terrain chunks loaded around the user
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Streaming {
+    /// Width and height of a chunk
+    pub chunk_size: f32,
+    /// Number of chunks kept loaded in each direction
+    pub radius: i64,
+}
+
+impl Default for Streaming {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1000.0,
+            radius: 1,
+        }
+    }
+}
+
+///
+/// Settings for `wss://` connections
+///
+/// A local server with a self-signed certificate can be trusted by adding
+/// that certificate to `ca_roots`.
+///
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct Tls {
+    /// PEM files of additional trusted root certificates
+    pub ca_roots: Vec<String>,
+    /// Client certificate presented to the server
+    pub identity: Option<TlsIdentity>,
+    /// Skip certificate verification, for development only
+    pub accept_invalid_certs: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TlsIdentity {
+    /// PKCS #12 archive holding the certificate and its private key
+    pub pkcs12: String,
+    /// Never written out, nor shown by `Debug`
+    #[serde(skip_serializing, default)]
+    pub password: String,
+}
+
+impl fmt::Debug for TlsIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsIdentity")
+            .field("pkcs12", &self.pkcs12)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct ConfigBuilder {
     cfg: Config,
@@ -37,7 +188,108 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn reconnect(mut self, backoff: Backoff) -> Self {
+        self.cfg.reconnect = backoff;
+        self
+    }
+
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.cfg.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn tls(mut self, tls: Tls) -> Self {
+        self.cfg.tls = tls;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.cfg.timeouts = timeouts;
+        self
+    }
+
+    pub fn streaming(mut self, streaming: Streaming) -> Self {
+        self.cfg.streaming = streaming;
+        self
+    }
+
+    pub fn cache(mut self, dir: &str) -> Self {
+        self.cfg.cache = Some(dir.into());
+        self
+    }
+
+    pub fn token(mut self, token: &str) -> Self {
+        self.cfg.credentials = Some(Credentials::Token(token.into()));
+        self
+    }
+
+    pub fn password(mut self, username: &str, password: &str) -> Self {
+        self.cfg.credentials = Some(Credentials::Password {
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+
+    pub fn spectator(mut self) -> Self {
+        self.cfg.spectator = true;
+        self
+    }
+
     pub fn build(self) -> Config {
         self.cfg
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let backoff = Backoff {
+            initial_ms: 100,
+            max_ms: 1000,
+            max_attempts: None,
+        };
+
+        let delays: Vec<_> = (1..=6).map(|n| backoff.delay(n).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff.delay(u32::MAX).as_millis(), 1000);
+    }
+
+    #[test]
+    fn secrets_are_not_shown_or_written() {
+        let mut cfg = Config::build().password("alice", "hunter2").build();
+        cfg.tls.identity = Some(TlsIdentity {
+            pkcs12: "client.p12".into(),
+            password: "gunma".into(),
+        });
+
+        let debug = format!("{:?}", cfg);
+        let json = serde_json::to_string(&cfg).unwrap();
+        for out in &[debug, json] {
+            assert!(!out.contains("hunter2"));
+            assert!(!out.contains("gunma"));
+            assert!(out.contains("client.p12"));
+        }
+
+        let back: Config = serde_json::from_str(&serde_json::to_string(&cfg).unwrap()).unwrap();
+        assert!(back.credentials.is_none());
+    }
+
+    #[test]
+    fn config_with_only_the_servers_loads() {
+        let cfg: Config = serde_json::from_str(
+            r#"{"game_server": "ws://game:8090/ws/", "terrain_server": "ws://terrain:8080/ws/"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(cfg.game_server.as_deref(), Some("ws://game:8090/ws/"));
+        assert_eq!(cfg.terrain_server, "ws://terrain:8080/ws/");
+        assert_eq!(cfg.timeouts.login_ms, Timeouts::default().login_ms);
+        assert_eq!(cfg.streaming.radius, Streaming::default().radius);
+        assert!(cfg.cache.is_none());
+        assert!(!cfg.spectator);
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{camera::Camera, components::*, systems::Systems, vector::Vector};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use std::collections::HashMap;
+
+// Moves longer than this in one turn are respawns or corrections, drawn
+// without interpolation
+const MAX_STEP: f32 = 100.0;
+
+///
+/// Sprite to draw, in world coordinates
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DrawItem {
+    pub entity: u32,
+    pub layer: Layer,
+    pub asset: Asset,
+    pub frame: u32,
+    /// Position between the last two turns, as given to `Systems::draw`
+    pub pos: Pos,
+    pub size: Size,
+    /// Mirror horizontally, for entities facing left
+    pub flip: bool,
+}
+
+///
+/// Sprites of the world, sorted from the lowest layer to the highest
+///
+#[derive(Default, Clone, Debug)]
+pub struct DrawList {
+    pub items: Vec<DrawItem>,
+}
+
+///
+/// Positions of moving entities before the last turn
+///
+#[derive(Default)]
+pub(crate) struct LastPos(pub HashMap<Entity, Pos>);
+
+pub(crate) struct RememberPos;
+
+impl<'a> System<'a> for RememberPos {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Pos>,
+        ReadStorage<'a, Vel>,
+        Write<'a, LastPos>,
+    );
+
+    fn run(&mut self, (e, pos, vel, mut last): Self::SystemData) {
+        last.0 = (&e, &pos, &vel)
+            .join()
+            .map(|(e, pos, _)| (e, *pos))
+            .collect();
+    }
+}
+
+type Kinds<'a> = (
+    ReadStorage<'a, User>,
+    ReadStorage<'a, Player>,
+    ReadStorage<'a, Bullet>,
+    ReadStorage<'a, Landmark>,
+    ReadStorage<'a, Item>,
+    ReadStorage<'a, Block>,
+);
+
+///
+/// Layer of an entity without a `Layer` of its own
+///
+fn layer_of(e: Entity, (user, ply, bullet, landmark, item, block): &Kinds) -> Layer {
+    if bullet.contains(e) {
+        LAYER_BULLET
+    } else if user.contains(e) {
+        LAYER_USER
+    } else if ply.contains(e) {
+        LAYER_PLAYER
+    } else if landmark.contains(e) {
+        LAYER_LANDMARK
+    } else if item.contains(e) {
+        LAYER_ITEM
+    } else if block.contains(e) {
+        LAYER_BLOCK
+    } else {
+        LAYER_BACKGROUND
+    }
+}
+
+///
+/// Fill the `DrawList` with every entity having a position, size and asset
+///
+/// `alpha` is how far the frame is into the next turn, from 0 to 1. Entities
+/// out of the camera's view are left out if it culls.
+///
+pub(crate) struct BuildDrawList {
+    pub alpha: f32,
+}
+
+impl<'a> System<'a> for BuildDrawList {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, DrawList>,
+        Read<'a, LastPos>,
+        Read<'a, Camera>,
+        ReadStorage<'a, Pos>,
+        ReadStorage<'a, Size>,
+        ReadStorage<'a, Asset>,
+        ReadStorage<'a, Layer>,
+        ReadStorage<'a, Dir>,
+        ReadStorage<'a, Animation>,
+        Kinds<'a>,
+    );
+
+    fn run(
+        &mut self,
+        (e, mut list, last, camera, pos, siz, asset, layer, dir, anim, kinds): Self::SystemData,
+    ) {
+        let alpha = self.alpha.clamp(0.0, 1.0);
+        let camera = camera.at(alpha);
+
+        list.items = (&e, &pos, &siz, &asset)
+            .join()
+            .map(|(e, pos, siz, asset)| {
+                let pos = match last.0.get(&e) {
+                    Some(prev) if (*pos - *prev).len() <= MAX_STEP => {
+                        *prev + (*pos - *prev) * alpha
+                    }
+                    _ => *pos,
+                };
+
+                DrawItem {
+                    entity: e.id(),
+                    layer: layer.get(e).cloned().unwrap_or_else(|| layer_of(e, &kinds)),
+                    asset: *asset,
+                    frame: anim.get(e).map(|a| a.frame).unwrap_or(0),
+                    pos,
+                    size: *siz,
+                    flip: dir.get(e).map(|d| d.0 < 0.0).unwrap_or(false),
+                }
+            })
+            .filter(|item| !camera.cull || camera.is_visible(&item.pos, &item.size))
+            .collect();
+
+        list.items.sort_by_key(|item| (item.layer, item.entity));
+    }
+}
+
+impl Systems {
+    ///
+    /// Build the draw list of a frame `alpha` of the way into the next turn
+    ///
+    /// The list is also left in the `DrawList` resource for `render`.
+    ///
+    pub fn draw(&mut self, alpha: f32) -> Vec<DrawItem> {
+        BuildDrawList { alpha }.run_now(&self.world);
+        self.world.read_resource::<DrawList>().items.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::CreateEntity;
+
+    fn systems(cull: bool) -> Systems {
+        let mut sys = Systems::new().unwrap();
+        let mut camera = Camera::default();
+        camera.cull = cull;
+        sys.set_camera(camera);
+        sys
+    }
+
+    fn player(sys: &mut Systems, id: u64, pos: Pos, vel: Vel) -> Entity {
+        let player = Player {
+            id,
+            class: CLASS_CHIBA,
+            lives: 3,
+        };
+        let e = sys
+            .create_entity()
+            .create_player(pos, Size::new(10.0, 10.0), player, Asset(id));
+        sys.world.write_storage::<Vel>().insert(e, vel).unwrap();
+        sys.world
+            .write_storage::<Acc>()
+            .insert(e, Acc::zero())
+            .unwrap();
+        e
+    }
+
+    #[test]
+    fn items_are_sorted_by_layer() {
+        let mut sys = systems(false);
+        let size = Size::new(10.0, 10.0);
+        let front = sys
+            .create_entity()
+            .create_terrain(Pos::new(0.0, 50.0), size, Asset(1));
+        sys.world
+            .write_storage::<Layer>()
+            .insert(front, Layer(99))
+            .unwrap();
+        sys.create_entity()
+            .create_terrain_block(Pos::new(0.0, -10.0), size, Asset(2));
+        player(&mut sys, 3, Pos::new(0.0, 20.0), Vel::zero());
+        sys.create_entity()
+            .create_terrain(Pos::new(20.0, 50.0), size, Asset(4));
+
+        let layers: Vec<_> = sys.draw(0.0).iter().map(|i| (i.layer, i.asset)).collect();
+
+        assert_eq!(
+            layers,
+            vec![
+                (LAYER_BACKGROUND, Asset(4)),
+                (LAYER_BLOCK, Asset(2)),
+                (LAYER_PLAYER, Asset(3)),
+                (Layer(99), Asset(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_are_interpolated_but_teleports_are_not() {
+        let mut sys = systems(false);
+        let walker = player(&mut sys, 1, Pos::new(0.0, 100.0), Vel::new(-4.0, 0.0));
+        let jumper = player(&mut sys, 2, Pos::new(0.0, 200.0), Vel::zero());
+        sys.world
+            .write_storage::<Dir>()
+            .insert(walker, Dir(-1.0))
+            .unwrap();
+
+        sys.update();
+        sys.world
+            .write_storage::<Pos>()
+            .insert(jumper, Pos::new(500.0, 200.0))
+            .unwrap();
+
+        let items = sys.draw(0.25);
+        let item = |asset| items.iter().find(|i| i.asset == Asset(asset)).unwrap();
+
+        assert_eq!(item(1).pos.x, -1.0);
+        assert!(item(1).flip);
+        assert_eq!(item(2).pos.x, 500.0);
+        assert!(!item(2).flip);
+
+        // Alpha past the last turn is held there
+        let items = sys.draw(3.0);
+        assert_eq!(
+            items.iter().find(|i| i.asset == Asset(1)).unwrap().pos.x,
+            -4.0
+        );
+    }
+
+    #[test]
+    fn entities_out_of_view_are_culled() {
+        let size = Size::new(10.0, 10.0);
+        let visible = |cull| {
+            let mut sys = systems(cull);
+            sys.create_entity()
+                .create_terrain(Pos::new(395.0, 0.0), size, Asset(1));
+            sys.create_entity()
+                .create_terrain(Pos::new(1000.0, 0.0), size, Asset(2));
+            sys.draw(0.0).iter().map(|i| i.asset).collect::<Vec<_>>()
+        };
+
+        assert_eq!(visible(true), vec![Asset(1)]);
+        assert_eq!(visible(false), vec![Asset(1), Asset(2)]);
+    }
+}
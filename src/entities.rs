@@ -1,65 +1,146 @@
-use crate::{components::*, systems::Systems};
+use crate::{
+    components::*,
+    error::Result,
+    prefab::{Components, Prefabs},
+};
 use specs::{
     prelude::*,
     world::{EntityBuilder, LazyBuilder},
 };
+use std::sync::OnceLock;
+
+///
+/// Create an entity from a builtin prefab, with its per-entity values
+///
+fn builtin<B: Builder>(builder: B, name: &str, overrides: Components) -> Entity {
+    static BUILTIN: OnceLock<Prefabs> = OnceLock::new();
+
+    BUILTIN
+        .get_or_init(Prefabs::builtin)
+        .build(builder, name, &overrides)
+        .expect("Builtin prefabs always resolve")
+}
 
 pub trait CreateEntity<T: Builder>: Sized {
     fn builder(self) -> T;
 
     fn create_terrain(self, pos: Pos, size: Size, asset: Asset) -> Entity {
-        self.builder()
-            .with(asset)
-            .with(pos)
-            .with(size)
-            .with(Background)
-            .build()
+        builtin(
+            self.builder(),
+            "terrain",
+            Components {
+                pos: Some(pos),
+                size: Some(size),
+                asset: Some(asset),
+                ..Components::default()
+            },
+        )
     }
 
     fn create_terrain_block(self, pos: Pos, size: Size, asset: Asset) -> Entity {
-        self.builder()
-            .with(asset)
-            .with(pos)
-            .with(size)
-            .with(Background)
-            .with(Block)
-            .build()
+        builtin(
+            self.builder(),
+            "block",
+            Components {
+                pos: Some(pos),
+                size: Some(size),
+                asset: Some(asset),
+                ..Components::default()
+            },
+        )
     }
 
     fn create_user(self, pos: Pos, size: Size, player: Player, asset: Asset) -> Entity {
-        self.builder()
-            .with(Vel::zero())
-            .with(Acc::gravity())
-            .with(pos)
-            .with(size)
-            .with(player)
-            .with(asset)
-            .with(Dir(1.0))
-            .with(User)
-            .build()
+        builtin(
+            self.builder(),
+            "user",
+            Components {
+                pos: Some(pos),
+                size: Some(size),
+                player: Some(player),
+                asset: Some(asset),
+                ..Components::default()
+            },
+        )
     }
 
     fn create_player(self, pos: Pos, size: Size, player: Player, asset: Asset) -> Entity {
-        self.builder()
-            .with(Vel::zero())
-            .with(Acc::gravity())
-            .with(pos)
-            .with(size)
-            .with(player)
-            .with(asset)
-            .with(Dir(1.0))
-            .build()
+        builtin(
+            self.builder(),
+            "player",
+            Components {
+                pos: Some(pos),
+                size: Some(size),
+                player: Some(player),
+                asset: Some(asset),
+                ..Components::default()
+            },
+        )
     }
 
     fn create_bullet(self, vel: Vel, pos: Pos, bullet: Bullet, size: Size, asset: Asset) -> Entity {
-        self.builder()
-            .with(vel)
-            .with(pos)
-            .with(Acc::zero())
-            .with(bullet)
-            .with(size)
-            .with(asset)
-            .build()
+        builtin(
+            self.builder(),
+            "bullet",
+            Components {
+                vel: Some(vel),
+                pos: Some(pos),
+                bullet: Some(bullet),
+                size: Some(size),
+                asset: Some(asset),
+                ..Components::default()
+            },
+        )
+    }
+
+    fn create_landmark(self, pos: Pos, size: Size, landmark: Landmark, asset: Asset) -> Entity {
+        builtin(
+            self.builder(),
+            "landmark",
+            Components {
+                pos: Some(pos),
+                size: Some(size),
+                landmark: Some(landmark),
+                asset: Some(asset),
+                ..Components::default()
+            },
+        )
+    }
+
+    fn create_item(self, pos: Pos, size: Size, item: Item, asset: Asset) -> Entity {
+        builtin(
+            self.builder(),
+            "item",
+            Components {
+                pos: Some(pos),
+                size: Some(size),
+                item: Some(item),
+                asset: Some(asset),
+                ..Components::default()
+            },
+        )
+    }
+
+    fn create_trigger(self, pos: Pos, size: Size, trigger: Trigger) -> Entity {
+        builtin(
+            self.builder(),
+            "trigger",
+            Components {
+                pos: Some(pos),
+                size: Some(size),
+                trigger: Some(trigger),
+                ..Components::default()
+            },
+        )
+    }
+
+    fn create_prefab(
+        self,
+        prefabs: &Prefabs,
+        name: &str,
+        overrides: &Components,
+    ) -> Result<Entity> {
+        prefabs.build(self.builder(), name, overrides)
     }
 }
 
@@ -77,7 +158,7 @@ impl<'a> CreateEntity<LazyBuilder<'a>> for LazyBuilder<'a> {
 
 pub struct EntityCreator<T>(T);
 
-impl<'a, T: Builder> CreateEntity<T> for EntityCreator<T> {
+impl<T: Builder> CreateEntity<T> for EntityCreator<T> {
     fn builder(self) -> T {
         self.0
     }
@@ -1,9 +1,43 @@
+// The `Fail` derive puts its impls inside a const block
+#![allow(non_local_definitions)]
+
+use crate::protocol::{LoginRejection, Rejection, RoomError};
 use failure::Fail;
 
 pub type Result<T> = std::result::Result<T, failure::Error>;
 
 #[derive(Debug, Fail)]
 pub enum Error {
-    #[fail(display = "Couldn't login")]
-    LoginError,
+    #[fail(display = "Couldn't login: {}", _0)]
+    LoginError(LoginRejection),
+    #[fail(display = "Invalid handshake response")]
+    HandshakeError,
+    #[fail(display = "Rejected by peer: {}", _0)]
+    Incompatible(Rejection),
+    #[fail(display = "Connection closed")]
+    Disconnected,
+    #[fail(display = "Malformed message: {}", _0)]
+    MalformedMessage(String),
+    #[fail(display = "Unexpected frame: {}", _0)]
+    UnexpectedFrame(String),
+    #[fail(display = "Request timed out")]
+    TimedOut,
+    #[fail(display = "No manifest for this map")]
+    NoManifest,
+    #[fail(display = "Spectators can't send actions")]
+    ReceiveOnly,
+    #[fail(display = "Unsupported save version {}", _0)]
+    UnsupportedSave(u32),
+    #[fail(display = "Unsupported level format {}", _0)]
+    UnsupportedLevel(u32),
+    #[fail(display = "Invalid map: {}", _0)]
+    InvalidMap(String),
+    #[fail(display = "Invalid prefab: {}", _0)]
+    InvalidPrefab(String),
+    #[fail(display = "Invalid asset: {}", _0)]
+    InvalidAsset(String),
+    #[fail(display = "Unknown asset {} used by {}", _0, _1)]
+    UnknownAsset(u64, String),
+    #[fail(display = "Room request failed: {}", _0)]
+    Room(RoomError),
 }
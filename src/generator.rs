@@ -0,0 +1,361 @@
+use crate::{
+    components::*,
+    level::{Level, Rect},
+    protocol::Terrain,
+};
+use serde::{Deserialize, Serialize};
+
+///
+/// Parameters of the procedural terrain
+///
+/// The movement values default to the physics of `Systems`; the gaps are
+/// only jumpable if they don't exceed it. Ranges are `(min, max)` in world units.
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Params {
+    pub seed: u64,
+    /// Horizontal extent of the terrain between the two outer walls
+    pub length: f32,
+    pub run_speed: f32,
+    pub jump_speed: f32,
+    /// Downward acceleration per turn
+    pub gravity: f32,
+    /// Share of the ideal jump height and reach the terrain may require
+    pub margin: f32,
+    /// Positions and sizes are rounded down to multiples of this
+    pub grid: f32,
+    pub thickness: f32,
+    pub floor_width: (f32, f32),
+    pub platform_width: (f32, f32),
+    /// Most platforms stacked above a stretch of floor
+    pub max_platforms: usize,
+    pub platform_chance: f32,
+    pub obstacle_chance: f32,
+    pub wall_height: f32,
+    pub floor_asset: Asset,
+    pub platform_asset: Asset,
+    pub wall_asset: Asset,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            length: 4000.0,
+            run_speed: RUN_SPEED,
+            jump_speed: JUMP_SPEED,
+            gravity: GRAVITY,
+            margin: 0.7,
+            grid: 16.0,
+            thickness: 32.0,
+            floor_width: (192.0, 640.0),
+            platform_width: (64.0, 192.0),
+            max_platforms: 3,
+            platform_chance: 0.5,
+            obstacle_chance: 0.25,
+            wall_height: 480.0,
+            floor_asset: Asset(1),
+            platform_asset: Asset(2),
+            wall_asset: Asset(3),
+        }
+    }
+}
+
+impl Params {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            ..Self::default()
+        }
+    }
+
+    ///
+    /// Highest rise a jump reaches
+    ///
+    pub fn jump_height(&self) -> f32 {
+        self.jump_speed * self.jump_speed / (2.0 * self.gravity)
+    }
+
+    ///
+    /// Horizontal distance covered by a jump landing `rise` above its start
+    ///
+    /// `None` if the jump can't go that high. A negative rise is a drop.
+    ///
+    pub fn jump_reach(&self, rise: f32) -> Option<f32> {
+        let v = self.jump_speed;
+        let d = v * v - 2.0 * self.gravity * rise;
+        if d < 0.0 {
+            return None;
+        }
+
+        // Land on the way down, the later root of the trajectory
+        let t = (v + d.sqrt()) / self.gravity;
+        Some(self.run_speed * t)
+    }
+
+    fn snap(&self, v: f32) -> f32 {
+        if self.grid > 0.0 {
+            (v / self.grid).floor() * self.grid
+        } else {
+            v
+        }
+    }
+}
+
+///
+/// SplitMix64, kept here so that seeds give the same terrain on every build
+///
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    fn unit(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn range(&mut self, (min, max): (f32, f32)) -> f32 {
+        min + (max - min) * self.unit()
+    }
+
+    fn chance(&mut self, p: f32) -> bool {
+        self.unit() < p
+    }
+}
+
+///
+/// Seeded source of terrain, extended section by section from left to right
+///
+/// Floors are separated by pits narrower than a running jump. Each floor
+/// carries either an obstacle low enough to jump over or a few platforms,
+/// each reachable from the floor or the previous platform.
+///
+pub struct Generator {
+    params: Params,
+    rng: Rng,
+    x: f32,
+    next_id: u64,
+}
+
+impl Generator {
+    pub fn new(params: Params) -> Self {
+        Self {
+            rng: Rng(params.seed),
+            params,
+            x: 0.0,
+            next_id: 0,
+        }
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    ///
+    /// Right edge of the terrain generated so far
+    ///
+    pub fn end(&self) -> f32 {
+        self.x
+    }
+
+    fn block(&mut self, x: f32, y: f32, w: f32, h: f32, asset: Asset) -> Terrain {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        Terrain {
+            id,
+            pos: Pos::new(x, y),
+            size: Size::new(w, h),
+            asset,
+            block: true,
+        }
+    }
+
+    ///
+    /// Wall the full height of the terrain, standing right of `x`
+    ///
+    pub fn wall(&mut self, x: f32) -> Terrain {
+        let p = &self.params;
+        let (t, h, asset) = (p.thickness, p.wall_height + p.thickness, p.wall_asset);
+        self.block(x, -t, t, h, asset)
+    }
+
+    ///
+    /// Generate the next `width` of terrain
+    ///
+    pub fn section(&mut self, width: f32) -> Vec<Terrain> {
+        let end = self.x + width;
+        let mut items = Vec::new();
+
+        while self.x < end {
+            let p = self.params.clone();
+            let floor_w = p
+                .snap(self.rng.range(p.floor_width).min(end - self.x))
+                .max(p.grid);
+            let fx = self.x;
+
+            let floor = self.block(fx, -p.thickness, floor_w, p.thickness, p.floor_asset);
+            items.push(floor);
+
+            let max_rise = p.snap(p.jump_height() * p.margin);
+
+            if floor_w >= p.floor_width.0 && self.rng.chance(p.obstacle_chance) {
+                let h = p.snap(self.rng.range((p.grid, max_rise))).max(p.grid);
+                let x = p.snap(fx + floor_w / 2.0);
+                let obstacle = self.block(x, 0.0, p.thickness, h, p.wall_asset);
+                items.push(obstacle);
+            } else if self.rng.chance(p.platform_chance) {
+                items.extend(self.platforms(fx, floor_w));
+            }
+
+            self.x += floor_w;
+            if self.x < end {
+                let reach = p.jump_reach(0.0).unwrap_or(0.0) * p.margin;
+                self.x += p.snap(self.rng.range((p.grid * 2.0, reach))).max(p.grid);
+            }
+        }
+
+        items
+    }
+
+    fn platforms(&mut self, fx: f32, floor_w: f32) -> Vec<Terrain> {
+        let p = self.params.clone();
+        let max_rise = p.snap(p.jump_height() * p.margin);
+        let min_rise = (max_rise / 2.0).max(p.grid);
+        let thickness = p.snap(p.thickness / 2.0).max(p.grid);
+
+        let mut items = Vec::new();
+        let mut x = p.snap(fx + self.rng.range((0.0, floor_w / 2.0)));
+        let mut y: f32 = 0.0;
+
+        for n in 0..p.max_platforms {
+            // Lower than the previous one is fine as long as there is headroom
+            let rise = self.rng.range((min_rise - y, max_rise));
+            let top = p.snap(y + rise).max(min_rise);
+            let w = p.snap(self.rng.range(p.platform_width)).max(p.grid);
+
+            // The first platform is reached from the floor beneath it
+            if n > 0 {
+                let reach = p.jump_reach(top - y).unwrap_or(0.0) * p.margin;
+                x = p.snap(x + self.rng.range((0.0, reach)));
+            }
+            if x + w > fx + floor_w {
+                break;
+            }
+
+            let platform = self.block(x, top - thickness, w, thickness, p.platform_asset);
+            items.push(platform);
+
+            x += w;
+            y = top;
+        }
+
+        items
+    }
+}
+
+///
+/// Walled terrain of `params.length`, the same for the same parameters
+///
+pub fn generate(params: &Params) -> Vec<Terrain> {
+    let mut gen = Generator::new(params.clone());
+
+    let left = gen.wall(-params.thickness);
+    let mut items = vec![left];
+    items.extend(gen.section(params.length));
+    let right = gen.wall(gen.end());
+    items.push(right);
+
+    items
+}
+
+///
+/// Generated terrain as a level, e.g. to be served by a terrain server
+///
+pub fn level(params: &Params) -> Level {
+    let mut level = Level::new(&format!("generated-{}", params.seed));
+
+    for t in generate(params) {
+        let rect = Rect {
+            pos: t.pos,
+            size: t.size,
+            asset: t.asset,
+        };
+        if t.block {
+            level.blocks.push(rect);
+        } else {
+            level.terrain.push(rect);
+        }
+    }
+
+    level
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Apex and landing distance of a running jump, stepped like `Systems`
+    ///
+    fn simulate_jump() -> (f32, f32) {
+        let mut pos = Pos::zero();
+        let mut vel = Vel::new(RUN_SPEED, JUMP_SPEED);
+        let mut apex: f32 = 0.0;
+
+        loop {
+            vel += Acc::gravity();
+            pos += vel;
+            if pos.y <= 0.0 {
+                return (apex, pos.x);
+            }
+            apex = apex.max(pos.y);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_terrain() {
+        let key = |t: &Terrain| (t.id, t.pos.x, t.pos.y, t.size.x, t.size.y, t.asset);
+        let a: Vec<_> = generate(&Params::new(7)).iter().map(key).collect();
+        let b: Vec<_> = generate(&Params::new(7)).iter().map(key).collect();
+        let c: Vec<_> = generate(&Params::new(8)).iter().map(key).collect();
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn gaps_are_jumpable() {
+        let (apex, reach) = simulate_jump();
+
+        for seed in 0..50 {
+            let params = Params::new(seed);
+            let items = generate(&params);
+            let mut floor_end = None;
+            let mut top = 0.0;
+
+            for t in &items[1..items.len() - 1] {
+                if t.asset == params.floor_asset {
+                    if let Some(end) = floor_end {
+                        assert!(t.pos.x - end < reach, "seed {}: pit at {}", seed, end);
+                    }
+                    floor_end = Some(t.pos.x + t.size.x);
+                    top = 0.0;
+                } else if t.asset == params.platform_asset {
+                    let rise = t.pos.y + t.size.y - top;
+                    assert!(rise < apex, "seed {}: platform at {}", seed, t.pos.x);
+                    top += rise;
+                } else {
+                    assert!(t.size.y < apex, "seed {}: obstacle at {}", seed, t.pos.x);
+                }
+            }
+        }
+    }
+}
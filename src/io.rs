@@ -1,74 +1,1186 @@
 use crate::{
-    client::Client,
+    cache::TerrainCache,
+    client::{Client, ConnectionState},
     components::*,
-    config::Config,
+    config::{Config, Heartbeat, Streaming, Timeouts},
     error::{Error, Result},
+    latency::Latency,
     protocol::*,
+    systems::Systems,
+    transport::Transport,
 };
 use log::*;
+use std::{
+    collections::{HashSet, VecDeque},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+///
+/// Handle of a request issued through `Io`
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(u64);
+
+///
+/// Outcome of a request, or a message pushed by the server
+///
+#[derive(Debug)]
+pub enum Response {
+    Login(RequestId, LoginAck),
+    Terrain(RequestId, Vec<Terrain>),
+    Manifest(RequestId, Manifest),
+    Failed(RequestId, Error),
+    TimedOut(RequestId),
+    ///
+    /// The game connection was restored and the session resumed
+    ///
+    Resumed(LoginAck),
+    ///
+    /// Chat message from another player, to show in the UI
+    ///
+    Chat(Chat),
+    Message(Message),
+}
+
+impl Response {
+    pub fn id(&self) -> Option<RequestId> {
+        match self {
+            Response::Login(id, _)
+            | Response::Terrain(id, _)
+            | Response::Manifest(id, _)
+            | Response::Failed(id, _)
+            | Response::TimedOut(id) => Some(*id),
+            Response::Resumed(_) | Response::Chat(_) | Response::Message(_) => None,
+        }
+    }
+}
+
+enum Request {
+    ///
+    /// Login not sent yet if the game connection was still being established
+    ///
+    Login {
+        resume: bool,
+        sent: bool,
+    },
+    AllTerrain(Vec<Terrain>),
+    Manifest,
+    Chunk(Chunk, Vec<Terrain>),
+}
+
+struct Pending {
+    id: RequestId,
+    req: Request,
+    deadline: Instant,
+}
+
+impl Pending {
+    fn new(id: RequestId, req: Request, timeout_ms: u64) -> Self {
+        Self {
+            id,
+            req,
+            deadline: Instant::now() + Duration::from_millis(timeout_ms),
+        }
+    }
+}
 
 pub struct Io {
-    game_client: Option<Client>,
-    terrain_client: Client,
+    game_client: Option<Box<dyn Transport>>,
+    terrain_client: Box<dyn Transport>,
+    timeouts: Timeouts,
+    cls: Option<Class>,
+    credentials: Option<Credentials>,
+    spectator: bool,
+    session: Option<SessionToken>,
+    heartbeat: Heartbeat,
+    last_ping: Option<Instant>,
+    latency: Latency,
+    next_id: u64,
+    game_requests: VecDeque<Pending>,
+    terrain_requests: VecDeque<Pending>,
+    ready: VecDeque<Response>,
+    streaming: Streaming,
+    requested: HashSet<Chunk>,
+    chunks: Vec<(Chunk, Vec<Terrain>)>,
+    cache: Option<TerrainCache>,
+    manifest: Option<Manifest>,
 }
 
 impl Io {
+    ///
+    /// Start connecting to the websocket servers given in the configuration
+    ///
+    /// The connections are established by `poll`. A login requested meanwhile
+    /// is sent once connected, and terrain is taken from the cache if the
+    /// terrain server can't be reached.
+    ///
     pub fn new(cfg: Config) -> Result<Self> {
-        let game_client = match &cfg.game_server {
-            Some(addr) => Some(Client::new(addr)?),
-            None => None,
+        let handshake = Duration::from_millis(cfg.timeouts.handshake_ms);
+        let connect = |addr: &str| {
+            Client::connect(addr, &cfg.tls, handshake).with_backoff(cfg.reconnect.clone())
         };
-        let terrain_client = Client::new(&cfg.terrain_server)?;
+        let game_client = cfg
+            .game_server
+            .as_ref()
+            .map(|addr| Box::new(connect(addr)) as Box<dyn Transport>);
+        let terrain_client = Box::new(connect(&cfg.terrain_server));
+
+        Ok(Self::with_transports(cfg, game_client, terrain_client))
+    }
 
-        Ok(Self {
+    ///
+    /// Use already established transports instead of the configured servers
+    ///
+    pub fn with_transports(
+        cfg: Config,
+        game_client: Option<Box<dyn Transport>>,
+        terrain_client: Box<dyn Transport>,
+    ) -> Self {
+        let cache = cfg
+            .cache
+            .as_ref()
+            .and_then(|dir| match TerrainCache::open(dir) {
+                Ok(cache) => Some(cache),
+                Err(e) => {
+                    warn!("Terrain cache disabled: {}", e);
+                    None
+                }
+            });
+
+        Self {
             game_client,
             terrain_client,
-        })
+            timeouts: cfg.timeouts,
+            cls: None,
+            credentials: cfg.credentials,
+            spectator: cfg.spectator,
+            session: None,
+            heartbeat: cfg.heartbeat,
+            last_ping: None,
+            latency: Latency::default(),
+            next_id: 0,
+            game_requests: VecDeque::new(),
+            terrain_requests: VecDeque::new(),
+            ready: VecDeque::new(),
+            streaming: cfg.streaming,
+            requested: HashSet::new(),
+            chunks: Vec::new(),
+            cache,
+            manifest: None,
+        }
     }
 
     pub fn is_client(&self) -> bool {
         self.game_client.is_some()
     }
 
-    pub fn login(&mut self, cls: Class) -> Result<LoginAck> {
+    ///
+    /// State of the connection to the game server, if any
+    ///
+    pub fn game_state(&self) -> Option<ConnectionState> {
+        self.game_client.as_ref().map(|c| c.state())
+    }
+
+    ///
+    /// State of the connection to the terrain server
+    ///
+    pub fn terrain_state(&self) -> ConnectionState {
+        self.terrain_client.state()
+    }
+
+    ///
+    /// Check if a protocol feature was agreed on with the game server
+    ///
+    pub fn has_feature(&self, feature: &str) -> bool {
         self.game_client
-            .as_mut()
-            .expect("Server tries to login")
-            .send(Message::Login(Login { cls }))?;
+            .as_ref()
+            .map(|c| c.has_feature(feature))
+            .unwrap_or(false)
+    }
 
-        match self.game_client.as_mut().unwrap().recv()? {
-            Message::LoginAck(ack) => Ok(ack),
-            msg => {
-                error!("Invalid response to login: {:?}", msg);
-                Err(Error::LoginError.into())
-            }
+    ///
+    /// Log in and wait for the answer
+    ///
+    pub fn login(&mut self, cls: Class) -> Result<LoginAck> {
+        let id = self.request_login(cls)?;
+
+        match self.wait(id) {
+            Response::Login(_, ack) => Ok(ack),
+            r => Err(failure(r)),
+        }
+    }
+
+    ///
+    /// Start logging in; the answer is returned by `poll`
+    ///
+    pub fn request_login(&mut self, cls: Class) -> Result<RequestId> {
+        self.session = None;
+        self.send_login(cls, false)
+    }
+
+    fn send_login(&mut self, cls: Class, resume: bool) -> Result<RequestId> {
+        let msg = self.login_message(cls);
+        let client = self.game_client.as_mut().expect("Server tries to login");
+
+        // Sent by `reconnect` once the connection is up
+        let sent = client.state() != ConnectionState::Connecting;
+        if sent {
+            client.send(msg)?;
         }
+        self.cls = Some(cls);
+
+        let id = self.next_id();
+        let timeout = self.timeouts.login_ms;
+        self.game_requests
+            .push_back(Pending::new(id, Request::Login { resume, sent }, timeout));
+
+        Ok(id)
+    }
+
+    fn login_message(&self, cls: Class) -> Message {
+        Message::Login(Login {
+            cls,
+            session: self.session.clone(),
+            credentials: self.credentials.clone(),
+            spectator: self.spectator,
+        })
+    }
+
+    ///
+    /// Whether this client only receives the world, without a player
+    ///
+    pub fn is_spectator(&self) -> bool {
+        self.spectator
     }
 
-    pub fn send_action(&mut self, info: SendAction) -> Result<()> {
+    pub fn send_action(&mut self, mut info: SendAction) -> Result<()> {
+        if self.spectator {
+            return Err(Error::ReceiveOnly.into());
+        }
+        if info.rtt_ms.is_none() {
+            info.rtt_ms = self.latency.rtt().map(|rtt| rtt.as_millis() as u64);
+        }
+
         self.game_client
             .as_mut()
             .expect("Server tries to send action")
-            .send(Message::SendAction(info))
+            .send_buffered(Message::SendAction(info))
+    }
+
+    ///
+    /// Send a chat message; messages of other players are returned by `poll`
+    ///
+    pub fn send_chat(&mut self, scope: ChatScope, text: &str) -> Result<()> {
+        self.game_client
+            .as_mut()
+            .expect("Server tries to chat")
+            .send(Message::Chat(Chat::new(scope, text)))
+    }
+
+    ///
+    /// Lobby requests; the answers are returned by `poll` as messages
+    ///
+    pub fn create_room(&mut self, name: &str, max_players: u32) -> Result<()> {
+        self.send_lobby(Message::CreateRoom(CreateRoom {
+            name: name.into(),
+            max_players,
+        }))
     }
 
+    pub fn list_rooms(&mut self) -> Result<()> {
+        self.send_lobby(Message::ListRooms)
+    }
+
+    pub fn join_room(&mut self, room: RoomId) -> Result<()> {
+        let spectate = self.spectator;
+        self.send_lobby(Message::JoinRoom(JoinRoom { room, spectate }))
+    }
+
+    pub fn leave_room(&mut self) -> Result<()> {
+        self.send_lobby(Message::LeaveRoom)
+    }
+
+    pub fn set_ready(&mut self, ready: bool) -> Result<()> {
+        self.send_lobby(Message::Ready(Ready { ready }))
+    }
+
+    pub fn start_match(&mut self) -> Result<()> {
+        self.send_lobby(Message::StartMatch)
+    }
+
+    fn send_lobby(&mut self, msg: Message) -> Result<()> {
+        self.game_client
+            .as_mut()
+            .expect("Server tries to use the lobby")
+            .send(msg)
+    }
+
+    ///
+    /// Download all terrain and wait for the answer
+    ///
+    /// With a cache configured, only the chunks that changed since the last
+    /// download are fetched, and the cached terrain is used if the terrain
+    /// server is unreachable.
+    ///
     pub fn get_all_terrain(&mut self) -> Result<Vec<Terrain>> {
+        // Whether the server or the cache is used depends on the connection
+        while self.terrain_state() == ConnectionState::Connecting {
+            self.pump();
+            sleep(Duration::from_millis(1));
+        }
+
+        if self.cache.is_some() {
+            if self.terrain_client.has_feature("cache") {
+                return self.sync_terrain();
+            }
+            if self.terrain_state() != ConnectionState::Connected {
+                if let Some(terrain) = self.cache.as_ref().and_then(|c| c.last_terrain()) {
+                    info!("Terrain server unreachable, using cached terrain");
+                    return Ok(terrain);
+                }
+            }
+        }
+
+        let id = self.request_all_terrain()?;
+
+        match self.wait(id) {
+            Response::Terrain(_, items) => Ok(items),
+            r => Err(failure(r)),
+        }
+    }
+
+    ///
+    /// Start downloading all terrain; the answer is returned by `poll`
+    ///
+    pub fn request_all_terrain(&mut self) -> Result<RequestId> {
         self.terrain_client.send(Message::GetAllTerrain)?;
 
-        let mut items = Vec::new();
+        let id = self.next_id();
+        let timeout = self.timeouts.terrain_ms;
+        self.terrain_requests
+            .push_back(Pending::new(id, Request::AllTerrain(Vec::new()), timeout));
+
+        Ok(id)
+    }
+
+    ///
+    /// Start downloading the manifest of the map; the answer is returned by `poll`
+    ///
+    pub fn request_manifest(&mut self) -> Result<RequestId> {
+        self.terrain_client.send(Message::GetManifest(GetManifest {
+            size: self.streaming.chunk_size,
+        }))?;
+
+        let id = self.next_id();
+        let timeout = self.timeouts.terrain_ms;
+        self.terrain_requests
+            .push_back(Pending::new(id, Request::Manifest, timeout));
+
+        Ok(id)
+    }
+
+    ///
+    /// Fetch the chunks missing from the cache and assemble the whole map
+    ///
+    fn sync_terrain(&mut self) -> Result<Vec<Terrain>> {
+        let id = self.request_manifest()?;
+        let manifest = match self.wait(id) {
+            Response::Manifest(_, manifest) => manifest,
+            r => return Err(failure(r)),
+        };
+
+        let mut terrain = Vec::new();
+        let mut missing = HashSet::new();
+        for entry in &manifest.chunks {
+            match self.cached(&entry.chunk) {
+                Some(items) => terrain.extend(items),
+                None => {
+                    missing.insert(entry.chunk);
+                }
+            }
+        }
+        debug!(
+            "{} of {} chunks of {} v{} are cached",
+            manifest.chunks.len() - missing.len(),
+            manifest.chunks.len(),
+            manifest.map,
+            manifest.version
+        );
+
+        for &chunk in &missing {
+            if !self.requested.contains(&chunk) {
+                self.request_chunk(chunk)?;
+            }
+        }
+        while missing.iter().any(|c| self.requested.contains(c)) {
+            self.pump();
+            sleep(Duration::from_millis(1));
+        }
+
+        let (fetched, streamed): (Vec<_>, Vec<_>) = self
+            .chunks
+            .drain(..)
+            .partition(|(chunk, _)| missing.contains(chunk));
+        self.chunks = streamed;
+        for (chunk, items) in fetched {
+            missing.remove(&chunk);
+            terrain.extend(items);
+        }
+
+        if !missing.is_empty() {
+            return Err(match self.terrain_state() {
+                ConnectionState::Connected => Error::TimedOut,
+                _ => Error::Disconnected,
+            }
+            .into());
+        }
+
+        // Terrain overlapping several chunks came with each of them
+        let mut seen = HashSet::new();
+        terrain.retain(|t| seen.insert(t.id));
+
+        Ok(terrain)
+    }
+
+    ///
+    /// Terrain of a chunk according to the manifest, if it is in the cache
+    ///
+    fn cached(&self, chunk: &Chunk) -> Option<Vec<Terrain>> {
+        let manifest = self.manifest.as_ref()?;
+
+        match manifest.hash(chunk) {
+            Some(hash) => self.cache.as_ref()?.load(hash),
+            None => Some(Vec::new()),
+        }
+    }
+
+    ///
+    /// Keep the terrain around `center` loaded in the world
+    ///
+    /// Inserts the chunks received since the last call and requests the
+    /// missing ones. Distant chunks are unloaded by `Systems::update`.
+    ///
+    pub fn stream_terrain(&mut self, sys: &mut Systems, center: &Pos) -> Result<()> {
+        sys.set_streaming(self.streaming.chunk_size, self.streaming.radius);
+
+        for (chunk, items) in self.chunks.drain(..) {
+            debug!("Loading chunk {:?} ({} items)", chunk, items.len());
+            sys.load_chunk(chunk, &items);
+        }
+
+        if self.manifest.is_none() && self.cache.is_some() {
+            if self.terrain_client.has_feature("cache") {
+                let pending = self
+                    .terrain_requests
+                    .iter()
+                    .any(|p| matches!(p.req, Request::Manifest));
+                if !pending {
+                    self.request_manifest()?;
+                }
+                return Ok(());
+            }
+            if self.terrain_state() != ConnectionState::Connected {
+                self.manifest = self.cache.as_ref().and_then(|c| c.last_manifest());
+            }
+        }
+
+        let center = Chunk::of(center, self.streaming.chunk_size);
+        for chunk in center.around(self.streaming.radius) {
+            if sys.is_chunk_loaded(&chunk) || self.requested.contains(&chunk) {
+                continue;
+            }
+
+            match self.cached(&chunk) {
+                Some(items) => sys.load_chunk(chunk, &items),
+                None if self.terrain_state() == ConnectionState::Connected => {
+                    self.request_chunk(chunk)?;
+                }
+                None => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn request_chunk(&mut self, chunk: Chunk) -> Result<RequestId> {
+        self.terrain_client.send(Message::GetTerrain(GetTerrain {
+            chunk,
+            size: self.streaming.chunk_size,
+        }))?;
+        self.requested.insert(chunk);
+
+        let id = self.next_id();
+        let timeout = self.timeouts.terrain_ms;
+        self.terrain_requests.push_back(Pending::new(
+            id,
+            Request::Chunk(chunk, Vec::new()),
+            timeout,
+        ));
+
+        Ok(id)
+    }
+
+    ///
+    /// Round trip time, jitter and clock statistics of the game connection
+    ///
+    pub fn latency(&self) -> &Latency {
+        &self.latency
+    }
+
+    ///
+    /// Drive both connections without blocking
+    ///
+    /// Returns the requests completed since the last call and the messages
+    /// pushed by the game server. Dropped connections are re-established
+    /// here as well.
+    ///
+    pub fn poll(&mut self) -> Result<Vec<Response>> {
+        self.pump();
+        Ok(self.ready.drain(..).collect())
+    }
+
+    fn pump(&mut self) {
+        self.reconnect();
+
+        let mut abandoned = Vec::new();
+
+        for msg in drain(self.terrain_client.as_mut()) {
+            self.on_terrain_message(msg);
+        }
+        if self.terrain_client.state() == ConnectionState::Closed {
+            abandoned.extend(self.terrain_requests.drain(..));
+        }
+
+        if let Some(client) = self.game_client.as_mut() {
+            for msg in drain(client.as_mut()) {
+                self.on_game_message(msg);
+            }
+        }
+        if self.game_state() == Some(ConnectionState::Closed) {
+            abandoned.extend(self.game_requests.drain(..));
+        }
+
+        for p in abandoned {
+            self.abandon(p, Error::Disconnected);
+        }
+
+        self.heartbeat();
 
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for requests in &mut [&mut self.game_requests, &mut self.terrain_requests] {
+            while requests.front().map(|p| p.deadline <= now).unwrap_or(false) {
+                expired.push(requests.pop_front().unwrap());
+            }
+        }
+        for p in expired {
+            warn!("Request {:?} timed out", p.id);
+            self.abandon(p, Error::TimedOut);
+        }
+    }
+
+    ///
+    /// Report a request that won't complete
+    ///
+    fn abandon(&mut self, p: Pending, e: Error) {
+        match p.req {
+            Request::Chunk(chunk, _) => {
+                warn!("Chunk {:?} not loaded: {}", chunk, e);
+                self.requested.remove(&chunk);
+            }
+            _ => self.ready.push_back(match e {
+                Error::TimedOut => Response::TimedOut(p.id),
+                e => Response::Failed(p.id, e),
+            }),
+        }
+    }
+
+    ///
+    /// Poll until the given request completes
+    ///
+    fn wait(&mut self, id: RequestId) -> Response {
         loop {
-            match self.terrain_client.recv()? {
-                Message::Terrain(t) => {
-                    info!("Received terrain from server: {:?}", t);
+            self.pump();
+
+            if let Some(pos) = self.ready.iter().position(|r| r.id() == Some(id)) {
+                return self.ready.remove(pos).unwrap();
+            }
+
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn on_game_message(&mut self, msg: Message) {
+        match msg {
+            Message::Pong(pong) => self.latency.pong(&pong),
+            Message::LoginAck(ack) => match self.game_requests.pop_front() {
+                Some(Pending {
+                    id,
+                    req: Request::Login { resume, .. },
+                    ..
+                }) => {
+                    self.session = ack.session.clone();
+                    if resume {
+                        let client = self.game_client.as_mut().unwrap();
+                        if let Some(seq) = ack.ack {
+                            client.ack(seq);
+                        }
+                        if let Err(e) = client.flush() {
+                            warn!("Couldn't flush buffered messages: {}", e);
+                        }
+                        self.ready.push_back(Response::Resumed(ack));
+                    } else {
+                        self.ready.push_back(Response::Login(id, ack));
+                    }
+                }
+                other => {
+                    warn!("Unexpected login response: {:?}", ack);
+                    if let Some(p) = other {
+                        self.game_requests.push_front(p);
+                    }
+                }
+            },
+            Message::LoginRejected(reason) => match self.game_requests.pop_front() {
+                Some(Pending {
+                    id,
+                    req: Request::Login { .. },
+                    ..
+                }) => {
+                    warn!("Login rejected: {}", reason);
+                    self.ready
+                        .push_back(Response::Failed(id, Error::LoginError(reason)));
+                }
+                other => {
+                    warn!("Unexpected login rejection: {}", reason);
+                    if let Some(p) = other {
+                        self.game_requests.push_front(p);
+                    }
+                }
+            },
+            Message::Ack(seq) => {
+                if let Some(client) = self.game_client.as_mut() {
+                    client.ack(seq);
+                }
+            }
+            Message::Chat(chat) => self.ready.push_back(Response::Chat(chat)),
+            msg => self.ready.push_back(Response::Message(msg)),
+        }
+    }
+
+    fn on_terrain_message(&mut self, msg: Message) {
+        let pending = match self.terrain_requests.front_mut() {
+            Some(p) => p,
+            None => {
+                warn!("Unexpected terrain message: {:?}", msg);
+                return;
+            }
+        };
+
+        match (msg, &mut pending.req) {
+            (Message::Terrain(t), Request::AllTerrain(items))
+            | (Message::Terrain(t), Request::Chunk(_, items)) => {
+                trace!("Received terrain from server: {:?}", t);
+
+                items.push(t);
+            }
+            (Message::Manifest(manifest), Request::Manifest) => {
+                let p = self.terrain_requests.pop_front().unwrap();
+
+                if manifest.size != self.streaming.chunk_size {
+                    warn!(
+                        "Manifest chunk size {} doesn't match {}",
+                        manifest.size, self.streaming.chunk_size
+                    );
+                }
+                if let Some(cache) = &self.cache {
+                    if let Err(e) = cache.save_manifest(&manifest) {
+                        warn!("Couldn't cache manifest: {}", e);
+                    }
+                }
+
+                self.manifest = Some(manifest.clone());
+                self.ready.push_back(Response::Manifest(p.id, manifest));
+            }
+            (Message::EndTerrain, _) => {
+                let p = self.terrain_requests.pop_front().unwrap();
+                match p.req {
+                    Request::AllTerrain(items) => {
+                        self.ready.push_back(Response::Terrain(p.id, items))
+                    }
+                    Request::Chunk(chunk, items) => {
+                        if let Some(cache) = &self.cache {
+                            if let Err(e) = cache.store(&items) {
+                                warn!("Couldn't cache chunk {:?}: {}", chunk, e);
+                            }
+                        }
+                        self.requested.remove(&chunk);
+                        self.chunks.push((chunk, items));
+                    }
+                    // The server doesn't serve the map, or not with this chunk size
+                    Request::Manifest => {
+                        warn!("Terrain server ended the manifest request");
+                        self.ready
+                            .push_back(Response::Failed(p.id, Error::NoManifest));
+                    }
+                    Request::Login { .. } => {}
+                }
+            }
+            (msg, _) => warn!("Invalid message: {:?}", msg),
+        }
+    }
+
+    ///
+    /// Re-establish dropped connections, resuming the game session
+    ///
+    fn reconnect(&mut self) {
+        match self.terrain_client.poll() {
+            Ok(true) => {
+                // The map may have been updated while disconnected
+                self.manifest = None;
+                write(self.terrain_client.as_mut(), |c| c.flush());
+            }
+            Ok(false) => {}
+            Err(e) => {
+                let failed = self.terrain_requests.drain(..).collect();
+                self.give_up("terrain", &e, failed);
+            }
+        }
+
+        let restored = match self.game_client.as_mut().map(|c| c.poll()) {
+            Some(Ok(restored)) => restored,
+            Some(Err(e)) => {
+                let failed = self.game_requests.drain(..).collect();
+                self.give_up("game", &e, failed);
+                false
+            }
+            None => false,
+        };
+        if !restored {
+            return;
+        }
+
+        self.latency.reset();
+        self.last_ping = None;
+
+        let unsent = self
+            .game_requests
+            .iter_mut()
+            .find_map(|p| match &mut p.req {
+                Request::Login { sent, .. } if !*sent => Some(sent),
+                _ => None,
+            });
+        match (unsent, self.cls) {
+            (Some(sent), Some(cls)) => {
+                *sent = true;
+                let msg = self.login_message(cls);
+                write(self.game_client.as_mut().unwrap().as_mut(), |c| c.send(msg));
+            }
+            (None, Some(cls)) => {
+                // Resumed again on the next connection if this one drops
+                if let Err(e) = self.send_login(cls, true) {
+                    warn!("Couldn't resume the session: {}", e);
+                    self.game_client.as_mut().unwrap().close();
+                }
+            }
+            _ => write(self.game_client.as_mut().unwrap().as_mut(), |c| c.flush()),
+        }
+    }
+
+    ///
+    /// Fail the requests of a connection that won't be re-established
+    ///
+    /// The other connection is still polled. As the error comes back on
+    /// every poll, it is only logged along with the requests it fails.
+    ///
+    fn give_up(&mut self, server: &str, e: &failure::Error, failed: Vec<Pending>) {
+        if failed.is_empty() {
+            return;
+        }
+        warn!("Giving up on the {} server: {}", server, e);
+
+        for p in failed {
+            let e = match e.downcast_ref() {
+                Some(Error::Incompatible(r)) => Error::Incompatible(r.clone()),
+                _ => Error::Disconnected,
+            };
+            self.abandon(p, e);
+        }
+    }
+
+    ///
+    /// Send periodic pings, closing the game connection if the server
+    /// stops answering
+    ///
+    fn heartbeat(&mut self) {
+        let client = match self.game_client.as_mut() {
+            Some(client) => client,
+            None => return,
+        };
+        if client.state() != ConnectionState::Connected {
+            return;
+        }
+
+        let timeout = Duration::from_millis(self.heartbeat.timeout_ms);
+        if self.latency.waiting().map(|d| d > timeout).unwrap_or(false) {
+            warn!("No heartbeat reply for {:?}, closing connection", timeout);
+            client.close();
+            return;
+        }
 
-                    items.push(t);
+        let interval = Duration::from_millis(self.heartbeat.interval_ms);
+        if self
+            .last_ping
+            .map(|t| t.elapsed() >= interval)
+            .unwrap_or(true)
+        {
+            if client.has_feature("heartbeat") {
+                let ping = self.latency.ping();
+                write(client.as_mut(), |c| c.send(Message::Ping(ping)));
+            }
+            self.last_ping = Some(Instant::now());
+        }
+    }
+
+    fn next_id(&mut self) -> RequestId {
+        self.next_id += 1;
+        RequestId(self.next_id)
+    }
+}
+
+///
+/// Receive everything available on a connection, skipping bad messages
+///
+fn drain(client: &mut dyn Transport) -> Vec<Message> {
+    let mut msgs = Vec::new();
+
+    loop {
+        match client.try_recv() {
+            Ok(Some(msg)) => msgs.push(msg),
+            Ok(None) => break,
+            Err(e) => {
+                if client.state() == ConnectionState::Closed {
+                    break;
                 }
-                Message::EndTerrain => break,
-                msg => warn!("Invalid message: {:?}", msg),
+                warn!("Dropping message: {}", e);
             }
         }
+    }
+
+    msgs
+}
+
+///
+/// Write on a connection, closing it if the write fails
+///
+/// The transport then reconnects with its backoff, as after any other drop.
+///
+fn write<F>(client: &mut dyn Transport, f: F)
+where
+    F: FnOnce(&mut dyn Transport) -> Result<()>,
+{
+    if let Err(e) = f(client) {
+        warn!("Closing connection after a failed write: {}", e);
+        client.close();
+    }
+}
+
+///
+/// Turn the answer to a blocking request into an error
+///
+fn failure(r: Response) -> failure::Error {
+    match r {
+        Response::Failed(_, e) => e.into(),
+        Response::TimedOut(_) => Error::TimedOut.into(),
+        r => {
+            error!("Invalid response: {:?}", r);
+            Error::UnexpectedFrame(format!("{:?}", r)).into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Backoff, Tls},
+        transport::Loopback,
+    };
+    use std::thread;
+
+    fn terrain(id: u64) -> Terrain {
+        Terrain {
+            id,
+            pos: Pos::new(id as f32 * 10.0, 0.0),
+            size: Size::new(10.0, 10.0),
+            asset: Asset(1),
+            block: true,
+        }
+    }
+
+    #[test]
+    fn login_and_terrain_over_loopback() {
+        let (game, mut game_server) = Loopback::pair();
+        let (terrain_client, mut terrain_server) = Loopback::pair();
+
+        thread::spawn(move || loop {
+            let mut idle = true;
+            if let Ok(Some(Message::Login(login))) = game_server.try_recv() {
+                let player = Player {
+                    id: 7,
+                    class: login.cls,
+                    lives: 3,
+                };
+                let ack = LoginAck::new(player, Pos::new(1.0, 2.0));
+                game_server.send(Message::LoginAck(ack)).unwrap();
+                idle = false;
+            }
+            match terrain_server.try_recv() {
+                Ok(Some(Message::GetAllTerrain)) => {
+                    for id in 0..3 {
+                        terrain_server.send(Message::Terrain(terrain(id))).unwrap();
+                    }
+                    terrain_server.send(Message::EndTerrain).unwrap();
+                    idle = false;
+                }
+                Err(_) => return,
+                _ => {}
+            }
+            if idle {
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let mut io = Io::with_transports(
+            Config::default(),
+            Some(Box::new(game)),
+            Box::new(terrain_client),
+        );
+        assert!(io.is_client());
+
+        let ack = io.login(CLASS_CHIBA).unwrap();
+        assert_eq!(ack.player.id, 7);
+        assert_eq!(ack.player.class, CLASS_CHIBA);
+
+        let items = io.get_all_terrain().unwrap();
+        let ids: Vec<_> = items.iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+    }
+
+    ///
+    /// Connection on which every write fails
+    ///
+    struct BrokenPipe(ConnectionState);
+
+    impl Transport for BrokenPipe {
+        fn send(&mut self, _msg: Message) -> Result<()> {
+            Err(Error::Disconnected.into())
+        }
+
+        fn try_recv(&mut self) -> Result<Option<Message>> {
+            Ok(None)
+        }
+
+        fn state(&self) -> ConnectionState {
+            self.0
+        }
+
+        fn close(&mut self) {
+            self.0 = ConnectionState::Closed;
+        }
+
+        fn has_feature(&self, feature: &str) -> bool {
+            feature == "heartbeat"
+        }
+    }
+
+    #[test]
+    fn failed_ping_only_closes_the_game_connection() {
+        let game = BrokenPipe(ConnectionState::Connected);
+        let (terrain_client, mut terrain_server) = Loopback::pair();
+        let mut io = Io::with_transports(
+            Config::default(),
+            Some(Box::new(game)),
+            Box::new(terrain_client),
+        );
+
+        let id = io.request_all_terrain().unwrap();
+        terrain_server.send(Message::Terrain(terrain(4))).unwrap();
+        terrain_server.send(Message::EndTerrain).unwrap();
+
+        let responses = io.poll().unwrap();
+        assert!(matches!(
+            responses.as_slice(),
+            [Response::Terrain(r, items)] if *r == id && items.len() == 1
+        ));
+        assert_eq!(io.game_state(), Some(ConnectionState::Closed));
+        assert_eq!(io.terrain_state(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn closed_loopback_fails_pending_requests() {
+        let (game, server) = Loopback::pair();
+        let (terrain_client, _terrain_server) = Loopback::pair();
+        let mut io = Io::with_transports(
+            Config::default(),
+            Some(Box::new(game)),
+            Box::new(terrain_client),
+        );
+
+        let id = io.request_login(CLASS_SAITAMA).unwrap();
+        drop(server);
+
+        let responses = io.poll().unwrap();
+        assert!(matches!(
+            responses.as_slice(),
+            [Response::Failed(r, Error::Disconnected)] if *r == id
+        ));
+        assert_eq!(io.game_state(), Some(ConnectionState::Closed));
+    }
+
+    #[test]
+    fn manifest_ended_early_fails() {
+        let (terrain_client, mut terrain_server) = Loopback::pair();
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Config::build().cache(dir.path().to_str().unwrap()).build();
+        let mut io = Io::with_transports(cfg, None, Box::new(terrain_client));
+
+        thread::spawn(move || loop {
+            match terrain_server.try_recv() {
+                Ok(Some(Message::GetManifest(_))) => {
+                    terrain_server.send(Message::EndTerrain).unwrap();
+                }
+                Ok(_) => thread::sleep(Duration::from_millis(1)),
+                Err(_) => return,
+            }
+        });
+
+        let e = io.get_all_terrain().unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::NoManifest)));
+    }
+
+    ///
+    /// Address nothing listens on
+    ///
+    fn unreachable() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("ws://{}", listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn unreachable_terrain_server_falls_back_to_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let items = vec![terrain(0), terrain(1)];
+        {
+            let cache = TerrainCache::open(dir.path()).unwrap();
+            cache.store(&items).unwrap();
+            cache
+                .save_manifest(&Manifest::new("map", 1, 1000.0, &items))
+                .unwrap();
+        }
+
+        let cfg = Config::build()
+            .terrain_server(&unreachable())
+            .cache(dir.path().to_str().unwrap())
+            .build();
+        let mut io = Io::new(cfg).unwrap();
+
+        let ids: Vec<_> = io.get_all_terrain().unwrap().iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![0, 1]);
+        assert_eq!(io.terrain_state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn game_messages_arrive_once_the_terrain_server_is_given_up() {
+        let (game, mut game_server) = Loopback::pair();
+        let backoff = Backoff {
+            initial_ms: 1,
+            max_ms: 1,
+            max_attempts: Some(1),
+        };
+        let terrain_client =
+            Client::connect(&unreachable(), &Tls::default(), Duration::from_secs(1))
+                .with_backoff(backoff);
+        let mut io = Io::with_transports(
+            Config::default(),
+            Some(Box::new(game)),
+            Box::new(terrain_client),
+        );
+
+        while io.terrain_state() != ConnectionState::Closed {
+            io.poll().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        // Past the single retry allowed
+        thread::sleep(Duration::from_millis(10));
+
+        for text in &["hello", "again"] {
+            game_server
+                .send(Message::Chat(Chat::new(ChatScope::All, text)))
+                .unwrap();
+            let responses = io.poll().unwrap();
+            assert!(matches!(
+                responses.as_slice(),
+                [Response::Chat(chat)] if chat.text == *text
+            ));
+        }
+
+        let e = io.get_all_terrain().unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Disconnected)));
+    }
+
+    #[test]
+    fn login_fails_once_the_game_server_is_unreachable() {
+        let cfg = Config::build()
+            .game_server(&unreachable())
+            .terrain_server(&unreachable())
+            .build();
+        let mut io = Io::new(cfg).unwrap();
+
+        let e = io.login(CLASS_CHIBA).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Disconnected)));
+    }
+
+    #[test]
+    fn login_is_sent_once_connected() {
+        use websocket::{sync::Server, OwnedMessage};
+
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        thread::spawn(move || {
+            let mut client = server
+                .filter_map(|r| r.ok())
+                .next()
+                .unwrap()
+                .accept()
+                .unwrap();
+            while let Ok(OwnedMessage::Binary(data)) = client.recv_message() {
+                let reply = match serde_json::from_slice(&data).unwrap() {
+                    Message::Hello(hello) => Message::HelloAck(hello.accept()),
+                    Message::Login(login) => {
+                        let player = Player {
+                            id: 3,
+                            class: login.cls,
+                            lives: 1,
+                        };
+                        Message::LoginAck(LoginAck::new(player, Pos::new(0.0, 0.0)))
+                    }
+                    _ => continue,
+                };
+                let data = serde_json::to_vec(&reply).unwrap();
+                client.send_message(&OwnedMessage::Binary(data)).unwrap();
+            }
+        });
+
+        let cfg = Config::build()
+            .game_server(&url)
+            .terrain_server(&unreachable())
+            .build();
+        let mut io = Io::new(cfg).unwrap();
+        assert_eq!(io.game_state(), Some(ConnectionState::Connecting));
 
-        Ok(items)
+        let ack = io.login(CLASS_SAITAMA).unwrap();
+        assert_eq!(ack.player.id, 3);
+        assert_eq!(io.game_state(), Some(ConnectionState::Connected));
     }
 }
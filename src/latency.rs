@@ -0,0 +1,198 @@
+use crate::protocol::{now_millis, Ping, Pong};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+///
+/// Round trip and clock statistics gathered from ping/pong exchanges
+///
+#[derive(Default)]
+pub struct Latency {
+    seq: u64,
+    inflight: HashMap<u64, Instant>,
+    last_pong: Option<Instant>,
+    rtt: Option<f64>,
+    jitter: f64,
+    offset: f64,
+    tick: Option<(u64, u64, Instant)>,
+}
+
+impl Latency {
+    ///
+    /// Create the next ping to send
+    ///
+    pub fn ping(&mut self) -> Ping {
+        self.seq += 1;
+        self.inflight.insert(self.seq, Instant::now());
+
+        Ping {
+            seq: self.seq,
+            sent: now_millis(),
+        }
+    }
+
+    ///
+    /// Account for a pong received from the server
+    ///
+    pub fn pong(&mut self, pong: &Pong) {
+        let now = Instant::now();
+        let sent = match self.inflight.remove(&pong.seq) {
+            Some(sent) => sent,
+            None => return,
+        };
+        self.inflight.retain(|&seq, _| seq > pong.seq);
+        self.last_pong = Some(now);
+
+        let sample = now.duration_since(sent).as_secs_f64() * 1000.0;
+
+        // Smoothing as done for TCP retransmission timers (RFC 6298)
+        match self.rtt {
+            Some(rtt) => {
+                self.jitter = 0.75 * self.jitter + 0.25 * (rtt - sample).abs();
+                self.rtt = Some(0.875 * rtt + 0.125 * sample);
+            }
+            None => {
+                self.jitter = sample / 2.0;
+                self.rtt = Some(sample);
+            }
+        }
+
+        let offset = pong.server_time as f64 + sample / 2.0 - now_millis() as f64;
+        self.offset = if self.tick.is_some() {
+            0.875 * self.offset + 0.125 * offset
+        } else {
+            offset
+        };
+        let one_way = Duration::from_micros((sample * 500.0) as u64);
+        self.tick = Some((
+            pong.tick,
+            pong.tick_ms,
+            now.checked_sub(one_way).unwrap_or(now),
+        ));
+    }
+
+    ///
+    /// Time elapsed since the oldest unanswered ping, if any
+    ///
+    pub fn waiting(&self) -> Option<Duration> {
+        self.inflight.values().min().map(|sent| sent.elapsed())
+    }
+
+    ///
+    /// Time elapsed since the last pong
+    ///
+    pub fn since_last_pong(&self) -> Option<Duration> {
+        self.last_pong.map(|t| t.elapsed())
+    }
+
+    ///
+    /// Smoothed round trip time
+    ///
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+            .map(|ms| Duration::from_micros((ms * 1000.0) as u64))
+    }
+
+    ///
+    /// Mean deviation of the round trip time
+    ///
+    pub fn jitter(&self) -> Duration {
+        Duration::from_micros((self.jitter * 1000.0) as u64)
+    }
+
+    ///
+    /// Estimated difference between the server and the local clock in milliseconds
+    ///
+    pub fn offset(&self) -> i64 {
+        self.offset as i64
+    }
+
+    ///
+    /// Estimated server time in milliseconds since the unix epoch
+    ///
+    pub fn server_time(&self) -> u64 {
+        (now_millis() as i64 + self.offset()) as u64
+    }
+
+    ///
+    /// Estimated tick the server is currently simulating
+    ///
+    pub fn server_tick(&self) -> Option<u64> {
+        self.tick.map(|(tick, tick_ms, at)| {
+            let elapsed = at.elapsed().as_millis() as u64;
+            tick + elapsed / tick_ms.max(1)
+        })
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.inflight.clear();
+        self.last_pong = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(ping: &Ping, server_time: u64, tick: u64) -> Pong {
+        Pong {
+            seq: ping.seq,
+            sent: ping.sent,
+            server_time,
+            tick,
+            tick_ms: 16,
+        }
+    }
+
+    #[test]
+    fn pong_measures_round_trip() {
+        let mut latency = Latency::default();
+        assert!(latency.rtt().is_none());
+
+        let ping = latency.ping();
+        assert!(latency.waiting().is_some());
+        std::thread::sleep(Duration::from_millis(5));
+        latency.pong(&reply(&ping, now_millis(), 0));
+
+        assert!(latency.rtt().unwrap() >= Duration::from_millis(5));
+        assert!(latency.waiting().is_none());
+        assert!(latency.since_last_pong().is_some());
+    }
+
+    #[test]
+    fn late_pong_answers_older_pings() {
+        let mut latency = Latency::default();
+        let first = latency.ping();
+        let second = latency.ping();
+
+        latency.pong(&reply(&second, now_millis(), 0));
+        assert!(latency.waiting().is_none());
+
+        // Already accounted for
+        latency.pong(&reply(&first, now_millis(), 0));
+        assert!(latency.waiting().is_none());
+    }
+
+    #[test]
+    fn unknown_pong_is_ignored() {
+        let mut latency = Latency::default();
+        let mut ping = latency.ping();
+        ping.seq += 10;
+
+        latency.pong(&reply(&ping, now_millis(), 0));
+        assert!(latency.rtt().is_none());
+        assert!(latency.waiting().is_some());
+    }
+
+    #[test]
+    fn server_clock_and_tick_are_estimated() {
+        let mut latency = Latency::default();
+        let ping = latency.ping();
+        latency.pong(&reply(&ping, now_millis() + 60_000, 500));
+
+        assert!((latency.offset() - 60_000).abs() < 1000);
+        assert!(latency.server_time() > now_millis() + 59_000);
+        assert!(latency.server_tick().unwrap() >= 500);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::{
+    camera::{Bounds, Camera},
+    components::*,
+    entities::CreateEntity,
+    error::{Error, Result},
+    protocol::{Manifest, Terrain},
+    resources::Spawns,
+    systems::Systems,
+};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use std::{fs, path::Path};
+
+///
+/// Version of the level format written by this build
+///
+pub const LEVEL_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rect {
+    pub pos: Pos,
+    pub size: Size,
+    pub asset: Asset,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpawnPoint {
+    pub class: Class,
+    pub pos: Pos,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LandmarkDef {
+    pub pos: Pos,
+    pub size: Size,
+    pub class: Class,
+    pub lives: u64,
+    pub asset: Asset,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemDef {
+    pub kind: String,
+    pub pos: Pos,
+    pub size: Size,
+    pub asset: Asset,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TriggerDef {
+    pub name: String,
+    pub pos: Pos,
+    pub size: Size,
+    #[serde(default)]
+    pub once: bool,
+}
+
+///
+/// Standalone description of a map, stored as JSON
+///
+/// Terrain is decoration the players pass in front of; blocks are terrain
+/// they collide with.
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Level {
+    pub format: u32,
+    pub name: String,
+    /// Revision of the map, bumped on every change
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub terrain: Vec<Rect>,
+    #[serde(default)]
+    pub blocks: Vec<Rect>,
+    #[serde(default)]
+    pub spawns: Vec<SpawnPoint>,
+    #[serde(default)]
+    pub landmarks: Vec<LandmarkDef>,
+    #[serde(default)]
+    pub items: Vec<ItemDef>,
+    #[serde(default)]
+    pub triggers: Vec<TriggerDef>,
+}
+
+impl Level {
+    pub fn new(name: &str) -> Self {
+        Self {
+            format: LEVEL_VERSION,
+            name: name.into(),
+            version: 0,
+            terrain: Vec::new(),
+            blocks: Vec::new(),
+            spawns: Vec::new(),
+            landmarks: Vec::new(),
+            items: Vec::new(),
+            triggers: Vec::new(),
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_slice(&fs::read(path)?)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let level: Level = serde_json::from_slice(bytes)?;
+        if level.format != LEVEL_VERSION {
+            return Err(Error::UnsupportedLevel(level.format).into());
+        }
+
+        Ok(level)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    ///
+    /// Terrain and blocks as served by a terrain server
+    ///
+    pub fn terrain_items(&self) -> Vec<Terrain> {
+        let terrain = self.terrain.iter().map(|r| (r, false));
+        let blocks = self.blocks.iter().map(|r| (r, true));
+
+        terrain
+            .chain(blocks)
+            .enumerate()
+            .map(|(id, (r, block))| Terrain {
+                id: id as u64,
+                pos: r.pos,
+                size: r.size,
+                asset: r.asset,
+                block,
+            })
+            .collect()
+    }
+
+    ///
+    /// Manifest of the terrain of this level split in chunks of `size`
+    ///
+    pub fn manifest(&self, size: f32) -> Manifest {
+        Manifest::new(&self.name, self.version, size, &self.terrain_items())
+    }
+
+    pub fn spawns_of(&self, class: Class) -> impl Iterator<Item = &Pos> {
+        self.spawns
+            .iter()
+            .filter(move |s| s.class == class)
+            .map(|s| &s.pos)
+    }
+}
+
+impl Systems {
+    ///
+    /// Create the entities of a level, record its spawn points and keep the
+    /// camera inside it, unbounded if the level has no terrain
+    ///
+    pub fn load_level(&mut self, level: &Level) {
+        for t in &level.terrain {
+            self.create_entity().create_terrain(t.pos, t.size, t.asset);
+        }
+        for b in &level.blocks {
+            self.create_entity()
+                .create_terrain_block(b.pos, b.size, b.asset);
+        }
+        for l in &level.landmarks {
+            let landmark = Landmark {
+                lives: l.lives,
+                class: l.class,
+            };
+            self.create_entity()
+                .create_landmark(l.pos, l.size, landmark, l.asset);
+        }
+        for i in &level.items {
+            let item = Item {
+                kind: i.kind.clone(),
+            };
+            self.create_entity()
+                .create_item(i.pos, i.size, item, i.asset);
+        }
+        for t in &level.triggers {
+            let trigger = Trigger {
+                name: t.name.clone(),
+                once: t.once,
+            };
+            self.create_entity().create_trigger(t.pos, t.size, trigger);
+        }
+
+        self.world.write_resource::<Camera>().bounds = Bounds::of_level(level);
+
+        let mut spawns = self.world.write_resource::<Spawns>();
+        spawns.0.clear();
+        for s in &level.spawns {
+            spawns.0.entry(s.class).or_default().push(s.pos);
+        }
+    }
+
+    ///
+    /// Spawn point of a class in the loaded level, picked by `n`
+    ///
+    pub fn spawn_point(&self, class: Class, n: usize) -> Option<Pos> {
+        let spawns = self.world.read_resource::<Spawns>();
+        let points = spawns.0.get(&class)?;
+
+        if points.is_empty() {
+            None
+        } else {
+            Some(points[n % points.len()])
+        }
+    }
+}
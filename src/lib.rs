@@ -1,24 +1,48 @@
 #[macro_use]
 mod vector;
 
+pub mod animation;
+pub mod assets;
+pub mod auth;
+pub mod camera;
+pub mod chat;
 pub mod components;
+pub mod draw;
 pub mod entities;
+pub mod generator;
+pub mod level;
+pub mod prefab;
 pub mod protocol;
 pub mod resources;
+pub mod room;
+pub mod session;
+pub mod terrain_server;
+pub mod tiled;
+pub mod transport;
+pub mod validation;
 
+mod cache;
 mod client;
 mod collide;
 mod config;
 mod error;
 mod io;
+mod latency;
+mod save;
 mod systems;
 
 pub mod prelude {
     pub use crate::entities::CreateEntity;
 }
 
-pub use crate::config::{Config, ConfigBuilder};
-pub use crate::error::Result;
-pub use crate::io::Io;
+pub use crate::cache::TerrainCache;
+pub use crate::client::ConnectionState;
+pub use crate::config::{
+    Backoff, Config, ConfigBuilder, Heartbeat, Streaming, Timeouts, Tls, TlsIdentity,
+};
+pub use crate::error::{Error, Result};
+pub use crate::io::{Io, RequestId, Response};
+pub use crate::latency::Latency;
+pub use crate::save::SAVE_VERSION;
 pub use crate::systems::Systems;
 pub use crate::vector::Vector;
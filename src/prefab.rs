@@ -0,0 +1,413 @@
+use crate::{
+    components::*,
+    error::{Error, Result},
+    protocol::Spawn,
+    systems::Systems,
+};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use std::{collections::HashMap, fs, path::Path};
+
+///
+/// Component values of an entity, as written in data
+///
+/// Absent components are left out of the entity; tags are written `true`.
+/// Components listed in `remove` are taken out of those being overridden.
+///
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Components {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pos: Option<Pos>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vel: Option<Vel>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acc: Option<Acc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<Size>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<Dir>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset: Option<Asset>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub player: Option<Player>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bullet: Option<Bullet>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub landmark: Option<Landmark>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub item: Option<Item>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trigger: Option<Trigger>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animation: Option<Animation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<Layer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<Background>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<Block>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<Kind>,
+}
+
+///
+/// Name of a component in `Components`
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Pos,
+    Vel,
+    Acc,
+    Size,
+    Dir,
+    Asset,
+    Player,
+    Bullet,
+    Landmark,
+    Item,
+    Trigger,
+    Animation,
+    Layer,
+    User,
+    Background,
+    Block,
+}
+
+impl Components {
+    ///
+    /// These values, without the ones `overrides` removes and with the ones
+    /// present in `overrides` replacing them
+    ///
+    pub fn merge(&self, overrides: &Components) -> Components {
+        fn pick<T: Clone>(base: &Option<T>, over: &Option<T>) -> Option<T> {
+            over.as_ref().or(base.as_ref()).cloned()
+        }
+
+        let mut base = self.clone();
+        for kind in &overrides.remove {
+            base.clear(*kind);
+        }
+
+        Components {
+            pos: pick(&base.pos, &overrides.pos),
+            vel: pick(&base.vel, &overrides.vel),
+            acc: pick(&base.acc, &overrides.acc),
+            size: pick(&base.size, &overrides.size),
+            dir: pick(&base.dir, &overrides.dir),
+            asset: pick(&base.asset, &overrides.asset),
+            player: pick(&base.player, &overrides.player),
+            bullet: pick(&base.bullet, &overrides.bullet),
+            landmark: pick(&base.landmark, &overrides.landmark),
+            item: pick(&base.item, &overrides.item),
+            trigger: pick(&base.trigger, &overrides.trigger),
+            animation: pick(&base.animation, &overrides.animation),
+            layer: pick(&base.layer, &overrides.layer),
+            user: pick(&base.user, &overrides.user),
+            background: pick(&base.background, &overrides.background),
+            block: pick(&base.block, &overrides.block),
+            remove: Vec::new(),
+        }
+    }
+
+    ///
+    /// Leave out a component
+    ///
+    pub fn clear(&mut self, kind: Kind) {
+        match kind {
+            Kind::Pos => self.pos = None,
+            Kind::Vel => self.vel = None,
+            Kind::Acc => self.acc = None,
+            Kind::Size => self.size = None,
+            Kind::Dir => self.dir = None,
+            Kind::Asset => self.asset = None,
+            Kind::Player => self.player = None,
+            Kind::Bullet => self.bullet = None,
+            Kind::Landmark => self.landmark = None,
+            Kind::Item => self.item = None,
+            Kind::Trigger => self.trigger = None,
+            Kind::Animation => self.animation = None,
+            Kind::Layer => self.layer = None,
+            Kind::User => self.user = None,
+            Kind::Background => self.background = None,
+            Kind::Block => self.block = None,
+        }
+    }
+
+    ///
+    /// Add the present components to a builder
+    ///
+    pub fn build<B: Builder>(self, builder: B) -> Entity {
+        fn with<B: Builder, C: Component + Send + Sync>(b: B, c: Option<C>) -> B {
+            match c {
+                Some(c) => b.with(c),
+                None => b,
+            }
+        }
+
+        let b = with(builder, self.pos);
+        let b = with(b, self.vel);
+        let b = with(b, self.acc);
+        let b = with(b, self.size);
+        let b = with(b, self.dir);
+        let b = with(b, self.asset);
+        let b = with(b, self.player);
+        let b = with(b, self.bullet);
+        let b = with(b, self.landmark);
+        let b = with(b, self.item);
+        let b = with(b, self.trigger);
+        let b = with(b, self.animation);
+        let b = with(b, self.layer);
+        let b = with(b, self.user);
+        let b = with(b, self.background);
+        let b = with(b, self.block);
+        b.build()
+    }
+}
+
+///
+/// Named archetype of entities
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Prefab {
+    pub name: String,
+    /// Prefab whose components this one starts from
+    #[serde(default)]
+    pub extends: Option<String>,
+    #[serde(default)]
+    pub components: Components,
+}
+
+///
+/// Registry of prefabs, looked up by name when creating entities
+///
+#[derive(Clone, Debug, Default)]
+pub struct Prefabs {
+    prefabs: HashMap<String, Prefab>,
+}
+
+impl Prefabs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Registry holding the archetypes of the `CreateEntity` methods
+    ///
+    /// Their position, size, asset and other per-entity values are expected
+    /// as overrides.
+    ///
+    pub fn builtin() -> Self {
+        let mut prefabs = Self::new();
+
+        let mover = Components {
+            vel: Some(Vel::zero()),
+            acc: Some(Acc::gravity()),
+            dir: Some(Dir(1.0)),
+            ..Components::default()
+        };
+
+        prefabs.register(
+            "terrain",
+            None,
+            Components {
+                background: Some(Background),
+                ..Components::default()
+            },
+        );
+        prefabs.register(
+            "block",
+            Some("terrain"),
+            Components {
+                block: Some(Block),
+                ..Components::default()
+            },
+        );
+        prefabs.register("player", None, mover);
+        prefabs.register(
+            "user",
+            Some("player"),
+            Components {
+                user: Some(User),
+                ..Components::default()
+            },
+        );
+        prefabs.register(
+            "bullet",
+            None,
+            Components {
+                acc: Some(Acc::zero()),
+                ..Components::default()
+            },
+        );
+        prefabs.register(
+            "landmark",
+            None,
+            Components {
+                block: Some(Block),
+                ..Components::default()
+            },
+        );
+        prefabs.register("item", None, Components::default());
+        prefabs.register("trigger", None, Components::default());
+
+        prefabs
+    }
+
+    ///
+    /// Read prefabs from a JSON list, replacing those of the same name
+    ///
+    pub fn load(&mut self, bytes: &[u8]) -> Result<()> {
+        let list: Vec<Prefab> = serde_json::from_slice(bytes)?;
+        for prefab in list {
+            self.prefabs.insert(prefab.name.clone(), prefab);
+        }
+
+        Ok(())
+    }
+
+    pub fn open<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.load(&fs::read(path)?)
+    }
+
+    pub fn register(&mut self, name: &str, extends: Option<&str>, components: Components) {
+        self.prefabs.insert(
+            name.into(),
+            Prefab {
+                name: name.into(),
+                extends: extends.map(|e| e.into()),
+                components,
+            },
+        );
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Prefab> {
+        self.prefabs.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Prefab> {
+        self.prefabs.values()
+    }
+
+    ///
+    /// Components of a prefab, including those of the prefabs it extends
+    ///
+    pub fn resolve(&self, name: &str) -> Result<Components> {
+        let mut chain = Vec::new();
+        let mut next = Some(name);
+
+        while let Some(name) = next {
+            if chain.iter().any(|p: &&Prefab| p.name == name) {
+                return Err(Error::InvalidPrefab(format!("{} extends itself", name)).into());
+            }
+            let prefab = self
+                .prefabs
+                .get(name)
+                .ok_or_else(|| Error::InvalidPrefab(format!("{} is unknown", name)))?;
+            next = prefab.extends.as_deref();
+            chain.push(prefab);
+        }
+
+        Ok(chain
+            .iter()
+            .rev()
+            .fold(Components::default(), |acc, p| acc.merge(&p.components)))
+    }
+
+    ///
+    /// Create an entity from a prefab, with some components overridden
+    ///
+    pub fn build<B: Builder>(
+        &self,
+        builder: B,
+        name: &str,
+        overrides: &Components,
+    ) -> Result<Entity> {
+        Ok(self.resolve(name)?.merge(overrides).build(builder))
+    }
+}
+
+impl Systems {
+    ///
+    /// Replace the prefabs available to `spawn`
+    ///
+    pub fn set_prefabs(&mut self, prefabs: Prefabs) {
+        *self.world.write_resource::<Prefabs>() = prefabs;
+    }
+
+    ///
+    /// Create the entity described by a spawn message
+    ///
+    pub fn spawn(&mut self, spawn: &Spawn) -> Result<Entity> {
+        let components = self
+            .world
+            .read_resource::<Prefabs>()
+            .resolve(&spawn.prefab)?
+            .merge(&spawn.overrides);
+
+        Ok(components.build(self.world.create_entity()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::CreateEntity;
+
+    #[test]
+    fn prefabs_merge_along_their_chain() {
+        let mut prefabs = Prefabs::builtin();
+        prefabs
+            .load(br#"[{"name": "ghost", "extends": "user", "components": {"asset": 9, "remove": ["acc"]}}]"#)
+            .unwrap();
+
+        let ghost = prefabs.resolve("ghost").unwrap();
+        assert!(ghost.user.is_some() && ghost.vel.is_some());
+        assert!(ghost.acc.is_none());
+        assert_eq!(ghost.asset, Some(Asset(9)));
+
+        prefabs.register("loop", Some("loop"), Components::default());
+        assert!(prefabs.resolve("loop").is_err());
+        assert!(prefabs.resolve("nothing").is_err());
+    }
+
+    #[test]
+    fn overrides_replace_and_remove_components() {
+        let mut systems = Systems::new().unwrap();
+        let spawn: Spawn = serde_json::from_str(
+            r#"{"prefab": "block", "overrides": {"pos": {"x": 1, "y": 2}, "remove": ["background"]}}"#,
+        )
+        .unwrap();
+
+        let e = systems.spawn(&spawn).unwrap();
+        let world = &systems.world;
+        assert_eq!(world.read_storage::<Pos>().get(e).unwrap().x, 1.0);
+        assert!(world.read_storage::<Block>().contains(e));
+        assert!(!world.read_storage::<Background>().contains(e));
+    }
+
+    #[test]
+    fn builtin_creators_match_their_prefabs() {
+        let mut systems = Systems::new().unwrap();
+        let player = Player {
+            id: 1,
+            class: CLASS_CHIBA,
+            lives: 3,
+        };
+        let e = systems.world.create_entity().create_user(
+            Pos::zero(),
+            Size::new(10.0, 10.0),
+            player,
+            Asset(4),
+        );
+
+        let world = &systems.world;
+        assert!(world.read_storage::<User>().contains(e));
+        assert_eq!(world.read_storage::<Acc>().get(e).unwrap().y, -GRAVITY);
+        assert_eq!(world.read_storage::<Dir>().get(e).unwrap().0, 1.0);
+        assert_eq!(world.read_storage::<Asset>().get(e), Some(&Asset(4)));
+        assert!(!world.read_storage::<Block>().contains(e));
+    }
+}
@@ -1,9 +1,120 @@
-use crate::{components::*, resources::*};
+use crate::{components::*, prefab::Components, resources::*};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+///
+/// Version of the wire protocol spoken by this build
+///
+pub const PROTOCOL_VERSION: u32 = 2;
+
+///
+/// Optional protocol features supported by this build
+///
+pub const FEATURES: &[&str] = &["resume", "heartbeat", "chunks", "cache", "ack"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    Json,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    pub codec: Codec,
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            codec: Codec::Json,
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    ///
+    /// Build the answer to this hello from the point of view of this build
+    ///
+    pub fn accept(&self) -> HelloAck {
+        let rejection = if self.version != PROTOCOL_VERSION {
+            Some(Rejection::Version {
+                expected: PROTOCOL_VERSION,
+                actual: self.version,
+            })
+        } else if self.codec != Codec::Json {
+            Some(Rejection::Codec(self.codec))
+        } else {
+            None
+        };
+
+        let features = self
+            .features
+            .iter()
+            .filter(|f| FEATURES.contains(&f.as_str()))
+            .cloned()
+            .collect();
+
+        HelloAck {
+            version: PROTOCOL_VERSION,
+            codec: Codec::Json,
+            features,
+            rejection,
+        }
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Rejection {
+    Version { expected: u32, actual: u32 },
+    Codec(Codec),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::Version { expected, actual } => write!(
+                f,
+                "protocol version mismatch (expected {}, got {})",
+                expected, actual
+            ),
+            Rejection::Codec(codec) => write!(f, "unsupported codec {:?}", codec),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HelloAck {
+    pub version: u32,
+    pub codec: Codec,
+    pub features: Vec<String>,
+    pub rejection: Option<Rejection>,
+}
+
+impl HelloAck {
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+///
+/// Request for the terrain overlapping a chunk
+///
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GetTerrain {
-    pub pos: Pos,
+    pub chunk: Chunk,
+    pub size: f32,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -12,6 +123,88 @@ pub struct Terrain {
     pub pos: Pos,
     pub size: Size,
     pub asset: Asset,
+    #[serde(default)]
+    pub block: bool,
+}
+
+///
+/// Content hash of the terrain of a chunk
+///
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChunkHash(pub String);
+
+impl ChunkHash {
+    ///
+    /// Hash terrain items, independently of their order
+    ///
+    pub fn of(terrain: &[Terrain]) -> Self {
+        let mut items: Vec<_> = terrain.iter().collect();
+        items.sort_by_key(|t| t.id);
+
+        let bytes = serde_json::to_vec(&items).expect("Terrain is always serializable");
+        ChunkHash(format!("{:x}", Sha256::digest(&bytes)))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkEntry {
+    pub chunk: Chunk,
+    pub hash: ChunkHash,
+}
+
+///
+/// Request for the manifest of the map, split in chunks of the given size
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetManifest {
+    pub size: f32,
+}
+
+///
+/// Hashes of every non-empty chunk of a version of a map
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub map: String,
+    pub version: u64,
+    pub size: f32,
+    pub chunks: Vec<ChunkEntry>,
+}
+
+impl Manifest {
+    ///
+    /// Split the terrain of a map in chunks and hash each of them
+    ///
+    /// Terrain overlapping several chunks is part of each of them.
+    ///
+    pub fn new(map: &str, version: u64, size: f32, terrain: &[Terrain]) -> Self {
+        let mut chunks: HashMap<Chunk, Vec<Terrain>> = HashMap::new();
+        for t in terrain {
+            for chunk in Chunk::covering(&t.pos, &t.size, size) {
+                chunks.entry(chunk).or_default().push(t.clone());
+            }
+        }
+
+        Self {
+            map: map.into(),
+            version,
+            size,
+            chunks: chunks
+                .into_iter()
+                .map(|(chunk, items)| ChunkEntry {
+                    chunk,
+                    hash: ChunkHash::of(&items),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn hash(&self, chunk: &Chunk) -> Option<&ChunkHash> {
+        self.chunks
+            .iter()
+            .find(|e| e.chunk == *chunk)
+            .map(|e| &e.hash)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -22,32 +215,487 @@ pub struct SendAction {
     pub acc: Acc,
     pub dir: Dir,
     pub action: Action,
+    /// Round trip time seen by the client, used for lag compensation
+    #[serde(default)]
+    pub rtt_ms: Option<u64>,
+}
+
+///
+/// Message kept by the sender until the peer acknowledges it
+///
+/// With the `ack` feature, the receiver answers with `Message::Ack` holding
+/// the highest number received, and ignores numbers it has already seen:
+/// unacknowledged messages are sent again after the session is resumed.
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sequenced {
+    pub seq: u64,
+    pub msg: Message,
+}
+
+///
+/// Opaque token that lets a reconnecting client reclaim its player
+///
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionToken(pub String);
+
+///
+/// Proof of identity presented on login
+///
+/// Secrets are left out of the `Debug` output, so that credentials can be
+/// logged.
+///
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Credentials {
+    Token(String),
+    Password { username: String, password: String },
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => f.debug_tuple("Token").field(&"<redacted>").finish(),
+            Credentials::Password { username, .. } => f
+                .debug_struct("Password")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Login {
     pub cls: Class,
+    #[serde(default)]
+    pub session: Option<SessionToken>,
+    #[serde(default)]
+    pub credentials: Option<Credentials>,
+    /// Watch the match without a player
+    #[serde(default)]
+    pub spectator: bool,
+}
+
+///
+/// Account a player logged in with
+///
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Account {
+    pub id: u64,
+    pub name: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LoginAck {
     pub player: Player,
     pub spawn: Pos,
+    pub session: Option<SessionToken>,
+    #[serde(default)]
+    pub account: Option<Account>,
+    #[serde(default)]
+    pub spectator: bool,
+    /// Highest sequence number received in the resumed session
+    #[serde(default)]
+    pub ack: Option<u64>,
 }
 
 impl LoginAck {
     pub fn new(player: Player, spawn: Pos) -> Self {
-        Self { player, spawn }
+        Self {
+            player,
+            spawn,
+            session: None,
+            account: None,
+            spectator: false,
+            ack: None,
+        }
+    }
+
+    ///
+    /// Answer to a spectator login; `player` only identifies the connection
+    ///
+    pub fn spectate(id: u64, spawn: Pos) -> Self {
+        Self {
+            spectator: true,
+            ..Self::new(Player::new(id, CLASS_NEUTRAL, 0), spawn)
+        }
+    }
+
+    pub fn with_session(mut self, session: SessionToken) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    pub fn with_account(mut self, account: Account) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    pub fn with_ack(mut self, seq: u64) -> Self {
+        self.ack = Some(seq);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LoginRejection {
+    MissingCredentials,
+    ///
+    /// Unknown user or wrong password, not telling which
+    ///
+    BadCredentials,
+    InvalidToken,
+    ClassUnavailable(Class),
+}
+
+impl fmt::Display for LoginRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoginRejection::MissingCredentials => write!(f, "no credentials given"),
+            LoginRejection::BadCredentials => write!(f, "wrong username or password"),
+            LoginRejection::InvalidToken => write!(f, "invalid or expired token"),
+            LoginRejection::ClassUnavailable(cls) => write!(f, "class {} is not available", cls.0),
+        }
+    }
+}
+
+///
+/// Milliseconds since the unix epoch on the local clock
+///
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ping {
+    pub seq: u64,
+    pub sent: u64,
+}
+
+impl Ping {
+    ///
+    /// Build the server answer to this ping
+    ///
+    pub fn reply(&self, tick: u64, tick_ms: u64) -> Pong {
+        Pong {
+            seq: self.seq,
+            sent: self.sent,
+            server_time: now_millis(),
+            tick,
+            tick_ms,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pong {
+    pub seq: u64,
+    pub sent: u64,
+    pub server_time: u64,
+    pub tick: u64,
+    pub tick_ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerState {
+    pub player: Player,
+    pub pos: Pos,
+    pub size: Size,
+    pub vel: Vel,
+    pub dir: Dir,
+    pub asset: Asset,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BulletState {
+    pub bullet: Bullet,
+    pub pos: Pos,
+    pub size: Size,
+    pub vel: Vel,
+    pub asset: Asset,
+}
+
+///
+/// State of every moving entity of the world at a turn
+///
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub tick: u64,
+    pub players: Vec<PlayerState>,
+    pub bullets: Vec<BulletState>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatScope {
+    All,
+    Team,
+}
+
+///
+/// Order to create an entity from a prefab
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Spawn {
+    pub prefab: String,
+    #[serde(default)]
+    pub overrides: Components,
+}
+
+impl Spawn {
+    pub fn new(prefab: &str, overrides: Components) -> Self {
+        Self {
+            prefab: prefab.into(),
+            overrides,
+        }
+    }
+}
+
+///
+/// Text message between players
+///
+/// `from`, `name` and `sent` are filled in by the server.
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chat {
+    pub scope: ChatScope,
+    pub text: String,
+    #[serde(default)]
+    pub from: u64,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub sent: u64,
+}
+
+impl Chat {
+    pub fn new(scope: ChatScope, text: &str) -> Self {
+        Self {
+            scope,
+            text: text.into(),
+            from: 0,
+            name: None,
+            sent: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ChatRejection {
+    Empty,
+    TooLong { max: usize },
+    RateLimited,
+    Filtered,
+}
+
+impl fmt::Display for ChatRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChatRejection::Empty => write!(f, "empty message"),
+            ChatRejection::TooLong { max } => write!(f, "message longer than {} characters", max),
+            ChatRejection::RateLimited => write!(f, "too many messages"),
+            ChatRejection::Filtered => write!(f, "message refused by the filter"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RoomId(pub u64);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreateRoom {
+    pub name: String,
+    pub max_players: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JoinRoom {
+    pub room: RoomId,
+    #[serde(default)]
+    pub spectate: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ready {
+    pub ready: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Member {
+    pub player: u64,
+    pub cls: Class,
+    pub ready: bool,
+}
+
+///
+/// Public state of a room, as shown in the lobby
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub id: RoomId,
+    pub name: String,
+    pub owner: u64,
+    pub max_players: u32,
+    pub members: Vec<Member>,
+    #[serde(default)]
+    pub spectators: Vec<u64>,
+    pub started: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RoomError {
+    NotFound(RoomId),
+    Full(RoomId),
+    AlreadyInRoom(RoomId),
+    NotInRoom,
+    NotOwner,
+    NotReady,
+    Started(RoomId),
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoomError::NotFound(id) => write!(f, "room {} doesn't exist", id.0),
+            RoomError::Full(id) => write!(f, "room {} is full", id.0),
+            RoomError::AlreadyInRoom(id) => write!(f, "already in room {}", id.0),
+            RoomError::NotInRoom => write!(f, "not in a room"),
+            RoomError::NotOwner => write!(f, "only the owner can start the match"),
+            RoomError::NotReady => write!(f, "not all players are ready"),
+            RoomError::Started(id) => write!(f, "match already started in room {}", id.0),
+        }
     }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Message {
+    Hello(Hello),
+    HelloAck(HelloAck),
     Login(Login),
     LoginAck(LoginAck),
+    LoginRejected(LoginRejection),
     GetTerrain(GetTerrain),
     GetAllTerrain,
+    GetManifest(GetManifest),
+    Manifest(Manifest),
     Terrain(Terrain),
     EndTerrain,
     SendAction(SendAction),
+    Sequenced(Box<Sequenced>),
+    Ack(u64),
+    Ping(Ping),
+    Pong(Pong),
+    Snapshot(Snapshot),
+    Spawn(Box<Spawn>),
+    Chat(Chat),
+    ChatRejected(ChatRejection),
+    CreateRoom(CreateRoom),
+    ListRooms,
+    RoomList(Vec<RoomInfo>),
+    JoinRoom(JoinRoom),
+    RoomJoined(RoomInfo),
+    LeaveRoom,
+    RoomLeft(RoomId),
+    Ready(Ready),
+    RoomUpdated(RoomInfo),
+    StartMatch,
+    MatchStarted(RoomInfo),
+    RoomRejected(RoomError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_from_same_build_is_accepted() {
+        let ack = Hello::new().accept();
+
+        assert!(ack.rejection.is_none());
+        assert_eq!(ack.version, PROTOCOL_VERSION);
+        for f in FEATURES {
+            assert!(ack.has_feature(f));
+        }
+    }
+
+    #[test]
+    fn hello_with_other_version_is_rejected() {
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..Hello::new()
+        };
+
+        match hello.accept().rejection {
+            Some(Rejection::Version { expected, actual }) => {
+                assert_eq!(expected, PROTOCOL_VERSION);
+                assert_eq!(actual, PROTOCOL_VERSION + 1);
+            }
+            r => panic!("unexpected rejection {:?}", r),
+        }
+    }
+
+    #[test]
+    fn only_features_known_to_both_are_agreed() {
+        let hello = Hello {
+            features: vec!["heartbeat".into(), "teleport".into()],
+            ..Hello::new()
+        };
+        let ack = hello.accept();
+
+        assert!(ack.rejection.is_none());
+        assert_eq!(ack.features, vec!["heartbeat".to_string()]);
+        assert!(!ack.has_feature("resume"));
+    }
+
+    #[test]
+    fn handshake_survives_the_wire() {
+        let bytes = serde_json::to_vec(&Message::Hello(Hello::new())).unwrap();
+
+        match serde_json::from_slice(&bytes).unwrap() {
+            Message::Hello(hello) => assert!(hello.accept().rejection.is_none()),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    fn terrain(id: u64, x: f32, y: f32, w: f32, h: f32) -> Terrain {
+        Terrain {
+            id,
+            pos: Pos::new(x, y),
+            size: Size::new(w, h),
+            asset: Asset(1),
+            block: false,
+        }
+    }
+
+    #[test]
+    fn manifest_has_terrain_in_every_chunk_it_overlaps() {
+        let terrain = vec![
+            terrain(1, 10.0, 10.0, 20.0, 20.0),
+            terrain(2, 90.0, 10.0, 20.0, 100.0),
+        ];
+        let manifest = Manifest::new("map", 1, 100.0, &terrain);
+
+        let mut chunks: Vec<_> = manifest
+            .chunks
+            .iter()
+            .map(|e| (e.chunk.x, e.chunk.y))
+            .collect();
+        chunks.sort();
+        assert_eq!(chunks, vec![(0, 0), (0, 1), (1, 0), (1, 1)]);
+
+        let only_second = ChunkHash::of(&terrain[1..]);
+        assert_eq!(manifest.hash(&Chunk::new(1, 1)), Some(&only_second));
+        assert_ne!(manifest.hash(&Chunk::new(0, 0)), Some(&only_second));
+    }
+
+    #[test]
+    fn login_without_session_is_accepted() {
+        let login: Login = serde_json::from_str(r#"{"cls":1}"#).unwrap();
+
+        assert_eq!(login.cls, CLASS_CHIBA);
+        assert!(login.session.is_none());
+        assert!(login.credentials.is_none());
+    }
 }
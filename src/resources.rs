@@ -1,15 +1,25 @@
 use crate::components::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use specs::Entity;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Event {
     Collision,
+    Hit { shooter: u64, target: u64 },
+    Shot { player: u64 },
+    Triggered { trigger: String, player: u64 },
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct Events(pub Vec<Event>);
 
+///
+/// Number of turns simulated so far
+///
+#[derive(Default, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Tick(pub u64);
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PlayerUpdate {
     pub pos: Pos,
@@ -65,12 +75,117 @@ impl Action {
         self.update = true;
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn drop(&mut self) {
         self.drop = true;
         self.update = true;
     }
 
     pub fn clear(&mut self) -> Self {
-        std::mem::replace(self, Self::default())
+        std::mem::take(self)
+    }
+}
+
+///
+/// Terrain chunks currently present in the world
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chunks {
+    pub size: f32,
+    pub radius: i64,
+    pub loaded: HashSet<Chunk>,
+}
+
+impl Default for Chunks {
+    fn default() -> Self {
+        Self {
+            size: 1000.0,
+            radius: 1,
+            loaded: HashSet::new(),
+        }
+    }
+}
+
+///
+/// Spawn points of each class in the loaded level
+///
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct Spawns(pub HashMap<Class, Vec<Pos>>);
+
+///
+/// State of triggers, so that they fire when a player enters them
+///
+/// Triggers are told apart by entity, as several may share a name.
+///
+#[derive(Default, Clone, Debug)]
+pub struct Fired {
+    /// Once-only triggers which already fired
+    pub once: HashSet<Entity>,
+    /// Triggers and the player entities currently inside them
+    pub inside: HashSet<(Entity, Entity)>,
+}
+
+///
+/// What a spectator looks at
+///
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum View {
+    Follow(u64),
+    Free(Pos),
+}
+
+impl Default for View {
+    fn default() -> Self {
+        View::Free(Pos::zero())
+    }
+}
+
+///
+/// Recent positions of players, to evaluate hits as each shooter saw them
+///
+#[derive(Clone, Debug)]
+pub struct History {
+    pub tick_ms: u64,
+    /// Longest rewind allowed, whatever the round trip time of the shooter
+    pub max_rewind_ms: u64,
+    /// Round trip time of each player in milliseconds
+    pub rtts: HashMap<u64, u64>,
+    /// Position and size of each player per turn, newest first
+    pub frames: VecDeque<HashMap<u64, (Pos, Size)>>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            tick_ms: 16,
+            max_rewind_ms: 200,
+            rtts: HashMap::new(),
+            frames: VecDeque::new(),
+        }
+    }
+}
+
+impl History {
+    ///
+    /// Number of turns to go back for hits of the given shooter
+    ///
+    pub fn rewind(&self, shooter: u64) -> usize {
+        let rtt = self.rtts.get(&shooter).cloned().unwrap_or(0);
+        (rtt.min(self.max_rewind_ms) / self.tick_ms.max(1)) as usize
+    }
+
+    ///
+    /// Players as they were the given number of turns ago, or as far back
+    /// as recorded
+    ///
+    pub fn frame(&self, ago: usize) -> Option<&HashMap<u64, (Pos, Size)>> {
+        self.frames.get(ago).or_else(|| self.frames.back())
+    }
+
+    pub fn record(&mut self, frame: HashMap<u64, (Pos, Size)>) {
+        let len = (self.max_rewind_ms / self.tick_ms.max(1)) as usize + 1;
+
+        self.frames.push_front(frame);
+        self.frames.truncate(len);
     }
 }
@@ -0,0 +1,432 @@
+use crate::{
+    components::*,
+    error::{Error, Result},
+    protocol::*,
+    systems::Systems,
+};
+use log::*;
+use std::collections::{BTreeMap, HashMap};
+
+///
+/// A group of players waiting for, or playing, a match
+///
+pub struct Room {
+    id: RoomId,
+    name: String,
+    owner: u64,
+    max_players: u32,
+    members: Vec<Member>,
+    spectators: Vec<u64>,
+    systems: Option<Systems>,
+}
+
+impl Room {
+    fn new(id: RoomId, owner: u64, req: &CreateRoom) -> Self {
+        Self {
+            id,
+            name: req.name.clone(),
+            owner,
+            max_players: req.max_players.max(1),
+            members: Vec::new(),
+            spectators: Vec::new(),
+            systems: None,
+        }
+    }
+
+    pub fn id(&self) -> RoomId {
+        self.id
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.id,
+            name: self.name.clone(),
+            owner: self.owner,
+            max_players: self.max_players,
+            members: self.members.clone(),
+            spectators: self.spectators.clone(),
+            started: self.is_started(),
+        }
+    }
+
+    pub fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    ///
+    /// Players watching the room, who should receive its snapshots
+    ///
+    pub fn spectators(&self) -> &[u64] {
+        &self.spectators
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.systems.is_some()
+    }
+
+    ///
+    /// World of the running match, if started
+    ///
+    pub fn systems(&mut self) -> Option<&mut Systems> {
+        self.systems.as_mut()
+    }
+
+    fn count(&self, cls: Class) -> usize {
+        self.members.iter().filter(|m| m.cls == cls).count()
+    }
+
+    ///
+    /// Team with the fewest members, Chiba on a tie
+    ///
+    fn smaller_team(&self) -> Class {
+        if self.count(CLASS_SAITAMA) < self.count(CLASS_CHIBA) {
+            CLASS_SAITAMA
+        } else {
+            CLASS_CHIBA
+        }
+    }
+
+    ///
+    /// Move the latest joiners of the larger team until both teams differ
+    /// by at most one player
+    ///
+    fn balance(&mut self) {
+        loop {
+            let (chiba, saitama) = (self.count(CLASS_CHIBA), self.count(CLASS_SAITAMA));
+            let (from, to) = if chiba > saitama + 1 {
+                (CLASS_CHIBA, CLASS_SAITAMA)
+            } else if saitama > chiba + 1 {
+                (CLASS_SAITAMA, CLASS_CHIBA)
+            } else {
+                return;
+            };
+
+            if let Some(m) = self.members.iter_mut().rev().find(|m| m.cls == from) {
+                m.cls = to;
+            }
+        }
+    }
+}
+
+///
+/// Server-side registry of rooms and of the room each player is in
+///
+#[derive(Default)]
+pub struct Rooms {
+    rooms: BTreeMap<RoomId, Room>,
+    players: HashMap<u64, RoomId>,
+    next_id: u64,
+}
+
+impl Rooms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Create a room and put its creator in it
+    ///
+    pub fn create(&mut self, owner: u64, req: &CreateRoom) -> Result<RoomInfo> {
+        if let Some(&id) = self.players.get(&owner) {
+            return Err(Error::Room(RoomError::AlreadyInRoom(id)).into());
+        }
+
+        self.next_id += 1;
+        let id = RoomId(self.next_id);
+        self.rooms.insert(id, Room::new(id, owner, req));
+
+        self.join(owner, id)
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        self.rooms.values().map(Room::info).collect()
+    }
+
+    ///
+    /// Put a player in a room, in the team with fewer players
+    ///
+    pub fn join(&mut self, player: u64, id: RoomId) -> Result<RoomInfo> {
+        if let Some(&current) = self.players.get(&player) {
+            return Err(Error::Room(RoomError::AlreadyInRoom(current)).into());
+        }
+
+        let room = self
+            .rooms
+            .get_mut(&id)
+            .ok_or(Error::Room(RoomError::NotFound(id)))?;
+        if room.is_started() {
+            return Err(Error::Room(RoomError::Started(id)).into());
+        }
+        if room.members.len() >= room.max_players as usize {
+            return Err(Error::Room(RoomError::Full(id)).into());
+        }
+
+        let cls = room.smaller_team();
+        room.members.push(Member {
+            player,
+            cls,
+            ready: false,
+        });
+        self.players.insert(player, id);
+
+        info!("Player {} joined room {} as {:?}", player, id.0, cls);
+
+        Ok(room.info())
+    }
+
+    ///
+    /// Put a player in a room as a spectator, even once the match started
+    ///
+    pub fn spectate(&mut self, player: u64, id: RoomId) -> Result<RoomInfo> {
+        if let Some(&current) = self.players.get(&player) {
+            return Err(Error::Room(RoomError::AlreadyInRoom(current)).into());
+        }
+
+        let room = self
+            .rooms
+            .get_mut(&id)
+            .ok_or(Error::Room(RoomError::NotFound(id)))?;
+        room.spectators.push(player);
+        self.players.insert(player, id);
+
+        info!("Player {} spectates room {}", player, id.0);
+
+        Ok(room.info())
+    }
+
+    ///
+    /// Remove a player from its room, closing the room once no member is left
+    ///
+    pub fn leave(&mut self, player: u64) -> Result<RoomId> {
+        let id = self
+            .players
+            .remove(&player)
+            .ok_or(Error::Room(RoomError::NotInRoom))?;
+
+        let empty = match self.rooms.get_mut(&id) {
+            Some(room) => {
+                room.members.retain(|m| m.player != player);
+                room.spectators.retain(|&s| s != player);
+                if room.owner == player {
+                    if let Some(m) = room.members.first() {
+                        room.owner = m.player;
+                    }
+                }
+                room.members.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            info!("Closing room {}", id.0);
+            if let Some(room) = self.rooms.remove(&id) {
+                for s in room.spectators {
+                    self.players.remove(&s);
+                }
+            }
+        }
+
+        Ok(id)
+    }
+
+    pub fn set_ready(&mut self, player: u64, ready: bool) -> Result<RoomInfo> {
+        let room = self.room_of_mut(player)?;
+
+        for m in room.members.iter_mut().filter(|m| m.player == player) {
+            m.ready = ready;
+        }
+
+        Ok(room.info())
+    }
+
+    ///
+    /// Start the match of the room owned by the player
+    ///
+    /// Every member must be ready. Teams are rebalanced and a fresh world
+    /// is created for the room.
+    ///
+    pub fn start(&mut self, player: u64) -> Result<RoomInfo> {
+        let room = self.room_of_mut(player)?;
+
+        if room.owner != player {
+            return Err(Error::Room(RoomError::NotOwner).into());
+        }
+        if room.is_started() {
+            return Err(Error::Room(RoomError::Started(room.id)).into());
+        }
+        if room.members.iter().any(|m| !m.ready) {
+            return Err(Error::Room(RoomError::NotReady).into());
+        }
+
+        room.balance();
+        let mut sys = Systems::new()?;
+        sys.set_server(true);
+        room.systems = Some(sys);
+
+        info!("Match started in room {}", room.id.0);
+
+        Ok(room.info())
+    }
+
+    pub fn room_of(&self, player: u64) -> Option<&Room> {
+        self.players.get(&player).and_then(|id| self.rooms.get(id))
+    }
+
+    pub fn get_mut(&mut self, id: RoomId) -> Option<&mut Room> {
+        self.rooms.get_mut(&id)
+    }
+
+    fn room_of_mut(&mut self, player: u64) -> Result<&mut Room> {
+        let id = self
+            .players
+            .get(&player)
+            .ok_or(Error::Room(RoomError::NotInRoom))?;
+
+        Ok(self
+            .rooms
+            .get_mut(id)
+            .ok_or(Error::Room(RoomError::NotFound(*id)))?)
+    }
+
+    ///
+    /// Execute one turn in every started room
+    ///
+    pub fn update(&mut self) {
+        for room in self.rooms.values_mut() {
+            if let Some(sys) = room.systems.as_mut() {
+                sys.update();
+            }
+        }
+    }
+
+    ///
+    /// Answer a lobby message sent by a player
+    ///
+    /// Returns `None` for messages unrelated to rooms. Updates other members
+    /// of the room should hear about, like `MatchStarted`, are only returned
+    /// to the sender; the server forwards them to `Room::members`.
+    ///
+    pub fn handle(&mut self, player: u64, msg: &Message) -> Option<Message> {
+        let reply = match msg {
+            Message::CreateRoom(req) => self.create(player, req).map(Message::RoomJoined),
+            Message::ListRooms => Ok(Message::RoomList(self.list())),
+            Message::JoinRoom(req) if req.spectate => {
+                self.spectate(player, req.room).map(Message::RoomJoined)
+            }
+            Message::JoinRoom(req) => self.join(player, req.room).map(Message::RoomJoined),
+            Message::LeaveRoom => self.leave(player).map(Message::RoomLeft),
+            Message::Ready(req) => self.set_ready(player, req.ready).map(Message::RoomUpdated),
+            Message::StartMatch => self.start(player).map(Message::MatchStarted),
+            _ => return None,
+        };
+
+        match reply {
+            Ok(reply) => Some(reply),
+            Err(e) => {
+                if let Some(Error::Room(e)) = e.downcast_ref::<Error>() {
+                    return Some(Message::RoomRejected(e.clone()));
+                }
+                error!("Room request of player {} failed: {}", player, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(rooms: &mut Rooms, owner: u64, max_players: u32) -> RoomId {
+        let req = CreateRoom {
+            name: "room".into(),
+            max_players,
+        };
+        rooms.create(owner, &req).unwrap().id
+    }
+
+    fn rejection(rooms: &mut Rooms, player: u64, msg: Message) -> RoomError {
+        match rooms.handle(player, &msg) {
+            Some(Message::RoomRejected(e)) => e,
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn joiners_fill_the_smaller_team_until_full() {
+        let mut rooms = Rooms::new();
+        let id = create(&mut rooms, 1, 3);
+        rooms.join(2, id).unwrap();
+        let info = rooms.join(3, id).unwrap();
+
+        let teams: Vec<_> = info.members.iter().map(|m| m.cls).collect();
+        assert_eq!(teams, vec![CLASS_CHIBA, CLASS_SAITAMA, CLASS_CHIBA]);
+
+        let join = Message::JoinRoom(JoinRoom {
+            room: id,
+            spectate: false,
+        });
+        assert!(matches!(rejection(&mut rooms, 4, join), RoomError::Full(_)));
+        let create = Message::CreateRoom(CreateRoom {
+            name: "other".into(),
+            max_players: 2,
+        });
+        assert!(matches!(
+            rejection(&mut rooms, 1, create),
+            RoomError::AlreadyInRoom(_)
+        ));
+    }
+
+    #[test]
+    fn only_the_owner_starts_once_everyone_is_ready() {
+        let mut rooms = Rooms::new();
+        let id = create(&mut rooms, 1, 4);
+        rooms.join(2, id).unwrap();
+
+        rooms.set_ready(1, true).unwrap();
+        assert!(matches!(
+            rejection(&mut rooms, 1, Message::StartMatch),
+            RoomError::NotReady
+        ));
+        rooms.set_ready(2, true).unwrap();
+        assert!(matches!(
+            rejection(&mut rooms, 2, Message::StartMatch),
+            RoomError::NotOwner
+        ));
+
+        match rooms.handle(1, &Message::StartMatch) {
+            Some(Message::MatchStarted(info)) => assert!(info.started),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        assert!(rooms.get_mut(id).unwrap().systems().is_some());
+
+        let join = Message::JoinRoom(JoinRoom {
+            room: id,
+            spectate: false,
+        });
+        assert!(matches!(
+            rejection(&mut rooms, 3, join),
+            RoomError::Started(_)
+        ));
+        let info = rooms.spectate(3, id).unwrap();
+        assert_eq!(info.spectators, vec![3]);
+    }
+
+    #[test]
+    fn leaving_hands_over_ownership_and_closes_empty_rooms() {
+        let mut rooms = Rooms::new();
+        let id = create(&mut rooms, 1, 4);
+        rooms.join(2, id).unwrap();
+        rooms.spectate(3, id).unwrap();
+
+        rooms.leave(1).unwrap();
+        assert_eq!(rooms.room_of(2).unwrap().info().owner, 2);
+
+        rooms.leave(2).unwrap();
+        assert!(rooms.list().is_empty());
+        assert!(rooms.room_of(3).is_none());
+        assert!(matches!(
+            rejection(&mut rooms, 2, Message::LeaveRoom),
+            RoomError::NotInRoom
+        ));
+    }
+}
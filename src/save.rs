@@ -0,0 +1,294 @@
+use crate::{
+    camera::Camera,
+    components::*,
+    error::{Error, Result},
+    resources::*,
+    systems::Systems,
+};
+use serde::{Deserialize, Serialize};
+use specs::{
+    error::NoError,
+    prelude::*,
+    saveload::{
+        DeserializeComponents, MarkerAllocator, SerializeComponents, SimpleMarker,
+        SimpleMarkerAllocator,
+    },
+};
+use std::io::{Read, Write as IoWrite};
+
+///
+/// Version of the save documents written by this build
+///
+pub const SAVE_VERSION: u32 = 1;
+
+///
+/// Marker of the entities stored in saves
+///
+pub struct Saved;
+
+pub type SaveMarker = SimpleMarker<Saved>;
+
+pub type SaveMarkerAllocator = SimpleMarkerAllocator<Saved>;
+
+#[derive(Serialize, Deserialize)]
+struct Document {
+    version: u32,
+    tick: Tick,
+    action: Action,
+    player_updates: PlayerUpdates,
+    events: Events,
+    #[serde(default)]
+    chunks: Chunks,
+    #[serde(default)]
+    spawns: Spawns,
+    #[serde(default)]
+    view: View,
+    #[serde(default)]
+    camera: Camera,
+    entities: serde_json::Value,
+    /// Components past the number a single pass can store
+    #[serde(default)]
+    extra: serde_json::Value,
+}
+
+type ReadSaved<'a> = (
+    ReadStorage<'a, Pos>,
+    ReadStorage<'a, Vel>,
+    ReadStorage<'a, Acc>,
+    ReadStorage<'a, Size>,
+    ReadStorage<'a, Player>,
+    ReadStorage<'a, Bullet>,
+    ReadStorage<'a, Landmark>,
+    ReadStorage<'a, Block>,
+    ReadStorage<'a, Background>,
+    ReadStorage<'a, Dir>,
+    ReadStorage<'a, Asset>,
+    ReadStorage<'a, User>,
+    ReadStorage<'a, Streamed>,
+    ReadStorage<'a, Item>,
+    ReadStorage<'a, Trigger>,
+    ReadStorage<'a, Animation>,
+);
+
+type WriteSaved<'a> = (
+    WriteStorage<'a, Pos>,
+    WriteStorage<'a, Vel>,
+    WriteStorage<'a, Acc>,
+    WriteStorage<'a, Size>,
+    WriteStorage<'a, Player>,
+    WriteStorage<'a, Bullet>,
+    WriteStorage<'a, Landmark>,
+    WriteStorage<'a, Block>,
+    WriteStorage<'a, Background>,
+    WriteStorage<'a, Dir>,
+    WriteStorage<'a, Asset>,
+    WriteStorage<'a, User>,
+    WriteStorage<'a, Streamed>,
+    WriteStorage<'a, Item>,
+    WriteStorage<'a, Trigger>,
+    WriteStorage<'a, Animation>,
+);
+
+type ReadExtra<'a> = (ReadStorage<'a, Layer>,);
+
+type WriteExtra<'a> = (WriteStorage<'a, Layer>,);
+
+impl Systems {
+    ///
+    /// Write all entities and the game resources as a JSON document
+    ///
+    pub fn save<W: IoWrite>(&mut self, writer: W) -> Result<()> {
+        self.mark_all();
+
+        let (entities, extra) = {
+            let (entities, markers, storages, extra) =
+                self.world
+                    .system_data::<(Entities, ReadStorage<SaveMarker>, ReadSaved, ReadExtra)>();
+
+            (
+                SerializeComponents::<NoError, SaveMarker>::serialize(
+                    &storages,
+                    &entities,
+                    &markers,
+                    serde_json::value::Serializer,
+                )?,
+                SerializeComponents::<NoError, SaveMarker>::serialize(
+                    &extra,
+                    &entities,
+                    &markers,
+                    serde_json::value::Serializer,
+                )?,
+            )
+        };
+
+        let doc = Document {
+            version: SAVE_VERSION,
+            tick: *self.world.read_resource::<Tick>(),
+            action: (*self.world.read_resource::<Action>()).clone(),
+            player_updates: (*self.world.read_resource::<PlayerUpdates>()).clone(),
+            events: (*self.world.read_resource::<Events>()).clone(),
+            chunks: (*self.world.read_resource::<Chunks>()).clone(),
+            spawns: (*self.world.read_resource::<Spawns>()).clone(),
+            view: *self.world.read_resource::<View>(),
+            camera: (*self.world.read_resource::<Camera>()).clone(),
+            entities,
+            extra,
+        };
+        serde_json::to_writer(writer, &doc)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Create a world from a document written by `save`
+    ///
+    pub fn load<R: Read>(reader: R) -> Result<Self> {
+        let doc: Document = serde_json::from_reader(reader)?;
+        if doc.version != SAVE_VERSION {
+            return Err(Error::UnsupportedSave(doc.version).into());
+        }
+
+        let mut sys = Systems::new()?;
+        {
+            let (entities, mut markers, mut allocator, mut storages, mut extra) =
+                sys.world.system_data::<(
+                    Entities,
+                    WriteStorage<SaveMarker>,
+                    Write<SaveMarkerAllocator>,
+                    WriteSaved,
+                    WriteExtra,
+                )>();
+
+            DeserializeComponents::<NoError, SaveMarker>::deserialize(
+                &mut storages,
+                &entities,
+                &mut markers,
+                &mut allocator,
+                doc.entities,
+            )?;
+            // Markers found in both passes resolve to the same entities
+            if !doc.extra.is_null() {
+                DeserializeComponents::<NoError, SaveMarker>::deserialize(
+                    &mut extra,
+                    &entities,
+                    &mut markers,
+                    &mut allocator,
+                    doc.extra,
+                )?;
+            }
+        }
+
+        *sys.world.write_resource::<Tick>() = doc.tick;
+        *sys.world.write_resource::<Action>() = doc.action;
+        *sys.world.write_resource::<PlayerUpdates>() = doc.player_updates;
+        *sys.world.write_resource::<Events>() = doc.events;
+        *sys.world.write_resource::<Chunks>() = doc.chunks;
+        *sys.world.write_resource::<Spawns>() = doc.spawns;
+        *sys.world.write_resource::<View>() = doc.view;
+        *sys.world.write_resource::<Camera>() = doc.camera;
+        sys.world.maintain();
+
+        Ok(sys)
+    }
+
+    ///
+    /// Give a marker to the entities created since the last save
+    ///
+    fn mark_all(&mut self) {
+        let (entities, mut markers, mut allocator) = self.world.system_data::<(
+            Entities,
+            WriteStorage<SaveMarker>,
+            Write<SaveMarkerAllocator>,
+        )>();
+
+        for e in (&entities).join() {
+            allocator.mark(e, &mut markers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{entities::CreateEntity, protocol::Terrain};
+
+    #[test]
+    fn world_survives_save_and_load() {
+        let mut sys = Systems::new().unwrap();
+        sys.create_entity().create_user(
+            Pos::new(5.0, 0.0),
+            Size::new(10.0, 10.0),
+            Player::new(1, CLASS_CHIBA, 2),
+            Asset(1),
+        );
+        let wall = Terrain {
+            id: 9,
+            pos: Pos::new(90.0, 0.0),
+            size: Size::new(20.0, 20.0),
+            asset: Asset(2),
+            block: true,
+        };
+        sys.set_streaming(100.0, 2);
+        sys.load_chunk(Chunk::new(0, 0), std::slice::from_ref(&wall));
+        sys.load_chunk(Chunk::new(1, 0), &[wall]);
+        sys.world
+            .write_resource::<Spawns>()
+            .0
+            .insert(CLASS_SAITAMA, vec![Pos::new(7.0, 8.0)]);
+        let e = (&sys.world.entities(), &sys.world.read_storage::<Block>())
+            .join()
+            .next()
+            .unwrap()
+            .0;
+        sys.world
+            .write_storage::<Layer>()
+            .insert(e, LAYER_BLOCK)
+            .unwrap();
+        sys.update();
+
+        let mut bytes = Vec::new();
+        sys.save(&mut bytes).unwrap();
+        let loaded = Systems::load(&bytes[..]).unwrap();
+        let world = &loaded.world;
+
+        assert_eq!(loaded.tick(), 1);
+        let users: Vec<_> = (
+            &world.read_storage::<Player>(),
+            &world.read_storage::<User>(),
+        )
+            .join()
+            .map(|(p, _)| (p.id, p.lives))
+            .collect();
+        assert_eq!(users, vec![(1, 2)]);
+
+        let streamed: Vec<_> = (
+            &world.read_storage::<Streamed>(),
+            &world.read_storage::<Block>(),
+        )
+            .join()
+            .map(|(s, _)| (s.id, s.chunks.clone()))
+            .collect();
+        assert_eq!(
+            streamed,
+            vec![(9, vec![Chunk::new(0, 0), Chunk::new(1, 0)])]
+        );
+        let layers: Vec<_> = world.read_storage::<Layer>().join().cloned().collect();
+        assert_eq!(layers, vec![LAYER_BLOCK]);
+
+        let chunks = world.read_resource::<Chunks>();
+        assert_eq!(
+            (chunks.size, chunks.radius, chunks.loaded.len()),
+            (100.0, 2, 2)
+        );
+        assert_eq!(
+            world.read_resource::<Spawns>().0[&CLASS_SAITAMA],
+            vec![Pos::new(7.0, 8.0)]
+        );
+    }
+
+    #[test]
+    fn absent_tags_are_not_restored() {
+        assert!(serde_json::from_str::<User>("true").is_ok());
+        assert!(serde_json::from_str::<User>("false").is_err());
+    }
+}
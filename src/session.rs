@@ -0,0 +1,155 @@
+use crate::{components::*, protocol::SessionToken, resources::PlayerUpdate};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+struct Entry {
+    update: PlayerUpdate,
+    last_seen: Instant,
+    last_seq: Option<u64>,
+}
+
+///
+/// Server-side store of player state kept across reconnections
+///
+pub struct Sessions {
+    entries: HashMap<SessionToken, Entry>,
+    ttl: Duration,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+        }
+    }
+
+    ///
+    /// Open a new session for a freshly logged in player
+    ///
+    pub fn open(&mut self, player: Player, spawn: Pos) -> SessionToken {
+        let token = SessionToken(thread_rng().sample_iter(&Alphanumeric).take(32).collect());
+        let update = PlayerUpdate::new(spawn, player, Dir(1.0), Vel::zero(), Acc::gravity());
+
+        self.entries.insert(
+            token.clone(),
+            Entry {
+                update,
+                last_seen: Instant::now(),
+                last_seq: None,
+            },
+        );
+
+        token
+    }
+
+    ///
+    /// Record the latest state of the player owning the session
+    ///
+    pub fn update(&mut self, token: &SessionToken, update: PlayerUpdate) {
+        if let Some(entry) = self.entries.get_mut(token) {
+            entry.update = update;
+            entry.last_seen = Instant::now();
+        }
+    }
+
+    ///
+    /// Reclaim the player state of a session, if it is still alive
+    ///
+    pub fn resume(&mut self, token: &SessionToken) -> Option<PlayerUpdate> {
+        self.expire();
+
+        self.entries.get_mut(token).map(|entry| {
+            entry.last_seen = Instant::now();
+            entry.update.clone()
+        })
+    }
+
+    ///
+    /// Record a sequenced message of the session
+    ///
+    /// Returns `false` if it was already received, e.g. sent again after a
+    /// reconnection, and must be ignored.
+    ///
+    pub fn receive(&mut self, token: &SessionToken, seq: u64) -> bool {
+        match self.entries.get_mut(token) {
+            Some(entry) if entry.last_seq.map(|s| seq > s).unwrap_or(true) => {
+                entry.last_seq = Some(seq);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    ///
+    /// Highest sequence number received in the session, to acknowledge
+    ///
+    pub fn acked(&self, token: &SessionToken) -> Option<u64> {
+        self.entries.get(token).and_then(|e| e.last_seq)
+    }
+
+    pub fn close(&mut self, token: &SessionToken) {
+        self.entries.remove(token);
+    }
+
+    ///
+    /// Drop sessions not seen for longer than the configured lifetime
+    ///
+    pub fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, e| e.last_seen.elapsed() < ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::Vector;
+
+    #[test]
+    fn resent_messages_are_ignored() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.open(Player::new(1, CLASS_CHIBA, 3), Pos::zero());
+
+        assert!(sessions.receive(&token, 1));
+        assert!(sessions.receive(&token, 2));
+        assert!(!sessions.receive(&token, 2));
+        assert!(!sessions.receive(&token, 1));
+        assert_eq!(sessions.acked(&token), Some(2));
+    }
+
+    #[test]
+    fn resumed_session_keeps_player_state() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.open(Player::new(1, CLASS_CHIBA, 3), Pos::zero());
+
+        let pos = Pos::new(10.0, 20.0);
+        let update = PlayerUpdate::new(
+            pos,
+            Player::new(1, CLASS_CHIBA, 2),
+            Dir(-1.0),
+            Vel::zero(),
+            Acc::gravity(),
+        );
+        sessions.update(&token, update);
+
+        let resumed = sessions.resume(&token).unwrap();
+        assert_eq!(resumed.player.lives, 2);
+        assert!((resumed.pos - pos).len() < 1e-6);
+
+        sessions.close(&token);
+        assert!(sessions.resume(&token).is_none());
+        assert!(!sessions.receive(&token, 3));
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let mut sessions = Sessions::new(Duration::from_millis(0));
+        let token = sessions.open(Player::new(1, CLASS_CHIBA, 3), Pos::zero());
+
+        assert!(sessions.resume(&token).is_none());
+    }
+}
@@ -1,16 +1,22 @@
 use crate::{
-    client::Client,
-    collide::{cease_vel, collide, normal, toi, update_vel},
+    animation::{Animate, Animations},
+    assets::{Assets, BULLET, DEFAULT_BULLET},
+    camera::{Camera, FollowCamera},
+    collide::{collide, rests_on, update_vel},
     components::*,
-    config::Config,
+    draw::{DrawList, LastPos, RememberPos},
     entities::{CreateEntity, EntityCreator},
     error::Result,
+    prefab::Prefabs,
     protocol::*,
     resources::*,
-    vector::Vector,
+    save::{SaveMarker, SaveMarkerAllocator},
 };
 use specs::{prelude::*, world::EntityBuilder};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use log::*;
 
@@ -39,14 +45,16 @@ impl<'a> System<'a> for TakeAction {
         WriteStorage<'a, Vel>,
         ReadStorage<'a, Acc>,
         WriteStorage<'a, Dir>,
+        Read<'a, Assets>,
+        Write<'a, Events>,
         Read<'a, LazyUpdate>,
     );
 
     fn run(
         &mut self,
-        (e, mut act, player, user, pos, siz, mut vel, acc, mut dir, lazy): Self::SystemData,
+        (e, mut act, player, user, pos, siz, mut vel, acc, mut dir, assets, mut events, lazy): Self::SystemData,
     ) {
-        for (player, _, pos, siz, vel, acc, dir) in
+        for (player, _, pos, siz, vel, _, dir) in
             (&player, &user, &pos, &siz, &mut vel, &acc, &mut dir).join()
         {
             if !act.update {
@@ -54,14 +62,14 @@ impl<'a> System<'a> for TakeAction {
             }
 
             if act.jump {
-                vel.y = 5.0;
+                vel.y = JUMP_SPEED;
             }
             if act.right {
-                vel.x = 5.0;
+                vel.x = RUN_SPEED;
                 dir.0 = 1.0;
             }
             if act.left {
-                vel.x = -5.0;
+                vel.x = -RUN_SPEED;
                 dir.0 = -1.0;
             }
             if act.take {
@@ -71,8 +79,9 @@ impl<'a> System<'a> for TakeAction {
                     *pos + Vel::new(d, 0.0),
                     Bullet::new(player.id, player.class),
                     Size::new(30.0, 30.0),
-                    Asset(100),
+                    assets.named(BULLET).unwrap_or(DEFAULT_BULLET),
                 );
+                events.0.push(Event::Shot { player: player.id });
             }
         }
 
@@ -104,10 +113,14 @@ impl<'a> System<'a> for UpdateCollide {
         WriteStorage<'a, Player>,
         ReadStorage<'a, User>,
         ReadStorage<'a, Block>,
+        WriteStorage<'a, Grounded>,
         Read<'a, LazyUpdate>,
     );
 
-    fn run(&mut self, (e, pos, siz, mut vel, bullet, mut ply, user, blk, lazy): Self::SystemData) {
+    fn run(
+        &mut self,
+        (e, pos, siz, mut vel, _bullet, ply, _user, blk, mut grounded, _lazy): Self::SystemData,
+    ) {
         let mut map = HashMap::<_, Vel>::new();
 
         for (e1, p1, s1, _) in (&e, &pos, &siz, &ply).join() {
@@ -132,11 +145,25 @@ impl<'a> System<'a> for UpdateCollide {
         }
 
         for (e, v) in map {
-            match vel.get_mut(e) {
-                Some(vel) => {
-                    *vel = v;
-                }
-                None => {}
+            if let Some(vel) = vel.get_mut(e) {
+                *vel = v;
+            }
+        }
+
+        // Players standing on a block once moved, unless leaving it upwards
+        let z = Vel::zero();
+        for (e1, p1, s1, _) in (&e, &pos, &siz, &ply).join() {
+            let v1 = vel.get(e1).unwrap_or(&z);
+            let next = *p1 + *v1;
+            let standing = v1.y <= 0.0
+                && (&e, &pos, &siz, &blk).join().any(|(e2, p2, s2, _)| {
+                    e2 != e1 && rests_on(&next, s1, &(*p2 + *vel.get(e2).unwrap_or(&z)), s2)
+                });
+
+            if standing {
+                let _ = grounded.insert(e1, Grounded);
+            } else {
+                grounded.remove(e1);
             }
         }
     }
@@ -154,30 +181,172 @@ impl<'a> System<'a> for UpdatePos {
     }
 }
 
+struct RecordHistory;
+
+impl<'a> System<'a> for RecordHistory {
+    type SystemData = (
+        Write<'a, History>,
+        ReadStorage<'a, Pos>,
+        ReadStorage<'a, Size>,
+        ReadStorage<'a, Player>,
+    );
+
+    fn run(&mut self, (mut history, pos, siz, ply): Self::SystemData) {
+        let frame = (&pos, &siz, &ply)
+            .join()
+            .map(|(pos, siz, ply)| (ply.id, (*pos, *siz)))
+            .collect();
+
+        history.record(frame);
+    }
+}
+
+///
+/// Hit players with bullets, placing players where the shooter saw them
+///
+struct BulletHit;
+
+impl<'a> System<'a> for BulletHit {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, History>,
+        Write<'a, Events>,
+        ReadStorage<'a, Pos>,
+        ReadStorage<'a, Size>,
+        ReadStorage<'a, Vel>,
+        ReadStorage<'a, Bullet>,
+        WriteStorage<'a, Player>,
+    );
+
+    fn run(&mut self, (e, history, mut events, pos, siz, vel, bullet, mut ply): Self::SystemData) {
+        for (e1, p1, s1, v1, bullet) in (&e, &pos, &siz, &vel, &bullet).join() {
+            let frame = match history.frame(history.rewind(bullet.id)) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            // Sweep over the move of this turn
+            let from = *p1 - *v1;
+
+            for target in (&mut ply).join() {
+                if target.id == bullet.id || target.class == bullet.class {
+                    continue;
+                }
+                let (p2, s2) = match frame.get(&target.id) {
+                    Some(ps) => ps,
+                    None => continue,
+                };
+
+                if collide(&from, s1, v1, p2, s2, &Vel::zero()) {
+                    target.lives = target.lives.saturating_sub(1);
+                    events.0.push(Event::Hit {
+                        shooter: bullet.id,
+                        target: target.id,
+                    });
+                    let _ = e.delete(e1);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+///
+/// Raise an event when a player overlaps a trigger
+///
+struct CheckTrigger;
+
+impl<'a> System<'a> for CheckTrigger {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, Events>,
+        Write<'a, Fired>,
+        ReadStorage<'a, Pos>,
+        ReadStorage<'a, Size>,
+        ReadStorage<'a, Trigger>,
+        ReadStorage<'a, Player>,
+    );
+
+    fn run(&mut self, (e, mut events, mut fired, pos, siz, trigger, ply): Self::SystemData) {
+        fired.once.retain(|t| e.is_alive(*t));
+        fired
+            .inside
+            .retain(|(t, p)| e.is_alive(*t) && e.is_alive(*p));
+
+        for (e1, p1, s1, trigger) in (&e, &pos, &siz, &trigger).join() {
+            for (e2, p2, s2, ply) in (&e, &pos, &siz, &ply).join() {
+                let overlap = p1.x < p2.x + s2.x
+                    && p2.x < p1.x + s1.x
+                    && p1.y < p2.y + s2.y
+                    && p2.y < p1.y + s1.y;
+                let key = (e1, e2);
+
+                if !overlap {
+                    fired.inside.remove(&key);
+                    continue;
+                }
+                if !fired.inside.insert(key) || fired.once.contains(&e1) {
+                    continue;
+                }
+                if trigger.once {
+                    fired.once.insert(e1);
+                }
+
+                events.0.push(Event::Triggered {
+                    trigger: trigger.name.clone(),
+                    player: ply.id,
+                });
+            }
+        }
+    }
+}
+
 struct OutOfBound;
 
 impl<'a> System<'a> for OutOfBound {
     type SystemData = (
         Entities<'a>,
+        Write<'a, Chunks>,
+        Read<'a, Camera>,
         ReadStorage<'a, Pos>,
-        ReadStorage<'a, Player>,
+        WriteStorage<'a, Streamed>,
+        ReadStorage<'a, Bullet>,
         ReadStorage<'a, User>,
     );
 
-    fn run(&mut self, (e, pos, ply, user): Self::SystemData) {
-        for (plypos, ply, _) in (&pos, &ply, &user).join() {
-            for (e1, pos) in (&e, &pos).join() {
-                let d = *pos - *plypos;
-                if d.len() >= 2000.0 {
-                    e.delete(e1);
-                }
+    fn run(&mut self, (e, mut chunks, camera, pos, mut streamed, bullet, user): Self::SystemData) {
+        // Keep what is shown, which is around the user unless spectating
+        let center = if camera.is_tracking() {
+            Chunk::of(&camera.center, chunks.size)
+        } else {
+            match (&pos, &user).join().next() {
+                Some((pos, _)) => Chunk::of(pos, chunks.size),
+                None => return,
+            }
+        };
+        // Keep one more chunk than streamed to avoid reloading at the border
+        let keep = chunks.radius + 1;
+
+        // Terrain goes once none of the chunks it overlaps is kept
+        for (e1, s) in (&e, &mut streamed).join() {
+            s.chunks.retain(|c| c.distance(&center) <= keep);
+            if s.chunks.is_empty() {
+                let _ = e.delete(e1);
             }
         }
+        for (e1, pos, _) in (&e, &pos, &bullet).join() {
+            if Chunk::of(pos, chunks.size).distance(&center) > keep {
+                let _ = e.delete(e1);
+            }
+        }
+
+        chunks.loaded.retain(|c| c.distance(&center) <= keep);
     }
 }
 
 pub struct Systems {
     pub(crate) world: World,
+    /// Hits are only decided by the server, clients learn them by snapshots
+    server: bool,
 }
 
 impl Systems {
@@ -192,15 +361,45 @@ impl Systems {
         world.register::<Bullet>();
         world.register::<Landmark>();
         world.register::<Block>();
+        world.register::<Grounded>();
         world.register::<Background>();
         world.register::<Dir>();
         world.register::<Asset>();
         world.register::<User>();
+        world.register::<Streamed>();
+        world.register::<Item>();
+        world.register::<Trigger>();
+        world.register::<Animation>();
+        world.register::<Layer>();
+        world.register::<SaveMarker>();
         world.insert(Action::default());
         world.insert(PlayerUpdates::default());
         world.insert(Events::default());
+        world.insert(Tick::default());
+        world.insert(Chunks::default());
+        world.insert(History::default());
+        world.insert(View::default());
+        world.insert(Spawns::default());
+        world.insert(Fired::default());
+        world.insert(Prefabs::builtin());
+        world.insert(Assets::default());
+        world.insert(Animations::default());
+        world.insert(DrawList::default());
+        world.insert(Camera::default());
+        world.insert(LastPos::default());
+        world.insert(SaveMarkerAllocator::new());
+
+        Ok(Self {
+            world,
+            server: false,
+        })
+    }
 
-        Ok(Self { world })
+    ///
+    /// Make this world the authoritative one of a server
+    ///
+    pub fn set_server(&mut self, server: bool) {
+        self.server = server;
     }
 
     ///
@@ -221,27 +420,319 @@ impl Systems {
             .insert(player.id, PlayerUpdate::new(pos, player, dir, vel, acc));
     }
 
+    ///
+    /// Apply an action sent by a client, once checked by a server
+    ///
+    /// The round trip time the client reports sets how far back its shots
+    /// are evaluated.
+    ///
+    pub fn apply_action(&mut self, info: &SendAction) {
+        if let Some(rtt) = info.rtt_ms {
+            self.set_rtt(info.player.id, Duration::from_millis(rtt));
+        }
+        self.update_player(info.pos, info.player.clone(), info.dir, info.vel, info.acc);
+    }
+
     ///
     /// Create a new entity
     ///
-    pub fn create_entity(&mut self) -> EntityCreator<EntityBuilder> {
+    pub fn create_entity(&mut self) -> EntityCreator<EntityBuilder<'_>> {
         self.world.create_entity().into()
     }
 
+    ///
+    /// Set the chunk size and the number of chunks kept around the user
+    ///
+    pub fn set_streaming(&mut self, size: f32, radius: i64) {
+        let mut chunks = self.world.write_resource::<Chunks>();
+        chunks.size = size;
+        chunks.radius = radius;
+    }
+
+    ///
+    /// Insert the terrain of a chunk, replacing what was loaded before
+    ///
+    /// Terrain already loaded with a neighbouring chunk is shared rather
+    /// than created again.
+    ///
+    pub fn load_chunk(&mut self, chunk: Chunk, terrain: &[Terrain]) {
+        let ids: HashSet<_> = terrain.iter().map(|t| t.id).collect();
+        self.release_chunk(chunk, |s| !ids.contains(&s.id));
+
+        let mut loaded = HashSet::new();
+        {
+            let entities = self.world.entities();
+            let mut streamed = self.world.write_storage::<Streamed>();
+            for (_, s) in (&entities, &mut streamed).join() {
+                if ids.contains(&s.id) {
+                    if !s.chunks.contains(&chunk) {
+                        s.chunks.push(chunk);
+                    }
+                    loaded.insert(s.id);
+                }
+            }
+        }
+
+        for t in terrain.iter().filter(|t| !loaded.contains(&t.id)) {
+            let e = if t.block {
+                self.create_entity()
+                    .create_terrain_block(t.pos, t.size, t.asset)
+            } else {
+                self.create_entity().create_terrain(t.pos, t.size, t.asset)
+            };
+            let streamed = Streamed {
+                id: t.id,
+                chunks: vec![chunk],
+            };
+            let _ = self.world.write_storage::<Streamed>().insert(e, streamed);
+        }
+
+        self.world.write_resource::<Chunks>().loaded.insert(chunk);
+    }
+
+    ///
+    /// Delete the entities streamed in with a chunk, unless they overlap
+    /// another loaded chunk
+    ///
+    pub fn unload_chunk(&mut self, chunk: Chunk) {
+        self.release_chunk(chunk, |_| true);
+        self.world.write_resource::<Chunks>().loaded.remove(&chunk);
+    }
+
+    ///
+    /// Detach the selected terrain from a chunk, deleting what is left in none
+    ///
+    fn release_chunk<F: Fn(&Streamed) -> bool>(&mut self, chunk: Chunk, select: F) {
+        {
+            let entities = self.world.entities();
+            let mut streamed = self.world.write_storage::<Streamed>();
+            for (e, s) in (&entities, &mut streamed).join() {
+                if select(s) {
+                    s.chunks.retain(|c| *c != chunk);
+                    if s.chunks.is_empty() {
+                        let _ = entities.delete(e);
+                    }
+                }
+            }
+        }
+        self.world.maintain();
+    }
+
+    ///
+    /// Set the turn duration and how far back hits may be evaluated
+    ///
+    pub fn set_lag_compensation(&mut self, tick_ms: u64, max_rewind_ms: u64) {
+        let mut history = self.world.write_resource::<History>();
+        history.tick_ms = tick_ms;
+        history.max_rewind_ms = max_rewind_ms;
+    }
+
+    ///
+    /// Round trip time of a player, used to rewind the world for its shots
+    ///
+    pub fn set_rtt(&mut self, player: u64, rtt: Duration) {
+        self.world
+            .write_resource::<History>()
+            .rtts
+            .insert(player, rtt.as_millis() as u64);
+    }
+
+    ///
+    /// State of every player and bullet, to stream to spectators
+    ///
+    pub fn snapshot(&self) -> Snapshot {
+        let pos = self.world.read_storage::<Pos>();
+        let siz = self.world.read_storage::<Size>();
+        let vel = self.world.read_storage::<Vel>();
+        let dir = self.world.read_storage::<Dir>();
+        let asset = self.world.read_storage::<Asset>();
+        let player = self.world.read_storage::<Player>();
+        let bullet = self.world.read_storage::<Bullet>();
+
+        Snapshot {
+            tick: self.tick(),
+            players: (&player, &pos, &siz, &vel, &dir, &asset)
+                .join()
+                .map(|(player, pos, size, vel, dir, asset)| PlayerState {
+                    player: player.clone(),
+                    pos: *pos,
+                    size: *size,
+                    vel: *vel,
+                    dir: *dir,
+                    asset: *asset,
+                })
+                .collect(),
+            bullets: (&bullet, &pos, &siz, &vel, &asset)
+                .join()
+                .map(|(bullet, pos, size, vel, asset)| BulletState {
+                    bullet: bullet.clone(),
+                    pos: *pos,
+                    size: *size,
+                    vel: *vel,
+                    asset: *asset,
+                })
+                .collect(),
+        }
+    }
+
+    ///
+    /// Replace players and bullets by the ones of a snapshot
+    ///
+    /// The user keeps the position and velocity it predicted, only its player
+    /// state and asset are taken from the snapshot.
+    ///
+    pub fn apply_snapshot(&mut self, snapshot: &Snapshot) {
+        let ids: HashSet<_> = snapshot.players.iter().map(|s| s.player.id).collect();
+        {
+            let entities = self.world.entities();
+            let player = self.world.read_storage::<Player>();
+            let bullet = self.world.read_storage::<Bullet>();
+            let user = self.world.read_storage::<User>();
+
+            for (e, player, _) in (&entities, &player, !&user).join() {
+                if !ids.contains(&player.id) {
+                    let _ = entities.delete(e);
+                }
+            }
+            for (e, _) in (&entities, &bullet).join() {
+                let _ = entities.delete(e);
+            }
+        }
+        self.world.maintain();
+
+        let existing: HashMap<_, _> =
+            (&self.world.entities(), &self.world.read_storage::<Player>())
+                .join()
+                .map(|(e, player)| (player.id, e))
+                .collect();
+
+        for s in &snapshot.players {
+            let e = match existing.get(&s.player.id) {
+                Some(&e) => e,
+                None => {
+                    self.create_entity()
+                        .create_player(s.pos, s.size, s.player.clone(), s.asset)
+                }
+            };
+            let _ = self
+                .world
+                .write_storage::<Player>()
+                .insert(e, s.player.clone());
+            let _ = self.world.write_storage::<Asset>().insert(e, s.asset);
+            if self.world.read_storage::<User>().contains(e) {
+                continue;
+            }
+            let _ = self.world.write_storage::<Pos>().insert(e, s.pos);
+            let _ = self.world.write_storage::<Size>().insert(e, s.size);
+            let _ = self.world.write_storage::<Vel>().insert(e, s.vel);
+            let _ = self.world.write_storage::<Dir>().insert(e, s.dir);
+        }
+        for s in &snapshot.bullets {
+            self.create_entity()
+                .create_bullet(s.vel, s.pos, s.bullet.clone(), s.size, s.asset);
+        }
+
+        self.world.write_resource::<Tick>().0 = snapshot.tick;
+    }
+
+    ///
+    /// Make the spectator view follow a player
+    ///
+    pub fn follow(&mut self, player: u64) {
+        *self.world.write_resource::<View>() = View::Follow(player);
+    }
+
+    ///
+    /// Follow the player with the next id, wrapping around
+    ///
+    pub fn follow_next(&mut self) -> Option<u64> {
+        let mut ids: Vec<_> = self
+            .world
+            .read_storage::<Player>()
+            .join()
+            .map(|p| p.id)
+            .collect();
+        ids.sort();
+
+        let next = match *self.world.read_resource::<View>() {
+            View::Follow(current) => ids.iter().find(|&&id| id > current).or_else(|| ids.first()),
+            View::Free(_) => ids.first(),
+        }
+        .cloned()?;
+
+        self.follow(next);
+        Some(next)
+    }
+
+    ///
+    /// Stop following, keeping the view where it is
+    ///
+    pub fn free_roam(&mut self) {
+        let pos = self.view_pos().unwrap_or_else(Pos::zero);
+        *self.world.write_resource::<View>() = View::Free(pos);
+    }
+
+    ///
+    /// Move the free-roaming view
+    ///
+    pub fn move_view(&mut self, delta: Vel) {
+        if let View::Free(pos) = &mut *self.world.write_resource::<View>() {
+            *pos += delta;
+        }
+    }
+
+    ///
+    /// Position the spectator looks at, if the followed player exists
+    ///
+    pub fn view_pos(&self) -> Option<Pos> {
+        match *self.world.read_resource::<View>() {
+            View::Follow(id) => (
+                &self.world.read_storage::<Player>(),
+                &self.world.read_storage::<Pos>(),
+            )
+                .join()
+                .find(|(p, _)| p.id == id)
+                .map(|(_, pos)| *pos),
+            View::Free(pos) => Some(pos),
+        }
+    }
+
+    pub fn is_chunk_loaded(&self, chunk: &Chunk) -> bool {
+        self.world.read_resource::<Chunks>().loaded.contains(chunk)
+    }
+
     ///
     /// Execute one turn
     ///
     pub fn update(&mut self) {
-        Print.run_now(&mut self.world);
-        TakeAction.run_now(&mut self.world);
-        UpdateVel.run_now(&mut self.world);
-        UpdateCollide.run_now(&mut self.world);
-        UpdatePos.run_now(&mut self.world);
-        OutOfBound.run_now(&mut self.world);
-        Print.run_now(&mut self.world);
+        let since = self.world.read_resource::<Events>().0.len();
+
+        Print.run_now(&self.world);
+        RememberPos.run_now(&self.world);
+        TakeAction.run_now(&self.world);
+        UpdateVel.run_now(&self.world);
+        UpdateCollide.run_now(&self.world);
+        UpdatePos.run_now(&self.world);
+        if self.server {
+            RecordHistory.run_now(&self.world);
+            BulletHit.run_now(&self.world);
+        }
+        CheckTrigger.run_now(&self.world);
+        Animate { since }.run_now(&self.world);
+        FollowCamera { since }.run_now(&self.world);
+        OutOfBound.run_now(&self.world);
+        Print.run_now(&self.world);
+        self.world.write_resource::<Tick>().0 += 1;
         self.world.maintain();
     }
 
+    ///
+    /// Number of turns executed so far
+    ///
+    pub fn tick(&self) -> u64 {
+        self.world.read_resource::<Tick>().0
+    }
+
     ///
     /// Retrieve all events happened in the last turn
     ///
@@ -251,6 +742,250 @@ impl Systems {
     }
 
     pub fn render<'a, T: System<'a>>(&'a mut self, mut sys: T) {
-        sys.run_now(&mut self.world);
+        sys.run_now(&self.world);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terrain(id: u64, x: f32, w: f32) -> Terrain {
+        Terrain {
+            id,
+            pos: Pos::new(x, 0.0),
+            size: Size::new(w, 10.0),
+            asset: Asset(1),
+            block: false,
+        }
+    }
+
+    fn streamed(sys: &Systems) -> Vec<(u64, usize)> {
+        let mut ids: Vec<_> = sys
+            .world
+            .read_storage::<Streamed>()
+            .join()
+            .map(|s| (s.id, s.chunks.len()))
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn terrain_shared_by_chunks_is_loaded_once() {
+        let mut sys = Systems::new().unwrap();
+        let wide = terrain(1, 90.0, 20.0);
+
+        sys.load_chunk(Chunk::new(0, 0), &[terrain(2, 10.0, 10.0), wide.clone()]);
+        sys.load_chunk(Chunk::new(1, 0), &[wide, terrain(3, 150.0, 10.0)]);
+        assert_eq!(streamed(&sys), vec![(1, 2), (2, 1), (3, 1)]);
+
+        sys.unload_chunk(Chunk::new(0, 0));
+        assert_eq!(streamed(&sys), vec![(1, 1), (3, 1)]);
+        assert!(!sys.is_chunk_loaded(&Chunk::new(0, 0)));
+
+        sys.unload_chunk(Chunk::new(1, 0));
+        assert!(streamed(&sys).is_empty());
+    }
+
+    #[test]
+    fn reloading_a_chunk_drops_removed_terrain() {
+        let mut sys = Systems::new().unwrap();
+
+        sys.load_chunk(
+            Chunk::new(0, 0),
+            &[terrain(1, 10.0, 10.0), terrain(2, 30.0, 10.0)],
+        );
+        sys.load_chunk(Chunk::new(0, 0), &[terrain(2, 30.0, 10.0)]);
+
+        assert_eq!(streamed(&sys), vec![(2, 1)]);
+    }
+
+    fn lives_after_shot(server: bool) -> u64 {
+        let mut sys = Systems::new().unwrap();
+        sys.set_server(server);
+
+        let target = Player {
+            id: 2,
+            class: CLASS_SAITAMA,
+            lives: 3,
+        };
+        let e = sys.create_entity().create_player(
+            Pos::new(50.0, 0.0),
+            Size::new(20.0, 20.0),
+            target,
+            Asset(0),
+        );
+        sys.create_entity().create_bullet(
+            Vel::new(10.0, 0.0),
+            Pos::new(35.0, 5.0),
+            Bullet::new(1, CLASS_CHIBA),
+            Size::new(10.0, 10.0),
+            Asset(0),
+        );
+        sys.update();
+
+        let lives = sys.world.read_storage::<Player>().get(e).unwrap().lives;
+        lives
+    }
+
+    #[test]
+    fn only_the_server_decides_hits() {
+        assert_eq!(lives_after_shot(true), 2);
+        assert_eq!(lives_after_shot(false), 3);
+    }
+
+    ///
+    /// Lives of a target shot where it stood before moving away, as seen by
+    /// a shooter with the given round trip time
+    ///
+    fn lives_after_late_shot(rtt_ms: u64, max_rewind_ms: u64) -> u64 {
+        let mut sys = Systems::new().unwrap();
+        sys.set_server(true);
+        sys.set_lag_compensation(16, max_rewind_ms);
+
+        let shooter = Player::new(1, CLASS_CHIBA, 3);
+        sys.apply_action(&SendAction {
+            player: shooter,
+            pos: Pos::new(0.0, 0.0),
+            vel: Vel::zero(),
+            acc: Acc::zero(),
+            dir: Dir(1.0),
+            action: Action::default(),
+            rtt_ms: Some(rtt_ms),
+        });
+
+        let e = sys.create_entity().create_player(
+            Pos::new(50.0, 0.0),
+            Size::new(20.0, 20.0),
+            Player::new(2, CLASS_SAITAMA, 3),
+            Asset(0),
+        );
+        for _ in 0..10 {
+            sys.update();
+        }
+
+        // 4 turns before the shot is evaluated, 64ms ago
+        let y = sys.world.read_storage::<Pos>().get(e).unwrap().y;
+        sys.world
+            .write_storage::<Pos>()
+            .insert(e, Pos::new(500.0, y))
+            .unwrap();
+        for _ in 0..3 {
+            sys.update();
+        }
+
+        sys.create_entity().create_bullet(
+            Vel::new(10.0, 0.0),
+            Pos::new(35.0, y + 5.0),
+            Bullet::new(1, CLASS_CHIBA),
+            Size::new(10.0, 10.0),
+            Asset(0),
+        );
+        sys.update();
+
+        let lives = sys.world.read_storage::<Player>().get(e).unwrap().lives;
+        lives
+    }
+
+    #[test]
+    fn shots_hit_where_the_shooter_saw_the_target() {
+        assert_eq!(lives_after_late_shot(100, 200), 2);
+        assert_eq!(lives_after_late_shot(0, 200), 3);
+    }
+
+    #[test]
+    fn rewind_is_capped() {
+        assert_eq!(lives_after_late_shot(1000, 200), 2);
+        assert_eq!(lives_after_late_shot(100, 32), 3);
+    }
+
+    #[test]
+    fn snapshots_update_players_in_place() {
+        let mut sys = Systems::new().unwrap();
+        let player = |id, lives| Player {
+            id,
+            class: CLASS_CHIBA,
+            lives,
+        };
+        let user = sys.create_entity().create_user(
+            Pos::new(0.0, 0.0),
+            Size::new(10.0, 10.0),
+            player(1, 3),
+            Asset(1),
+        );
+        let state = |id, lives, x, asset| PlayerState {
+            player: player(id, lives),
+            pos: Pos::new(x, 0.0),
+            size: Size::new(10.0, 10.0),
+            vel: Vel::zero(),
+            dir: Dir(1.0),
+            asset: Asset(asset),
+        };
+
+        for asset in &[2, 3] {
+            sys.apply_snapshot(&Snapshot {
+                tick: 1,
+                players: vec![state(1, 2, 100.0, *asset), state(2, 3, 50.0, *asset)],
+                bullets: vec![],
+            });
+        }
+
+        let players = sys.world.read_storage::<Player>();
+        let assets = sys.world.read_storage::<Asset>();
+        let pos = sys.world.read_storage::<Pos>();
+        let all: Vec<_> = (&players, &assets)
+            .join()
+            .map(|(p, a)| (p.id, p.lives, a.0))
+            .collect();
+        assert_eq!(all, vec![(1, 2, 3), (2, 3, 3)]);
+
+        // The user stays where it predicted to be
+        assert_eq!(pos.get(user).unwrap().x, 0.0);
+    }
+
+    #[test]
+    fn triggers_with_the_same_name_fire_apart() {
+        let mut sys = Systems::new().unwrap();
+        let size = Size::new(10.0, 10.0);
+        for (x, once) in &[(0.0, true), (100.0, false)] {
+            let trigger = Trigger {
+                name: "door".into(),
+                once: *once,
+            };
+            sys.create_entity()
+                .create_trigger(Pos::new(*x, 0.0), size, trigger);
+        }
+        let e = sys.create_entity().create_player(
+            Pos::new(0.0, 0.0),
+            size,
+            Player::new(1, CLASS_CHIBA, 3),
+            Asset(0),
+        );
+
+        let mut fired = 0;
+        for x in &[0.0, 50.0, 0.0, 100.0, 50.0, 100.0] {
+            sys.world
+                .write_storage::<Pos>()
+                .insert(e, Pos::new(*x, 0.0))
+                .unwrap();
+            sys.world
+                .write_storage::<Vel>()
+                .insert(e, Vel::zero())
+                .unwrap();
+            sys.world
+                .write_storage::<Acc>()
+                .insert(e, Acc::zero())
+                .unwrap();
+            sys.update();
+            fired += sys
+                .take_events()
+                .iter()
+                .filter(|ev| matches!(ev, Event::Triggered { .. }))
+                .count();
+        }
+
+        // The once trigger fired on the first entry only, the other one twice
+        assert_eq!(fired, 3);
     }
 }
@@ -0,0 +1,532 @@
+use crate::{
+    assets::Assets,
+    error::{Error, Result},
+    level::Level,
+    protocol::*,
+    tiled,
+};
+use log::*;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+use websocket::{sync::Server, OwnedMessage};
+
+///
+/// Features meaningful on a terrain connection
+///
+const TERRAIN_FEATURES: &[&str] = &["chunks", "cache"];
+
+struct Served {
+    level: Level,
+    terrain: Vec<Terrain>,
+    /// Modification time of the file the level was read from
+    modified: Option<SystemTime>,
+}
+
+impl Served {
+    fn new(level: Level, modified: Option<SystemTime>) -> Self {
+        let terrain = level.terrain_items();
+        Self {
+            level,
+            terrain,
+            modified,
+        }
+    }
+}
+
+enum Source {
+    Memory,
+    File(PathBuf),
+    Dir(PathBuf),
+}
+
+struct Inner {
+    source: Source,
+    /// Level served to clients that don't name one
+    default: String,
+    levels: RwLock<HashMap<String, Served>>,
+    assets: RwLock<Option<Assets>>,
+}
+
+///
+/// Server answering terrain requests from levels
+///
+/// Levels are read from files, either level documents or Tiled maps, and
+/// read again whenever a file changes. A level is named after its file and
+/// picked by the last segment of the websocket path, e.g. `/ws/castle`,
+/// falling back to the default level. Cloning gives another handle on the
+/// same server.
+///
+#[derive(Clone)]
+pub struct TerrainServer {
+    inner: Arc<Inner>,
+    poll: Duration,
+}
+
+impl TerrainServer {
+    fn with_source(source: Source, default: &str) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                source,
+                default: default.into(),
+                levels: RwLock::new(HashMap::new()),
+                assets: RwLock::new(None),
+            }),
+            poll: Duration::from_secs(1),
+        }
+    }
+
+    ///
+    /// Serve the level stored in a file
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let server = Self::with_source(Source::File(path.clone()), &level_name(&path));
+        server.load(&path)?;
+        Ok(server)
+    }
+
+    ///
+    /// Serve every level in a directory, `name` or the first one by default
+    ///
+    /// Files that fail to load are reported and skipped. Files added later
+    /// are served once they load.
+    ///
+    pub fn open_dir<P: AsRef<Path>>(dir: P, name: Option<&str>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut files = level_files(dir)?;
+        files.sort();
+
+        let default = match name {
+            Some(name) => files.iter().map(|p| level_name(p)).find(|n| n == name),
+            None => files.first().map(|p| level_name(p)),
+        };
+        let default = default.ok_or_else(|| {
+            Error::InvalidMap(format!(
+                "no level {}in {}",
+                name.map(|n| format!("{} ", n)).unwrap_or_default(),
+                dir.display()
+            ))
+        })?;
+
+        let server = Self::with_source(Source::Dir(dir.to_path_buf()), &default);
+        server.reload()?;
+        if !server.inner.levels.read().unwrap().contains_key(&default) {
+            return Err(Error::InvalidMap(format!("level {} failed to load", default)).into());
+        }
+
+        Ok(server)
+    }
+
+    ///
+    /// Serve a level held in memory, e.g. a generated one
+    ///
+    pub fn from_level(level: Level) -> Self {
+        let server = Self::with_source(Source::Memory, &level.name);
+        server
+            .inner
+            .levels
+            .write()
+            .unwrap()
+            .insert(level.name.clone(), Served::new(level, None));
+        server
+    }
+
+    ///
+    /// Set how often the level files are checked for changes
+    ///
+    pub fn poll_interval(mut self, poll: Duration) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    ///
+    /// Only serve levels whose assets are all in `assets`
+    ///
+    pub fn with_assets(self, assets: Assets) -> Result<Self> {
+        for served in self.inner.levels.read().unwrap().values() {
+            assets.validate_level(&served.level)?;
+        }
+        *self.inner.assets.write().unwrap() = Some(assets);
+        Ok(self)
+    }
+
+    ///
+    /// Names of the levels being served
+    ///
+    pub fn levels(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.inner.levels.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    ///
+    /// Read the level files again if they changed since they were last read
+    ///
+    /// A file that fails to load, or uses unknown assets, is reported and the
+    /// previous level is kept until the file loads. In a directory, new files
+    /// are added and removed ones dropped.
+    ///
+    pub fn reload(&self) -> Result<bool> {
+        match &self.inner.source {
+            Source::Memory => Ok(false),
+            Source::File(path) => self.load(path),
+            Source::Dir(dir) => {
+                let files = level_files(dir)?;
+                let mut changed = false;
+
+                for path in &files {
+                    match self.load(path) {
+                        Ok(loaded) => changed |= loaded,
+                        Err(e) => warn!("Couldn't load level {}: {}", path.display(), e),
+                    }
+                }
+
+                let names: Vec<_> = files.iter().map(|p| level_name(p)).collect();
+                self.inner.levels.write().unwrap().retain(|name, _| {
+                    let kept = names.contains(name);
+                    if !kept {
+                        info!("Level {} was removed", name);
+                        changed = true;
+                    }
+                    kept
+                });
+
+                Ok(changed)
+            }
+        }
+    }
+
+    ///
+    /// Read a level file unless it is unchanged since it was last loaded
+    ///
+    /// The modification time is only recorded once the level is loaded, so a
+    /// file caught while being written is read again on the next check.
+    ///
+    fn load(&self, path: &Path) -> Result<bool> {
+        let name = level_name(path);
+        let modified = fs::metadata(path)?.modified().ok();
+        if let Some(served) = self.inner.levels.read().unwrap().get(&name) {
+            if served.modified == modified {
+                return Ok(false);
+            }
+        }
+
+        let level = read_level(path)?;
+        if let Some(assets) = &*self.inner.assets.read().unwrap() {
+            assets.validate_level(&level)?;
+        }
+        info!(
+            "Serving {} v{} as {} from {}",
+            level.name,
+            level.version,
+            name,
+            path.display()
+        );
+        self.inner
+            .levels
+            .write()
+            .unwrap()
+            .insert(name, Served::new(level, modified));
+
+        Ok(true)
+    }
+
+    ///
+    /// Answer a message from a client of a level, the default one if `None`
+    ///
+    pub fn handle(&self, map: Option<&str>, msg: Message) -> Vec<Message> {
+        let levels = self.inner.levels.read().unwrap();
+        let map = map.unwrap_or(&self.inner.default);
+        let served = match levels.get(map) {
+            Some(served) => served,
+            None => {
+                if let Message::Hello(hello) = msg {
+                    return vec![Message::HelloAck(hello.accept())];
+                }
+                warn!("Request for level {}, which isn't served", map);
+                return vec![Message::EndTerrain];
+            }
+        };
+
+        match msg {
+            Message::Hello(hello) => {
+                let mut ack = hello.accept();
+                ack.features
+                    .retain(|f| TERRAIN_FEATURES.contains(&f.as_str()));
+                vec![Message::HelloAck(ack)]
+            }
+            Message::GetAllTerrain => served
+                .terrain
+                .iter()
+                .cloned()
+                .map(Message::Terrain)
+                .chain(Some(Message::EndTerrain))
+                .collect(),
+            Message::GetTerrain(req) => {
+                if req.size <= 0.0 {
+                    warn!("Chunk size {} requested", req.size);
+                    return vec![Message::EndTerrain];
+                }
+                served
+                    .terrain
+                    .iter()
+                    .filter(|t| req.chunk.overlaps(&t.pos, &t.size, req.size))
+                    .cloned()
+                    .map(Message::Terrain)
+                    .chain(Some(Message::EndTerrain))
+                    .collect()
+            }
+            Message::GetManifest(req) => {
+                vec![Message::Manifest(served.level.manifest(req.size))]
+            }
+            msg => {
+                warn!("Unexpected message on terrain server: {:?}", msg);
+                Vec::new()
+            }
+        }
+    }
+
+    ///
+    /// Accept connections on `addr` and serve them until the listener fails
+    ///
+    /// Each connection is served on its own thread, and another one watches
+    /// the level files. Connections asking for a level that isn't served are
+    /// refused.
+    ///
+    pub fn serve(&self, addr: &str) -> Result<()> {
+        let server = Server::bind(addr)?;
+        info!("Terrain server listening on {}", addr);
+
+        if !matches!(self.inner.source, Source::Memory) {
+            let watcher = self.clone();
+            thread::spawn(move || loop {
+                thread::sleep(watcher.poll);
+                if let Err(e) = watcher.reload() {
+                    warn!("Couldn't reload levels: {}", e);
+                }
+            });
+        }
+
+        for req in server.filter_map(|r| r.ok()) {
+            let handler = self.clone();
+            thread::spawn(move || {
+                let map = map_name(&req.uri());
+                let known = {
+                    let levels = handler.inner.levels.read().unwrap();
+                    levels.contains_key(map.as_deref().unwrap_or(&handler.inner.default))
+                };
+                if !known {
+                    warn!("Refused a client of unknown level {:?}", map);
+                    let _ = req.reject();
+                    return;
+                }
+
+                let client = match req.accept() {
+                    Ok(client) => client,
+                    Err((_, e)) => {
+                        warn!("Couldn't accept terrain client: {}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = handler.connection(client, map.as_deref()) {
+                    debug!("Terrain client left: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn connection(
+        &self,
+        mut client: websocket::sync::Client<std::net::TcpStream>,
+        map: Option<&str>,
+    ) -> Result<()> {
+        let peer = client.peer_addr()?;
+        debug!("Terrain client {} connected", peer);
+
+        loop {
+            let msg = match client.recv_message()? {
+                OwnedMessage::Binary(data) => match serde_json::from_slice(&data) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        warn!("Malformed message from {}: {}", peer, e);
+                        continue;
+                    }
+                },
+                OwnedMessage::Ping(data) => {
+                    client.send_message(&OwnedMessage::Pong(data))?;
+                    continue;
+                }
+                OwnedMessage::Close(_) => {
+                    let _ = client.send_message(&OwnedMessage::Close(None));
+                    debug!("Terrain client {} disconnected", peer);
+                    return Ok(());
+                }
+                _ => continue,
+            };
+
+            for reply in self.handle(map, msg) {
+                client.send_message(&OwnedMessage::Binary(serde_json::to_vec(&reply)?))?;
+            }
+        }
+    }
+}
+
+fn is_level_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("json") | Some("tmx")
+    )
+}
+
+fn level_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| is_level_file(p))
+        .collect())
+}
+
+///
+/// Name a level is served under, the stem of its file
+///
+fn level_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+///
+/// Level asked for by the path of a websocket request, e.g. `/ws/castle`
+///
+fn map_name(uri: &str) -> Option<String> {
+    let path = uri.split('?').next().unwrap_or_default();
+    match path.trim_matches('/').rsplit('/').next() {
+        Some("") | Some("ws") | None => None,
+        Some(name) => Some(name.into()),
+    }
+}
+
+///
+/// Read a level document, or import a Tiled map if it isn't one
+///
+fn read_level(path: &Path) -> Result<Level> {
+    if path.extension().and_then(|e| e.to_str()) == Some("tmx") {
+        return tiled::import(path);
+    }
+
+    Level::open(path).or_else(|e| match e.downcast_ref::<Error>() {
+        Some(Error::UnsupportedLevel(_)) => Err(e),
+        _ => tiled::import(path).map_err(|_| e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::*;
+    use crate::level::Rect;
+
+    fn level(name: &str, asset: u64) -> Level {
+        let mut level = Level::new(name);
+        level.blocks.push(Rect {
+            pos: Pos::zero(),
+            size: Size::new(10.0, 10.0),
+            asset: Asset(asset),
+        });
+        level
+    }
+
+    fn assets(server: &TerrainServer, map: Option<&str>) -> Vec<u64> {
+        server
+            .handle(map, Message::GetAllTerrain)
+            .into_iter()
+            .filter_map(|m| match m {
+                Message::Terrain(t) => Some(t.asset.0),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_level_is_served_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        level("castle", 1)
+            .save(dir.path().join("castle.json"))
+            .unwrap();
+        level("forest", 2)
+            .save(dir.path().join("forest.json"))
+            .unwrap();
+
+        let server = TerrainServer::open_dir(dir.path(), Some("forest")).unwrap();
+
+        assert_eq!(server.levels(), vec!["castle", "forest"]);
+        assert_eq!(assets(&server, Some("castle")), vec![1]);
+        assert_eq!(assets(&server, None), vec![2]);
+        assert!(assets(&server, Some("swamp")).is_empty());
+    }
+
+    #[test]
+    fn added_and_removed_files_are_picked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        level("castle", 1)
+            .save(dir.path().join("castle.json"))
+            .unwrap();
+        let server = TerrainServer::open_dir(dir.path(), None).unwrap();
+
+        level("forest", 2)
+            .save(dir.path().join("forest.json"))
+            .unwrap();
+        assert!(server.reload().unwrap());
+        assert_eq!(assets(&server, Some("forest")), vec![2]);
+
+        fs::remove_file(dir.path().join("forest.json")).unwrap();
+        assert!(server.reload().unwrap());
+        assert_eq!(server.levels(), vec!["castle"]);
+        assert!(!server.reload().unwrap());
+    }
+
+    #[test]
+    fn failed_parse_is_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("castle.json");
+        level("castle", 1).save(&path).unwrap();
+        let server = TerrainServer::open(&path).unwrap();
+
+        // Caught half written, then completed within the same timestamp
+        let stamp = SystemTime::now() + Duration::from_secs(60);
+        fs::write(&path, b"{\"format\":").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(stamp)
+            .unwrap();
+        assert!(server.reload().is_err());
+        assert_eq!(assets(&server, None), vec![1]);
+
+        level("castle", 2).save(&path).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(stamp)
+            .unwrap();
+        assert!(server.reload().unwrap());
+        assert_eq!(assets(&server, None), vec![2]);
+    }
+
+    #[test]
+    fn map_is_the_last_path_segment() {
+        assert_eq!(map_name("/ws/"), None);
+        assert_eq!(map_name("/"), None);
+        assert_eq!(map_name("/ws/castle"), Some("castle".into()));
+        assert_eq!(map_name("/ws/castle/?v=2"), Some("castle".into()));
+    }
+}
@@ -0,0 +1,485 @@
+use crate::{
+    components::*,
+    error::{Error, Result},
+    level::*,
+};
+use log::{debug, trace, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, fs, path::Path};
+
+// Tiled stores flips in the high bits of global tile ids
+const GID_MASK: u32 = 0x1fff_ffff;
+
+#[derive(Deserialize)]
+struct RawMap {
+    width: u32,
+    height: u32,
+    tilewidth: u32,
+    tileheight: u32,
+    #[serde(default)]
+    layers: Vec<RawLayer>,
+    #[serde(default)]
+    tilesets: Vec<RawTileset>,
+}
+
+#[derive(Deserialize)]
+struct RawTileset {
+    firstgid: u32,
+    #[serde(default)]
+    properties: Vec<Property>,
+}
+
+#[derive(Deserialize)]
+struct RawLayer {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    width: u32,
+    #[serde(default)]
+    encoding: Option<String>,
+    #[serde(default)]
+    data: Value,
+    #[serde(default)]
+    objects: Vec<RawObject>,
+    #[serde(default)]
+    layers: Vec<RawLayer>,
+    #[serde(default)]
+    properties: Vec<Property>,
+}
+
+#[derive(Deserialize)]
+struct RawObject {
+    #[serde(default)]
+    name: String,
+    #[serde(default, rename = "type")]
+    kind: String,
+    /// Replaces `type` since Tiled 1.9
+    #[serde(default)]
+    class: String,
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    width: f32,
+    #[serde(default)]
+    height: f32,
+    #[serde(default)]
+    gid: Option<u32>,
+    #[serde(default)]
+    properties: Vec<Property>,
+}
+
+#[derive(Deserialize)]
+struct Property {
+    name: String,
+    value: Value,
+}
+
+fn prop<'a>(props: &'a [Property], name: &str) -> Option<&'a Value> {
+    props.iter().find(|p| p.name == name).map(|p| &p.value)
+}
+
+// TMX stores every property as a string, JSON as typed values
+
+fn prop_u64(props: &[Property], name: &str) -> Option<u64> {
+    match prop(props, name)? {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn prop_bool(props: &[Property], name: &str) -> Option<bool> {
+    match prop(props, name)? {
+        Value::Bool(b) => Some(*b),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn prop_str<'a>(props: &'a [Property], name: &str) -> Option<&'a str> {
+    prop(props, name)?.as_str()
+}
+
+fn prop_class(props: &[Property]) -> Option<Class> {
+    match prop_str(props, "class").map(|s| s.to_lowercase()) {
+        Some(ref s) if s == "chiba" => Some(CLASS_CHIBA),
+        Some(ref s) if s == "saitama" => Some(CLASS_SAITAMA),
+        Some(ref s) if s == "neutral" => Some(CLASS_NEUTRAL),
+        _ => prop_u64(props, "class").map(Class),
+    }
+}
+
+///
+/// Import a Tiled map, in `.tmx` or `.json` format, as a level
+///
+/// Tile layers become terrain, or blocks if the layer has a `collision`
+/// property set. Adjacent identical tiles are merged into terrain rectangles,
+/// and any adjacent solid tiles into blocks. The
+/// asset of a tile is its global id, or offset from an `asset` property of
+/// its tileset. Objects become spawns, landmarks, items or triggers
+/// according to their type.
+///
+pub fn import<P: AsRef<Path>>(path: P) -> Result<Level> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    let name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("tmx") => from_tmx(&text, &name),
+        _ => from_json(&text, &name),
+    }
+}
+
+pub fn from_json(text: &str, name: &str) -> Result<Level> {
+    let map: RawMap = serde_json::from_str(text)?;
+    convert(map, name)
+}
+
+pub fn from_tmx(text: &str, name: &str) -> Result<Level> {
+    let doc = roxmltree::Document::parse(text).map_err(|e| invalid(e.to_string()))?;
+    let root = doc.root_element();
+
+    let map = RawMap {
+        width: attr(root, "width")?,
+        height: attr(root, "height")?,
+        tilewidth: attr(root, "tilewidth")?,
+        tileheight: attr(root, "tileheight")?,
+        layers: tmx_layers(root)?,
+        tilesets: root
+            .children()
+            .filter(|n| n.has_tag_name("tileset"))
+            .map(|n| {
+                Ok(RawTileset {
+                    firstgid: attr(n, "firstgid")?,
+                    properties: tmx_properties(n),
+                })
+            })
+            .collect::<Result<_>>()?,
+    };
+
+    convert(map, name)
+}
+
+fn invalid<S: Into<String>>(msg: S) -> failure::Error {
+    Error::InvalidMap(msg.into()).into()
+}
+
+fn attr<T: std::str::FromStr>(node: roxmltree::Node, name: &str) -> Result<T> {
+    node.attribute(name)
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| invalid(format!("<{}> lacks {}", node.tag_name().name(), name)))
+}
+
+fn tmx_properties(node: roxmltree::Node) -> Vec<Property> {
+    node.children()
+        .filter(|n| n.has_tag_name("properties"))
+        .flat_map(|n| n.children().filter(|n| n.has_tag_name("property")))
+        .map(|p| Property {
+            name: p.attribute("name").unwrap_or_default().into(),
+            value: Value::String(
+                p.attribute("value")
+                    .or_else(|| p.text())
+                    .unwrap_or_default()
+                    .into(),
+            ),
+        })
+        .collect()
+}
+
+fn tmx_layers(node: roxmltree::Node) -> Result<Vec<RawLayer>> {
+    let mut layers = Vec::new();
+
+    for n in node.children().filter(|n| n.is_element()) {
+        let mut layer = RawLayer {
+            kind: String::new(),
+            name: n.attribute("name").unwrap_or_default().into(),
+            width: 0,
+            encoding: None,
+            data: Value::Null,
+            objects: Vec::new(),
+            layers: Vec::new(),
+            properties: tmx_properties(n),
+        };
+
+        match n.tag_name().name() {
+            "layer" => {
+                layer.kind = "tilelayer".into();
+                layer.width = attr(n, "width")?;
+                layer.data = Value::Array(tmx_data(n)?.into_iter().map(Value::from).collect());
+            }
+            "objectgroup" => {
+                layer.kind = "objectgroup".into();
+                layer.objects = n
+                    .children()
+                    .filter(|n| n.has_tag_name("object"))
+                    .map(|o| {
+                        Ok(RawObject {
+                            name: o.attribute("name").unwrap_or_default().into(),
+                            kind: o.attribute("type").unwrap_or_default().into(),
+                            class: o.attribute("class").unwrap_or_default().into(),
+                            x: attr(o, "x")?,
+                            y: attr(o, "y")?,
+                            width: attr(o, "width").unwrap_or(0.0),
+                            height: attr(o, "height").unwrap_or(0.0),
+                            gid: attr(o, "gid").ok(),
+                            properties: tmx_properties(o),
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+            }
+            "group" => {
+                layer.kind = "group".into();
+                layer.layers = tmx_layers(n)?;
+            }
+            _ => continue,
+        }
+
+        layers.push(layer);
+    }
+
+    Ok(layers)
+}
+
+fn tmx_data(layer: roxmltree::Node) -> Result<Vec<u32>> {
+    let data = layer
+        .children()
+        .find(|n| n.has_tag_name("data"))
+        .ok_or_else(|| invalid("<layer> lacks <data>"))?;
+
+    match data.attribute("encoding") {
+        Some("csv") => data
+            .text()
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().parse().map_err(|_| invalid("bad csv tile data")))
+            .collect(),
+        None => Ok(data
+            .children()
+            .filter(|n| n.has_tag_name("tile"))
+            .map(|t| attr(t, "gid").unwrap_or(0))
+            .collect()),
+        Some(e) => Err(invalid(format!("unsupported tile encoding {}", e))),
+    }
+}
+
+fn convert(map: RawMap, name: &str) -> Result<Level> {
+    let mut level = Level::new(name);
+    let tile = Size::new(map.tilewidth as f32, map.tileheight as f32);
+    let height = (map.height * map.tileheight) as f32;
+
+    // Tiled counts y downwards from the top, the world upwards from the bottom
+    let flip = |x: f32, y: f32, h: f32| Pos::new(x, height - y - h);
+
+    let mut tilesets: Vec<_> = map
+        .tilesets
+        .iter()
+        .map(|t| (t.firstgid, prop_u64(&t.properties, "asset")))
+        .collect();
+    tilesets.sort_by_key(|(first, _)| *first);
+    let asset = |gid: u32| {
+        let base = tilesets.iter().rev().find(|(first, _)| *first <= gid);
+        match base {
+            Some((first, Some(asset))) => Asset(asset + (gid - first) as u64),
+            _ => Asset(gid as u64),
+        }
+    };
+
+    let mut layers: Vec<&RawLayer> = map.layers.iter().collect();
+    while let Some(layer) = layers.pop() {
+        match layer.kind.as_str() {
+            "group" => layers.extend(layer.layers.iter()),
+            "tilelayer" => {
+                if layer.encoding.as_ref().map(|e| e != "csv").unwrap_or(false) {
+                    return Err(invalid(format!("layer {} isn't stored as csv", layer.name)));
+                }
+                let gids: Vec<u32> = serde_json::from_value(layer.data.clone())?;
+                let width = if layer.width > 0 {
+                    layer.width
+                } else {
+                    map.width
+                } as usize;
+                let collision = prop_bool(&layer.properties, "collision").unwrap_or(false);
+
+                // Blocks only need to cover solid cells, whatever tile they show
+                let rects = if collision {
+                    merge(&gids, width, |_, _| true)
+                } else {
+                    merge(&gids, width, |a, b| a == b)
+                };
+                debug!(
+                    "Layer {}: {} tiles merged into {} rectangles",
+                    layer.name,
+                    gids.iter().filter(|&&g| g & GID_MASK != 0).count(),
+                    rects.len()
+                );
+
+                for (gid, x, y, w, h) in rects {
+                    let size = Size::new(tile.x * w as f32, tile.y * h as f32);
+                    let rect = Rect {
+                        pos: flip(tile.x * x as f32, tile.y * y as f32, size.y),
+                        size,
+                        asset: asset(gid),
+                    };
+                    if collision {
+                        level.blocks.push(rect);
+                    } else {
+                        level.terrain.push(rect);
+                    }
+                }
+            }
+            "objectgroup" => {
+                for o in &layer.objects {
+                    // Tile objects are anchored at their bottom-left corner
+                    let top = if o.gid.is_some() { o.y - o.height } else { o.y };
+                    let pos = flip(o.x, top, o.height);
+                    let size = Size::new(o.width, o.height);
+                    let kind = if o.kind.is_empty() { &o.class } else { &o.kind };
+                    let props = &o.properties;
+                    let object_asset = prop_u64(props, "asset")
+                        .map(Asset)
+                        .or_else(|| o.gid.map(|g| asset(g & GID_MASK)))
+                        .unwrap_or(Asset(0));
+
+                    match kind.as_str() {
+                        "spawn" => level.spawns.push(SpawnPoint {
+                            class: prop_class(props).unwrap_or(CLASS_NEUTRAL),
+                            pos,
+                        }),
+                        "landmark" => level.landmarks.push(LandmarkDef {
+                            pos,
+                            size,
+                            class: prop_class(props).unwrap_or(CLASS_NEUTRAL),
+                            lives: prop_u64(props, "lives").unwrap_or(1),
+                            asset: object_asset,
+                        }),
+                        "item" => level.items.push(ItemDef {
+                            kind: prop_str(props, "kind").unwrap_or(&o.name).into(),
+                            pos,
+                            size,
+                            asset: object_asset,
+                        }),
+                        "trigger" => level.triggers.push(TriggerDef {
+                            name: o.name.clone(),
+                            pos,
+                            size,
+                            once: prop_bool(props, "once").unwrap_or(false),
+                        }),
+                        other => warn!("Ignoring object {} of type {:?}", o.name, other),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(level)
+}
+
+///
+/// Cover the non-empty cells of a grid with few rectangles of matching tiles
+///
+/// Greedy: each rectangle starts at the first uncovered cell in row order,
+/// grows right as far as possible, then down while whole rows match.
+/// `same` tells whether a cell's gid may join a rectangle started by another.
+/// Returns `(gid, x, y, width, height)` in cells, with the starting gid.
+///
+fn merge<F>(gids: &[u32], width: usize, same: F) -> Vec<(u32, usize, usize, usize, usize)>
+where
+    F: Fn(u32, u32) -> bool,
+{
+    if width == 0 {
+        return Vec::new();
+    }
+
+    let height = gids.len() / width;
+    let gid = |x: usize, y: usize| gids[y * width + x] & GID_MASK;
+    let joins = |g: u32, other: u32| other != 0 && same(g, other);
+    let mut covered = vec![false; gids.len()];
+    let mut rects = Vec::new();
+    let mut sizes: HashMap<u32, usize> = HashMap::new();
+
+    for y in 0..height {
+        for x in 0..width {
+            let g = gid(x, y);
+            if g == 0 || covered[y * width + x] {
+                continue;
+            }
+
+            let mut w = 1;
+            while x + w < width && joins(g, gid(x + w, y)) && !covered[y * width + x + w] {
+                w += 1;
+            }
+
+            let mut h = 1;
+            while y + h < height
+                && (x..x + w).all(|cx| joins(g, gid(cx, y + h)) && !covered[(y + h) * width + cx])
+            {
+                h += 1;
+            }
+
+            for cy in y..y + h {
+                for cx in x..x + w {
+                    covered[cy * width + cx] = true;
+                }
+            }
+            *sizes.entry(g).or_default() += w * h;
+            rects.push((g, x, y, w, h));
+        }
+    }
+
+    trace!("Merged tiles per gid: {:?}", sizes);
+
+    rects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn solid_tiles_merge_into_one_block() {
+        let level = import(fixture("collision.json")).unwrap();
+
+        // Three different tiles form a single solid area
+        assert_eq!(level.blocks.len(), 1);
+        let block = &level.blocks[0];
+        assert_eq!((block.pos.x, block.pos.y), (0.0, 0.0));
+        assert_eq!((block.size.x, block.size.y), (48.0, 32.0));
+        assert_eq!(block.asset, Asset(100));
+
+        // Decoration keeps its tiles apart
+        let mut terrain: Vec<_> = level
+            .terrain
+            .iter()
+            .map(|t| (t.asset.0, t.pos.x, t.pos.y, t.size.x))
+            .collect();
+        terrain.sort_by_key(|t| t.0);
+        assert_eq!(
+            terrain,
+            vec![(104, 0.0, 32.0, 32.0), (105, 32.0, 32.0, 16.0)]
+        );
+
+        assert_eq!(level.spawns.len(), 1);
+        assert_eq!(level.spawns[0].class, CLASS_CHIBA);
+        assert_eq!((level.spawns[0].pos.x, level.spawns[0].pos.y), (48.0, 48.0));
+    }
+
+    #[test]
+    fn merge_follows_the_predicate() {
+        let gids = [1, 2, 0, 1, 1, 0];
+
+        assert_eq!(merge(&gids, 3, |a, b| a == b).len(), 3);
+        assert_eq!(merge(&gids, 3, |_, _| true), vec![(1, 0, 0, 2, 2)]);
+        assert!(merge(&gids, 0, |_, _| true).is_empty());
+    }
+}
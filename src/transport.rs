@@ -0,0 +1,139 @@
+use crate::{
+    client::ConnectionState,
+    error::{Error, Result},
+    protocol::{Message, FEATURES},
+};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+///
+/// Message channel between a game and a server
+///
+pub trait Transport: Send {
+    fn send(&mut self, msg: Message) -> Result<()>;
+
+    fn try_recv(&mut self) -> Result<Option<Message>>;
+
+    fn state(&self) -> ConnectionState;
+
+    fn close(&mut self);
+
+    ///
+    /// Check if a protocol feature was agreed on with the peer
+    ///
+    fn has_feature(&self, _feature: &str) -> bool {
+        false
+    }
+
+    ///
+    /// Send a message, and again after a reconnection until it is acknowledged
+    ///
+    fn send_buffered(&mut self, msg: Message) -> Result<()> {
+        self.send(msg)
+    }
+
+    ///
+    /// Acknowledge the messages sent with `send_buffered` up to `seq`
+    ///
+    fn ack(&mut self, _seq: u64) {}
+
+    ///
+    /// Send again the messages not acknowledged yet
+    ///
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    ///
+    /// Move the transport forward without blocking, reconnecting if it can
+    ///
+    /// Returns `true` if a new connection was established since the last
+    /// call, so that the session can be resumed on it.
+    ///
+    fn poll(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+///
+/// In-process transport backed by channels
+///
+/// Both ends are created at once by `Loopback::pair`; closing or dropping
+/// one end closes the other.
+///
+pub struct Loopback {
+    tx: Option<Sender<Message>>,
+    rx: Receiver<Message>,
+    state: ConnectionState,
+}
+
+impl Loopback {
+    pub fn pair() -> (Loopback, Loopback) {
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+
+        (Loopback::new(tx1, rx2), Loopback::new(tx2, rx1))
+    }
+
+    fn new(tx: Sender<Message>, rx: Receiver<Message>) -> Self {
+        Self {
+            tx: Some(tx),
+            rx,
+            state: ConnectionState::Connected,
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, msg: Message) -> Result<()> {
+        let tx = match &self.tx {
+            Some(tx) if self.state == ConnectionState::Connected => tx,
+            _ => return Err(Error::Disconnected.into()),
+        };
+
+        if tx.send(msg).is_err() {
+            self.state = ConnectionState::Closed;
+            return Err(Error::Disconnected.into());
+        }
+
+        Ok(())
+    }
+
+    fn try_recv(&mut self) -> Result<Option<Message>> {
+        match self.rx.try_recv() {
+            Ok(msg) => Ok(Some(msg)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => {
+                self.close();
+                Err(Error::Disconnected.into())
+            }
+        }
+    }
+
+    fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    fn close(&mut self) {
+        self.tx = None;
+        self.state = ConnectionState::Closed;
+    }
+
+    fn has_feature(&self, feature: &str) -> bool {
+        FEATURES.contains(&feature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_peer_disconnects() {
+        let (mut a, b) = Loopback::pair();
+        drop(b);
+
+        let e = a.send(Message::GetAllTerrain).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Disconnected)));
+        assert_eq!(a.state(), ConnectionState::Closed);
+    }
+}
@@ -0,0 +1,513 @@
+use crate::{
+    collide::{rests_on, update_vel},
+    components::*,
+    protocol::{now_millis, SendAction, Terrain},
+    vector::Vector,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Instant};
+
+/// Longest stretch of motion replayed for one action
+const MAX_REPLAY_TICKS: u64 = 600;
+
+/// Violations kept until `take_violations` is called
+const MAX_VIOLATIONS: usize = 1024;
+
+///
+/// Bounds on what a client may report, matching the physics of `Systems`
+///
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Limits {
+    /// Duration of a simulation turn
+    pub tick_ms: u64,
+    /// Horizontal speed given by moving left or right
+    pub run_speed: f32,
+    /// Vertical speed given by jumping
+    pub jump_speed: f32,
+    /// Fastest fall allowed
+    pub fall_speed: f32,
+    /// Distance a position may be off on each axis, to absorb timing and
+    /// rounding differences
+    pub tolerance: f32,
+    /// Longest move accepted in one message, however long since the last one
+    pub max_teleport: f32,
+    /// Shortest delay between two shots
+    pub fire_interval_ms: u64,
+    /// Messages accepted per second on a connection
+    pub messages_per_sec: f64,
+    /// Messages accepted at once before the rate applies
+    pub burst: f64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            tick_ms: 16,
+            run_speed: RUN_SPEED,
+            jump_speed: JUMP_SPEED,
+            fall_speed: 30.0,
+            tolerance: 20.0,
+            max_teleport: 200.0,
+            fire_interval_ms: 250,
+            messages_per_sec: 120.0,
+            burst: 60.0,
+        }
+    }
+}
+
+impl Limits {
+    pub fn rate_limiter(&self) -> RateLimiter {
+        RateLimiter::new(self.messages_per_sec, self.burst)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ViolationKind {
+    /// Action sent on behalf of another player
+    Impersonation { claimed: u64 },
+    /// Velocity beyond what the inputs allow; clamped
+    Speed { vel: Vel },
+    /// Acceleration other than gravity; reset
+    Acceleration { acc: Acc },
+    /// Jump without standing on anything; ignored
+    AirJump,
+    /// Position out of reach of the last accepted one; replaced by the
+    /// replayed one
+    Teleport { distance: f32 },
+    /// Position away from where the replayed move ends; replaced by it
+    Desync { pos: Pos, expected: Pos },
+    /// Shot fired too soon after the previous one; dropped
+    FireRate,
+    /// Lives or class other than granted by the server; reset
+    Player { player: Player },
+    /// Too many messages on the connection; dropped
+    Flood,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Violation {
+    pub player: u64,
+    pub kind: ViolationKind,
+    /// Server time in milliseconds since the unix epoch
+    pub at: u64,
+}
+
+///
+/// Token bucket limiting the rate of messages on a connection
+///
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    ///
+    /// Account for a message, returning `false` if it should be dropped
+    ///
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct Accepted {
+    pos: Pos,
+    vel: Vel,
+    size: Size,
+    player: Player,
+    at: Instant,
+    last_shot: Option<Instant>,
+}
+
+///
+/// Server-side check of the state reported by clients
+///
+/// The last accepted state of each player is kept, and the move since then
+/// is replayed against the terrain blocks on every new action. The replay
+/// decides the velocity and whether a jump is possible; a position off the
+/// replayed one is reset to it, and recorded as a violation.
+///
+pub struct Validator {
+    limits: Limits,
+    blocks: Vec<(Pos, Size)>,
+    players: HashMap<u64, Accepted>,
+    violations: Vec<Violation>,
+    counts: HashMap<u64, usize>,
+}
+
+impl Validator {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            blocks: Vec::new(),
+            players: HashMap::new(),
+            violations: Vec::new(),
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    ///
+    /// Terrain that moves are replayed against; only blocks stop players
+    ///
+    pub fn set_terrain(&mut self, terrain: &[Terrain]) {
+        self.blocks = terrain
+            .iter()
+            .filter(|t| t.block)
+            .map(|t| (t.pos, t.size))
+            .collect();
+    }
+
+    ///
+    /// Trust the given state of a player, e.g. on login or respawn
+    ///
+    pub fn reset(&mut self, player: Player, pos: Pos, size: Size) {
+        self.players.insert(
+            player.id,
+            Accepted {
+                pos,
+                vel: Vel::zero(),
+                size,
+                player,
+                at: Instant::now(),
+                last_shot: None,
+            },
+        );
+    }
+
+    ///
+    /// Forget a player that left, with its violation count
+    ///
+    pub fn remove(&mut self, player: u64) {
+        self.players.remove(&player);
+        self.counts.remove(&player);
+    }
+
+    ///
+    /// Check an action sent by the connection logged in as `player`
+    ///
+    /// Returns the action to apply, corrected if needed, or `None` if it must
+    /// be dropped.
+    ///
+    pub fn check(&mut self, player: u64, mut info: SendAction) -> Option<SendAction> {
+        if info.player.id != player {
+            self.record(
+                player,
+                ViolationKind::Impersonation {
+                    claimed: info.player.id,
+                },
+            );
+            return None;
+        }
+
+        let limits = self.limits.clone();
+        let now = Instant::now();
+        let mut found = Vec::new();
+
+        let last = match self.players.get_mut(&player) {
+            Some(last) => last,
+            None => {
+                warn!("Action from player {} before it spawned", player);
+                return None;
+            }
+        };
+
+        let ticks = (now.duration_since(last.at).as_millis() as u64 / limits.tick_ms.max(1)) + 1;
+        let (expected, mut vel) = replay(&self.blocks, &limits, last, ticks);
+
+        // Inputs decide the velocity, whatever the client says
+        if info.action.right {
+            vel.x = limits.run_speed;
+        }
+        if info.action.left {
+            vel.x = -limits.run_speed;
+        }
+        if info.action.jump {
+            let grounded = self
+                .blocks
+                .iter()
+                .any(|(p, s)| rests_on(&expected, &last.size, p, s));
+            if grounded {
+                vel.y = limits.jump_speed;
+            } else {
+                found.push(ViolationKind::AirJump);
+                info.action.jump = false;
+            }
+        }
+
+        let within = |v: f32, min: f32, max: f32| v >= min - 0.001 && v <= max + 0.001;
+        if !within(info.vel.x, -limits.run_speed, limits.run_speed)
+            || !within(info.vel.y, -limits.fall_speed, limits.jump_speed)
+        {
+            found.push(ViolationKind::Speed { vel: info.vel });
+        }
+        info.vel = vel;
+
+        if (info.acc - Acc::gravity()).len() > 0.001 {
+            found.push(ViolationKind::Acceleration { acc: info.acc });
+            info.acc = Acc::gravity();
+        }
+
+        // Each axis has its own speed limit, and falling is faster than rising
+        let ticks = ticks as f32;
+        let reach = |speed: f32| (limits.tolerance + ticks * speed).min(limits.max_teleport);
+        let moved = info.pos - last.pos;
+        let off = info.pos - expected;
+        if moved.x.abs() > reach(limits.run_speed)
+            || moved.y > reach(limits.jump_speed)
+            || -moved.y > reach(limits.fall_speed)
+        {
+            found.push(ViolationKind::Teleport {
+                distance: moved.len(),
+            });
+            info.pos = expected;
+        } else if off.x.abs() > limits.tolerance || off.y.abs() > limits.tolerance {
+            found.push(ViolationKind::Desync {
+                pos: info.pos,
+                expected,
+            });
+            info.pos = expected;
+        }
+
+        if info.player.lives > last.player.lives || info.player.class != last.player.class {
+            found.push(ViolationKind::Player {
+                player: info.player.clone(),
+            });
+            info.player = last.player.clone();
+        }
+
+        if info.action.take {
+            let interval = limits.fire_interval_ms as u128;
+            match last.last_shot {
+                Some(t) if now.duration_since(t).as_millis() < interval => {
+                    found.push(ViolationKind::FireRate);
+                    info.action.take = false;
+                }
+                _ => last.last_shot = Some(now),
+            }
+        }
+
+        last.pos = info.pos;
+        last.vel = info.vel;
+        last.player = info.player.clone();
+        last.at = now;
+
+        for kind in found {
+            self.record(player, kind);
+        }
+
+        Some(info)
+    }
+
+    ///
+    /// Check the rate of messages of a connection, recording floods
+    ///
+    pub fn check_rate(&mut self, player: u64, limiter: &mut RateLimiter) -> bool {
+        if limiter.allow() {
+            return true;
+        }
+
+        self.record(player, ViolationKind::Flood);
+        false
+    }
+
+    fn record(&mut self, player: u64, kind: ViolationKind) {
+        warn!("Player {} violation: {:?}", player, kind);
+
+        *self.counts.entry(player).or_default() += 1;
+        if self.violations.len() >= MAX_VIOLATIONS {
+            self.violations.remove(0);
+        }
+        self.violations.push(Violation {
+            player,
+            kind,
+            at: now_millis(),
+        });
+    }
+
+    ///
+    /// Number of violations recorded for a player so far
+    ///
+    pub fn violation_count(&self, player: u64) -> usize {
+        self.counts.get(&player).cloned().unwrap_or(0)
+    }
+
+    ///
+    /// Retrieve the violations recorded since the last call
+    ///
+    pub fn take_violations(&mut self) -> Vec<Violation> {
+        self.violations.split_off(0)
+    }
+}
+
+///
+/// Move a player from its last accepted state the way `Systems` does, with
+/// gravity and the blocks in the way
+///
+fn replay(blocks: &[(Pos, Size)], limits: &Limits, last: &Accepted, ticks: u64) -> (Pos, Vel) {
+    let (mut pos, mut vel) = (last.pos, last.vel);
+
+    // Only the blocks in reach can be hit
+    let near: Vec<_> = blocks
+        .iter()
+        .filter(|(p, s)| {
+            let margin = limits.max_teleport + last.size.len();
+            p.x - margin < last.pos.x + last.size.x
+                && last.pos.x - margin < p.x + s.x
+                && p.y - margin < last.pos.y + last.size.y
+                && last.pos.y - margin < p.y + s.y
+        })
+        .collect();
+
+    for _ in 0..ticks.min(MAX_REPLAY_TICKS) {
+        vel += Acc::gravity();
+        let mut next = vel;
+        for (p, s) in &near {
+            let (v, _) = update_vel(&pos, &last.size, &vel, p, s, &Vel::zero());
+            next = next.min(&v);
+        }
+        vel = next;
+        pos += vel;
+    }
+
+    (pos, vel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::Action;
+
+    const ID: u64 = 1;
+
+    fn validator(at: Pos) -> Validator {
+        let mut v = Validator::new(Limits::default());
+        v.set_terrain(&[Terrain {
+            id: 0,
+            pos: Pos::new(-1000.0, -10.0),
+            size: Size::new(2000.0, 10.0),
+            asset: Asset(0),
+            block: true,
+        }]);
+        v.reset(player(), at, Size::new(10.0, 10.0));
+        v
+    }
+
+    fn player() -> Player {
+        Player {
+            id: ID,
+            class: CLASS_CHIBA,
+            lives: 3,
+        }
+    }
+
+    fn action(pos: Pos, update: impl FnOnce(&mut Action)) -> SendAction {
+        let mut action = Action::default();
+        update(&mut action);
+        SendAction {
+            player: player(),
+            pos,
+            vel: Vel::zero(),
+            acc: Acc::gravity(),
+            dir: Dir(1.0),
+            action,
+            rtt_ms: None,
+        }
+    }
+
+    fn kinds(v: &mut Validator) -> Vec<String> {
+        v.take_violations()
+            .into_iter()
+            .map(|v| format!("{:?}", v.kind))
+            .collect()
+    }
+
+    #[test]
+    fn jumping_needs_the_ground() {
+        let mut v = validator(Pos::new(0.0, 0.0));
+        let info = v
+            .check(ID, action(Pos::new(0.0, 0.0), |a| a.jump = true))
+            .unwrap();
+        assert_eq!(info.vel.y, Limits::default().jump_speed);
+        assert!(kinds(&mut v).is_empty());
+
+        let mut v = validator(Pos::new(0.0, 100.0));
+        let info = v
+            .check(ID, action(Pos::new(0.0, 100.0), |a| a.jump = true))
+            .unwrap();
+        assert!(!info.action.jump);
+        assert!(info.vel.y < 0.0);
+        assert_eq!(kinds(&mut v), vec!["AirJump"]);
+    }
+
+    #[test]
+    fn reach_is_checked_per_axis() {
+        // Within the fall speed, but beyond the run speed
+        let mut v = validator(Pos::new(0.0, 500.0));
+        let info = v.check(ID, action(Pos::new(40.0, 500.0), |_| {})).unwrap();
+        assert_eq!(info.pos.x, 0.0);
+        assert!(kinds(&mut v)[0].starts_with("Teleport"));
+    }
+
+    #[test]
+    fn moves_are_replayed_on_the_server() {
+        // Hovering in reach, but the player can only stay on the ground
+        let mut v = validator(Pos::new(0.0, 0.0));
+        let info = v.check(ID, action(Pos::new(0.0, 22.0), |_| {})).unwrap();
+        assert_eq!(info.pos.y, 0.0);
+        assert!(kinds(&mut v)[0].starts_with("Desync"));
+
+        // The replayed velocity is kept, not the reported one
+        let mut fly = action(Pos::new(0.0, 0.0), |_| {});
+        fly.vel = Vel::new(0.0, 5.0);
+        let info = v.check(ID, fly).unwrap();
+        assert_eq!(info.vel, Vel::zero());
+
+        let info = v
+            .check(ID, action(Pos::new(0.0, 0.0), |a| a.right = true))
+            .unwrap();
+        assert_eq!(info.vel.x, Limits::default().run_speed);
+        assert!(kinds(&mut v).is_empty());
+    }
+
+    #[test]
+    fn state_of_players_is_pruned() {
+        let mut v = validator(Pos::new(0.0, 0.0));
+        for _ in 0..MAX_VIOLATIONS + 10 {
+            let mut other = action(Pos::new(0.0, 0.0), |_| {});
+            other.player.id = 2;
+            assert!(v.check(ID, other).is_none());
+        }
+        assert_eq!(v.violation_count(ID), MAX_VIOLATIONS + 10);
+        assert_eq!(v.take_violations().len(), MAX_VIOLATIONS);
+
+        v.remove(ID);
+        assert_eq!(v.violation_count(ID), 0);
+        assert!(v.check(ID, action(Pos::new(0.0, 0.0), |_| {})).is_none());
+    }
+}
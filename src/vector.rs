@@ -1,3 +1,4 @@
+#[allow(clippy::len_without_is_empty)]
 pub trait Vector: Sized {
     fn x(&self) -> f32;
 
@@ -8,13 +9,13 @@ pub trait Vector: Sized {
     }
 }
 
-impl<'a, T: Vector> Vector for &'a T {
+impl<T: Vector> Vector for &T {
     fn x(&self) -> f32 {
-        self.x()
+        T::x(*self)
     }
 
     fn y(&self) -> f32 {
-        self.y()
+        T::y(*self)
     }
 }
 
@@ -66,17 +67,6 @@ macro_rules! impl_vector {
                     *vec
                 }
             }
-
-            pub fn to_vec(&self) -> ncollide2d::math::Vector<f32> {
-                use ncollide2d::math::Vector;
-                Vector::new(self.x, self.y)
-            }
-        }
-
-        impl From<ncollide2d::math::Vector<f32>> for $n {
-            fn from(v: ncollide2d::math::Vector<f32>) -> Self {
-                Self::new(v[0], v[1])
-            }
         }
 
         impl Vector for $n {